@@ -0,0 +1,593 @@
+use crate::history::{DbOptions, Normalization};
+use crate::settings::Settings;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+
+/// Settings read from `~/.mcfly/config.toml`. Every section and every key is optional; anything
+/// left out keeps McFly's built-in default, and environment variables still take precedence.
+/// `Config::load_or_default` also falls back to the defaults for any section it can't read.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub weights: Weights,
+    pub ignore: Ignore,
+    pub retention: Retention,
+    pub search: Search,
+    pub import: Import,
+    /// `[database]`: how to open the history database.
+    pub database: DbOptions,
+}
+
+/// `[weights]`: bonuses added on top of the network's rank.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Weights {
+    /// Overrides `History::same_program_weight`.
+    pub same_program: Option<f64>,
+    /// Overrides `History::modifier_weight`.
+    pub modifier: Option<f64>,
+    /// Overrides `History::arg_count_weight`.
+    pub arg_count: Option<f64>,
+    /// Overrides `History::session_recency_weight`.
+    pub session_recency: Option<f64>,
+    /// Overrides `History::missing_path_weight`.
+    pub missing_path: Option<f64>,
+    /// Overrides `History::query_length_weight`.
+    pub query_length: Option<f64>,
+}
+
+/// Weights are rank bonuses (or, for `missing_path`, a penalty) next to ranks between -1 and 1, so
+/// `Weights::validate` and `Weights::apply_update` keep them within this range.
+const WEIGHT_MIN: f64 = 0.0;
+const WEIGHT_MAX: f64 = 1.0;
+
+const WEIGHT_NAMES: [&str; 6] = [
+    "same_program",
+    "modifier",
+    "arg_count",
+    "session_recency",
+    "missing_path",
+    "query_length",
+];
+
+impl Weights {
+    /// Check that every weight that's set is finite and between 0 and 1, since a hand-edited typo
+    /// could otherwise make every rank NaN. NaN and infinite weights are unset, so the built-in
+    /// default is used, and the rest are clamped, with a warning for each. Returns false if
+    /// anything was changed.
+    pub fn validate(&mut self) -> bool {
+        let mut valid = true;
+        for (weight, name) in self.values_mut().iter_mut().zip(WEIGHT_NAMES.iter()) {
+            match **weight {
+                Some(value) if !value.is_finite() => {
+                    eprintln!(
+                        "McFly: Ignoring weights.{} = {}, using the default.",
+                        name, value
+                    );
+                    **weight = None;
+                    valid = false;
+                }
+                Some(value) if !(WEIGHT_MIN..=WEIGHT_MAX).contains(&value) => {
+                    let clamped = value.clamp(WEIGHT_MIN, WEIGHT_MAX);
+                    eprintln!(
+                        "McFly: Clamping weights.{} = {} to {}.",
+                        name, value, clamped
+                    );
+                    **weight = Some(clamped);
+                    valid = false;
+                }
+                _ => {}
+            }
+        }
+        valid
+    }
+
+    /// Take one gradient descent step: move each weight against its entry in `gradient`, scaled by
+    /// the next step of `learning_rate`, and keep it between 0 and 1. Weights that are unset count
+    /// as 0; ones with no gradient are left alone.
+    pub fn apply_update(&mut self, gradient: &Weights, learning_rate: &mut LearningRate) {
+        let rate = learning_rate.next_rate();
+        for (weight, gradient) in self.values_mut().iter_mut().zip(gradient.values().iter()) {
+            if let Some(gradient) = gradient {
+                let updated = weight.unwrap_or(0.0) - rate * gradient;
+                **weight = Some(updated.clamp(WEIGHT_MIN, WEIGHT_MAX));
+            }
+        }
+    }
+
+    fn values(&self) -> [Option<f64>; 6] {
+        [
+            self.same_program,
+            self.modifier,
+            self.arg_count,
+            self.session_recency,
+            self.missing_path,
+            self.query_length,
+        ]
+    }
+
+    fn values_mut(&mut self) -> [&mut Option<f64>; 6] {
+        [
+            &mut self.same_program,
+            &mut self.modifier,
+            &mut self.arg_count,
+            &mut self.session_recency,
+            &mut self.missing_path,
+            &mut self.query_length,
+        ]
+    }
+}
+
+/// A learning rate for `Weights::apply_update` that shrinks with each update, as
+/// `initial / (1 + decay * updates)`, so training settles instead of oscillating. A `decay` of 0
+/// keeps it constant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LearningRate {
+    pub initial: f64,
+    pub decay: f64,
+    updates: u32,
+}
+
+impl LearningRate {
+    pub fn new(initial: f64, decay: f64) -> LearningRate {
+        LearningRate {
+            initial,
+            decay,
+            updates: 0,
+        }
+    }
+
+    /// The rate for the next update, which then counts as applied.
+    pub fn next_rate(&mut self) -> f64 {
+        let rate = self.initial / (1.0 + self.decay * f64::from(self.updates));
+        self.updates += 1;
+        rate
+    }
+}
+
+/// `[ignore]`: commands that aren't recorded.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ignore {
+    /// Replaces `History::ignored_commands`, so list the defaults too if you still want them.
+    pub commands: Option<Vec<String>>,
+}
+
+/// `[retention]`: how much history to keep.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Sets `History::max_age_days`, for `mcfly maintain`. Unset keeps everything.
+    pub max_age_days: Option<u32>,
+}
+
+/// `[import]`: how shell history is imported.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Import {
+    /// Overrides `History::skip_comments`.
+    pub skip_comments: Option<bool>,
+}
+
+/// `[search]`: how suggestions are found and ranked.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Search {
+    /// Overrides `History::min_occurrences`.
+    pub min_occurrences: Option<i64>,
+    /// Overrides `History::lookback`.
+    pub lookback: Option<u16>,
+    /// Overrides `History::normalization`: `"minmax"` or `"zscore"`.
+    pub normalization: Option<Normalization>,
+    /// Overrides `History::template_placeholders`.
+    pub template_placeholders: Option<bool>,
+    /// Sets `History::template_command`.
+    pub template_command: Option<String>,
+    /// Replaces `History::modifier_programs`.
+    pub modifier_programs: Option<Vec<String>>,
+    /// Overrides `History::recent_sessions`.
+    pub recent_sessions: Option<u16>,
+    /// Sets `History::equivalent_dirs`.
+    pub equivalent_dirs: Option<Vec<String>>,
+    /// Sets `History::private_dirs`.
+    pub private_dirs: Option<Vec<String>>,
+    /// Sets `History::relative_home` to `$HOME`. `mcfly maintain` converts the directories already
+    /// stored.
+    pub home_relative_dirs: Option<bool>,
+    /// Overrides `History::negative_terms`.
+    pub negative_terms: Option<bool>,
+    /// Overrides `History::unique_templates`.
+    pub unique_templates: Option<bool>,
+    /// Overrides `History::session_context_only`.
+    pub session_context_only: Option<bool>,
+    /// Sets `History::occurrence_window_days`.
+    pub occurrence_window_days: Option<u32>,
+    /// Overrides `History::dir_inodes`.
+    pub dir_inodes: Option<bool>,
+    /// Sets `History::max_history_rows`.
+    pub max_history_rows: Option<u32>,
+}
+
+impl Search {
+    /// Raise a `lookback` of 0, which would leave nothing to compare context against, to 1,
+    /// warning about it. Returns whether it was already valid.
+    pub fn validate(&mut self) -> bool {
+        if self.lookback == Some(0) {
+            eprintln!("McFly: Raising search.lookback = 0 to 1.");
+            self.lookback = Some(1);
+            return false;
+        }
+        true
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        Settings::storage_dir_path().join(PathBuf::from("config.toml"))
+    }
+
+    /// Read the config file, or use the defaults if there isn't one. A broken config file mustn't
+    /// stop the shell hook from recording commands, so problems are only warned about: a file
+    /// that can't be read or parsed is ignored, and so is any section with a bad or unknown key.
+    pub fn load_or_default() -> Config {
+        let path = Config::path();
+        match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse_sections(&contents, &path.display().to_string()),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Config::default(),
+            Err(err) => {
+                eprintln!(
+                    "McFly: Unable to read {}, using the defaults ({})",
+                    path.display(),
+                    err
+                );
+                Config::default()
+            }
+        }
+    }
+
+    /// Parse `contents` section by section, warning about (and using the defaults for) any
+    /// section that doesn't parse, and checking the weights and lookback. `name` is the file, for warnings.
+    pub fn parse_sections(contents: &str, name: &str) -> Config {
+        let mut table: toml::value::Table = match toml::from_str(contents) {
+            Ok(table) => table,
+            Err(err) => {
+                eprintln!(
+                    "McFly: Unable to parse {}, using the defaults ({})",
+                    name, err
+                );
+                return Config::default();
+            }
+        };
+        let mut config = Config {
+            weights: Config::section(&mut table, "weights", name),
+            ignore: Config::section(&mut table, "ignore", name),
+            retention: Config::section(&mut table, "retention", name),
+            search: Config::section(&mut table, "search", name),
+            import: Config::section(&mut table, "import", name),
+            database: Config::section(&mut table, "database", name),
+        };
+        for key in table.keys() {
+            eprintln!("McFly: Ignoring unknown section [{}] in {}", key, name);
+        }
+        config.weights.validate();
+        config.search.validate();
+        config
+    }
+
+    fn section<T: DeserializeOwned + Default>(
+        table: &mut toml::value::Table,
+        key: &str,
+        name: &str,
+    ) -> T {
+        match table.remove(key) {
+            Some(value) => value.try_into().unwrap_or_else(|err| {
+                eprintln!(
+                    "McFly: Ignoring [{}] in {}, using its defaults ({})",
+                    key, name, err
+                );
+                T::default()
+            }),
+            None => T::default(),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+}
+
+/// A config file open for changes, such as weights adjusted while learning. Changes stay in memory
+/// until `flush`, which writes them all at once by replacing the file, so a crash never leaves it
+/// half-written. Comments in the file aren't kept.
+pub struct ConfigFile {
+    path: PathBuf,
+    config: Config,
+    dirty: bool,
+}
+
+impl ConfigFile {
+    /// Read the config file at `path`, starting from the defaults if there isn't one.
+    pub fn open(path: PathBuf) -> io::Result<ConfigFile> {
+        let config = match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(&contents)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Config::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(ConfigFile {
+            path,
+            config,
+            dirty: false,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The `[weights]` section, to change. Marks the file as needing a `flush`.
+    pub fn weights_mut(&mut self) -> &mut Weights {
+        self.dirty = true;
+        &mut self.config.weights
+    }
+
+    /// Whether there are changes `flush` hasn't written yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Write any changes: to a temporary file next to the config file first, which then replaces
+    /// it.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let contents = toml::to_string(&self.config)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        let temp_path = self.path.with_extension("toml.tmp");
+        {
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigFile, Ignore, Import, LearningRate, Retention, Search, Weights};
+    use crate::history::{DbOptions, Normalization};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn parses_every_section() {
+        let config = Config::parse(
+            r#"
+            [weights]
+            same_program = 0.2
+            modifier = 0.1
+            arg_count = 0.3
+            session_recency = 0.4
+            missing_path = 0.1
+            query_length = 0.2
+
+            [ignore]
+            commands = ["ls", "git status*"]
+
+            [retention]
+            max_age_days = 365
+
+            [search]
+            min_occurrences = 2
+            lookback = 5
+            normalization = "zscore"
+            template_placeholders = true
+            template_command = "my-normalizer --stdin"
+            modifier_programs = ["vim", "sed"]
+            recent_sessions = 2
+            equivalent_dirs = ["/app"]
+            private_dirs = ["/work/client"]
+            home_relative_dirs = true
+            negative_terms = true
+            unique_templates = true
+            session_context_only = true
+            occurrence_window_days = 30
+            dir_inodes = true
+            max_history_rows = 100000
+
+            [import]
+            skip_comments = true
+
+            [database]
+            exclusive_locking = true
+            vfs = "unix-dotfile"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                weights: Weights {
+                    same_program: Some(0.2),
+                    modifier: Some(0.1),
+                    arg_count: Some(0.3),
+                    session_recency: Some(0.4),
+                    missing_path: Some(0.1),
+                    query_length: Some(0.2),
+                },
+                ignore: Ignore {
+                    commands: Some(vec!["ls".to_string(), "git status*".to_string()]),
+                },
+                retention: Retention {
+                    max_age_days: Some(365),
+                },
+                search: Search {
+                    min_occurrences: Some(2),
+                    lookback: Some(5),
+                    normalization: Some(Normalization::ZScore),
+                    template_placeholders: Some(true),
+                    template_command: Some("my-normalizer --stdin".to_string()),
+                    modifier_programs: Some(vec!["vim".to_string(), "sed".to_string()]),
+                    recent_sessions: Some(2),
+                    equivalent_dirs: Some(vec!["/app".to_string()]),
+                    private_dirs: Some(vec!["/work/client".to_string()]),
+                    home_relative_dirs: Some(true),
+                    negative_terms: Some(true),
+                    unique_templates: Some(true),
+                    session_context_only: Some(true),
+                    occurrence_window_days: Some(30),
+                    dir_inodes: Some(true),
+                    max_history_rows: Some(100000),
+                },
+                import: Import {
+                    skip_comments: Some(true),
+                },
+                database: DbOptions {
+                    exclusive_locking: true,
+                    vfs: Some("unix-dotfile".to_string()),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn missing_sections_and_keys_keep_defaults() {
+        let config = Config::parse("[search]\nlookback = 4\n").unwrap();
+        assert_eq!(config.weights, Weights::default());
+        assert_eq!(config.retention, Retention::default());
+        assert_eq!(config.search.lookback, Some(4));
+        assert_eq!(config.search.min_occurrences, None);
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("[search]\nlokback = 4\n").is_err());
+    }
+
+    #[test]
+    fn parse_sections_falls_back_to_defaults_for_bad_sections() {
+        let config = Config::parse_sections(
+            "[weights]\nsame_program = 0.2\nmodifier = nan\n\
+             [search]\nlokback = 4\n\
+             [retention]\nmax_age_days = 30\n\
+             [colors]\nprompt = \"red\"\n",
+            "config.toml",
+        );
+        assert_eq!(
+            config,
+            Config {
+                weights: Weights {
+                    same_program: Some(0.2),
+                    ..Weights::default()
+                },
+                retention: Retention {
+                    max_age_days: Some(30),
+                },
+                ..Config::default()
+            }
+        );
+
+        assert_eq!(
+            Config::parse_sections("[search\nlookback = 4\n", "config.toml"),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn validate_drops_nan_weights_and_clamps_the_rest() {
+        let mut config = Config::parse(
+            "[weights]\nsame_program = nan\nmodifier = 1e9\narg_count = -0.5\nquery_length = 0.2\n",
+        )
+        .unwrap();
+        assert!(config.weights.same_program.unwrap().is_nan());
+
+        assert!(!config.weights.validate());
+        assert_eq!(
+            config.weights,
+            Weights {
+                same_program: None,
+                modifier: Some(1.0),
+                arg_count: Some(0.0),
+                query_length: Some(0.2),
+                ..Weights::default()
+            }
+        );
+        assert!(config.weights.validate());
+    }
+
+    #[test]
+    fn config_file_writes_batched_changes_once() {
+        let dir = env::temp_dir().join(format!("mcfly-config-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[search]\nlookback = 4\n").unwrap();
+
+        let mut file = ConfigFile::open(path.to_owned()).unwrap();
+        assert!(!file.is_dirty());
+        file.weights_mut().same_program = Some(0.2);
+        file.weights_mut().modifier = Some(0.1);
+        file.weights_mut().arg_count = Some(0.3);
+        assert!(file.is_dirty());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[search]\nlookback = 4\n"
+        );
+
+        file.flush().unwrap();
+        assert!(!file.is_dirty());
+        let saved = Config::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            saved.weights,
+            Weights {
+                same_program: Some(0.2),
+                modifier: Some(0.1),
+                arg_count: Some(0.3),
+                session_recency: None,
+                missing_path: None,
+                query_length: None,
+            }
+        );
+        assert_eq!(saved.search.lookback, Some(4));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn weight_updates_converge_within_bounds() {
+        let target = Weights {
+            same_program: Some(0.3),
+            modifier: Some(0.6),
+            arg_count: Some(0.0),
+            session_recency: None,
+            missing_path: None,
+            query_length: None,
+        };
+        let mut weights = Weights::default();
+        // Deliberately too high: without decay, each step would overshoot further than the last.
+        let mut learning_rate = LearningRate::new(1.5, 0.1);
+        for _ in 0..500 {
+            // The gradient of the squared distance to the target.
+            let gradient = Weights {
+                same_program: Some(2.0 * (weights.same_program.unwrap_or(0.0) - 0.3)),
+                modifier: Some(2.0 * (weights.modifier.unwrap_or(0.0) - 0.6)),
+                arg_count: Some(5.0),
+                ..Weights::default()
+            };
+            weights.apply_update(&gradient, &mut learning_rate);
+        }
+
+        assert!((weights.same_program.unwrap() - 0.3).abs() < 1e-6);
+        assert!((weights.modifier.unwrap() - 0.6).abs() < 1e-6);
+        assert_eq!(weights.arg_count, target.arg_count);
+        assert_eq!(weights.session_recency, None);
+        assert!(learning_rate.next_rate() < 0.1);
+    }
+}
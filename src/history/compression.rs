@@ -0,0 +1,186 @@
+use rusqlite::types::Value;
+use rusqlite::Connection;
+#[cfg(feature = "compression")]
+use rusqlite::Error;
+
+/// Columns of `commands` other than `cmd`, which the view passes through unchanged.
+#[cfg(feature = "compression")]
+const COLUMNS: [&str; 20] = [
+    "id",
+    "cmd_tpl",
+    "session_id",
+    "when_run",
+    "exit_code",
+    "exit_signal",
+    "selected",
+    "dir",
+    "old_dir",
+    "interactive",
+    "dangerous",
+    "parent",
+    "cursor_pos",
+    "note",
+    "source",
+    "hidden",
+    "arg_count",
+    "error_snippet",
+    "env_hash",
+    "dir_inode",
+];
+
+#[cfg(feature = "compression")]
+const LEVEL: i32 = 3;
+
+/// Register `mcfly_compress` and `mcfly_decompress`, which code writing to `main.commands` wraps
+/// around `cmd`. Without the `compression` feature both just return their argument.
+pub fn add_db_functions(db: &Connection) {
+    db.create_scalar_function("mcfly_compress", 1, true, |ctx| {
+        Ok(compress(ctx.get::<Value>(0)?))
+    })
+    .unwrap_or_else(|err| {
+        panic!(format!(
+            "McFly error: Successful create_scalar_function ({})",
+            err
+        ))
+    });
+
+    db.create_scalar_function("mcfly_decompress", 1, true, |ctx| {
+        decompress(ctx.get::<Value>(0)?)
+    })
+    .unwrap_or_else(|err| {
+        panic!(format!(
+            "McFly error: Successful create_scalar_function ({})",
+            err
+        ))
+    });
+}
+
+/// Read `cmd` text back transparently when some of it is stored compressed.
+///
+/// A temporary `commands` view shadows the real table for this connection, decompressing `cmd`
+/// so that every query reading from `commands` keeps working. SQLite can't write through a view
+/// that shadows its own table, so anything modifying commands must target `main.commands` and
+/// store `mcfly_compress(:cmd)`. Reads have to decompress every row and can't use the index on
+/// `cmd`, so this trades speed for size.
+///
+/// Must be called after `schema::migrate`, since the view would otherwise hide the table from
+/// `ALTER TABLE`.
+#[cfg(feature = "compression")]
+pub fn install(connection: &Connection) {
+    connection
+        .execute_batch(&format!(
+            "CREATE TEMP VIEW IF NOT EXISTS commands AS
+                 SELECT mcfly_decompress(cmd) AS cmd, {} FROM main.commands;",
+            COLUMNS.join(", ")
+        ))
+        .unwrap_or_else(|err| {
+            panic!(format!(
+                "McFly error: Unable to set up command compression ({})",
+                err
+            ))
+        });
+}
+
+// Commands are only stored compressed when that actually saves space; short ones stay plain text.
+#[cfg(feature = "compression")]
+fn compress(value: Value) -> Value {
+    match value {
+        Value::Text(text) => match zstd::encode_all(text.as_bytes(), LEVEL) {
+            Ok(compressed) if compressed.len() < text.len() => Value::Blob(compressed),
+            _ => Value::Text(text),
+        },
+        other => other,
+    }
+}
+
+#[cfg(feature = "compression")]
+fn decompress(value: Value) -> Result<Value, Error> {
+    match value {
+        Value::Blob(compressed) => {
+            let bytes = zstd::decode_all(compressed.as_slice())
+                .map_err(|err| Error::UserFunctionError(Box::new(err)))?;
+            Ok(Value::Text(String::from_utf8_lossy(&bytes).into_owned()))
+        }
+        other => Ok(other),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(value: Value) -> Value {
+    value
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(value: Value) -> Result<Value, rusqlite::Error> {
+    Ok(value)
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::install;
+    use crate::history::{schema, DbOptions, History, MatchMode};
+    use rusqlite::types::Value;
+    use rusqlite::NO_PARAMS;
+    use std::path::PathBuf;
+
+    #[test]
+    fn commands_round_trip_through_compressed_storage() {
+        let history = History::from_db_path(PathBuf::from(":memory:"), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        install(&history.connection);
+
+        let long = format!("echo {}", "abc ".repeat(100));
+        history.add(
+            &long,
+            Some("s1"),
+            Some("/tmp"),
+            &Some(1),
+            Some(0),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+        history.add(
+            "ls -la",
+            Some("s1"),
+            Some("/tmp"),
+            &Some(2),
+            Some(0),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+
+        let stored: Vec<Value> = history
+            .connection
+            .prepare("SELECT cmd FROM main.commands ORDER BY id")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .map(|value| value.unwrap())
+            .collect();
+        match &stored[0] {
+            Value::Blob(compressed) => assert!(compressed.len() < long.len()),
+            other => panic!("expected a compressed command, got {:?}", other),
+        }
+        assert_eq!(stored[1], Value::Text("ls -la".to_string()));
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].cmd, long);
+        assert_eq!(commands[1].cmd, "ls -la");
+
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(3));
+        assert_eq!(
+            history.find_matches("abc abc", 10, false, false, MatchMode::Substring)[0].cmd,
+            long
+        );
+
+        history.delete_command(&long);
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::history::compression;
 use crate::history::history::Features;
 use crate::network::Network;
 use rusqlite::Connection;
@@ -37,4 +38,6 @@ pub fn add_db_functions(db: &Connection) {
             err
         ))
     });
+
+    compression::add_db_functions(db);
 }
@@ -1,19 +1,32 @@
 #![allow(clippy::module_inception)]
 use crate::shell_history;
-use rusqlite::{Connection, MappedRows, Row, NO_PARAMS};
+use rusqlite::{Connection, MappedRows, OpenFlags, Row, NO_PARAMS};
+use std::cell::Cell;
 use std::cmp::Ordering;
-use std::io::Write;
-use std::path::PathBuf;
-use std::{fmt, fs, io};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::{env, fmt, fs, io, thread};
 //use std::time::Instant;
+use crate::config::{Config, Weights};
+use crate::edit_distance;
+#[cfg(feature = "compression")]
+use crate::history::compression;
 use crate::history::{db_extensions, schema};
 use crate::network::Network;
 use crate::path_update_helpers;
-use crate::settings::{HistoryFormat, Settings};
+use crate::settings::{HistoryFormat, IgnorePrevious, Settings};
 use crate::simplified_command::SimplifiedCommand;
 use itertools::Itertools;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use regex::Regex;
 use rusqlite::types::ToSql;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Default)]
 pub struct Features {
@@ -29,6 +42,145 @@ pub struct Features {
     pub occurrences_factor: f64,
 }
 
+impl Features {
+    /// Field names of the factors, in the same order as `factors_mut`.
+    pub const NAMES: [&'static str; 10] = [
+        "age_factor",
+        "length_factor",
+        "exit_factor",
+        "recent_failure_factor",
+        "selected_dir_factor",
+        "dir_factor",
+        "overlap_factor",
+        "immediate_overlap_factor",
+        "selected_occurrences_factor",
+        "occurrences_factor",
+    ];
+
+    pub fn factors_mut(&mut self) -> Vec<&mut f64> {
+        vec![
+            &mut self.age_factor,
+            &mut self.length_factor,
+            &mut self.exit_factor,
+            &mut self.recent_failure_factor,
+            &mut self.selected_dir_factor,
+            &mut self.dir_factor,
+            &mut self.overlap_factor,
+            &mut self.immediate_overlap_factor,
+            &mut self.selected_occurrences_factor,
+            &mut self.occurrences_factor,
+        ]
+    }
+}
+
+// The rank bonuses and penalties `build_cache_table` and `find_matches` apply, gathered up so a
+// search can be previewed with different ones.
+#[derive(Debug, Clone, Copy)]
+struct RankWeights {
+    same_program: f64,
+    modifier: f64,
+    session_recency: f64,
+    arg_count: f64,
+    missing_path: f64,
+    query_length: f64,
+}
+
+/// Where in a command `find_matches` looks for the search text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// Anywhere in the command.
+    Substring,
+    /// Only at the start of the command, for completing what's been typed so far.
+    Prefix,
+}
+
+/// What `build_cache_table` scores commands against, from `History::current_context`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    /// Templates of the last `lookback` commands, most recent first, taken from the whole history
+    /// when the session doesn't have enough. Ends with an empty template when neither does. With
+    /// `History::session_context_only`, just the session's, which is none for a new session.
+    pub last_command_templates: Vec<String>,
+    /// The directory `dir_factor` compares against.
+    pub dir: String,
+    /// How many times the most common command was run, which `occurrences_factor` divides by.
+    pub max_occurrences: f64,
+    /// When the oldest command was run. An hour before `when_run_max` if they'd be equal.
+    pub when_run_min: f64,
+    /// When the newest command was run. `age_factor` is scaled over the spread between the two.
+    pub when_run_max: f64,
+}
+
+/// What `History::find_matches_with_policy` does with commands flagged as dangerous.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DangerPolicy {
+    /// Rank them along with everything else, like `find_matches`.
+    Include,
+    /// Leave them out.
+    Exclude,
+    /// Return them in `SplitMatches::dangerous`, so the UI can ask before running one.
+    Separate,
+}
+
+/// Matches from `History::find_matches_with_policy`.
+#[derive(Debug, Clone, Default)]
+pub struct SplitMatches {
+    /// Matches that can be suggested as usual, best first.
+    pub matches: Vec<Command>,
+    /// Dangerous matches, best first. Only filled in with `DangerPolicy::Separate`.
+    pub dangerous: Vec<Command>,
+}
+
+/// A `#mcfly:` marker line in the history file, from `History::parse_mcfly_marker`. The bundled
+/// bash integration writes search markers when Ctrl-r is pressed; edit markers only come from
+/// custom integrations. The grammar is:
+///
+/// ```text
+/// marker := "#mcfly:edit:" cursor_pos " " command   (an Edit)
+///         | "#mcfly:" [" "] query                    (a Search)
+/// ```
+///
+/// `cursor_pos` is a byte offset into `command`, at most its length.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditRequest {
+    /// Ctrl-r was pressed with `query` in the prompt, so a search should start from it.
+    Search { query: String },
+    /// `command` was picked for editing rather than running, with the cursor at `cursor_pos`.
+    Edit { command: String, cursor_pos: i64 },
+}
+
+/// Why `find_matches_debug` left out a command that matched the search text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterReason {
+    /// Every run of the command is hidden.
+    Hidden,
+    /// Every run of the command was in one of the `private_dirs`.
+    PrivateDir,
+    /// Run fewer than `min_occurrences` times.
+    TooFewOccurrences,
+    /// Not run between the start and end times given to `build_cache_table`.
+    OutsideTimeWindow,
+    /// Only run before the newest `max_history_rows` commands.
+    BeyondRowLimit,
+    /// Only ever run from scripts, and the search was `interactive_only`.
+    NotInteractive,
+    /// Never run with `match_env_hash`.
+    OtherEnvironment,
+    /// Ranked below the `num` matches returned.
+    BelowLimit,
+}
+
+/// How `find_matches` rescales each factor across the matching commands before ranking, so that
+/// factors spread over a narrow range still count as much as ones spread over a wide range.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Map each factor onto 0..1 between its lowest and highest value.
+    MinMax,
+    /// Subtract each factor's mean and divide by its standard deviation.
+    ZScore,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Command {
     pub id: i64,
@@ -40,10 +192,155 @@ pub struct Command {
     pub exit_code: Option<i32>,
     pub selected: bool,
     pub dir: Option<String>,
+    /// Flagged as destructive, so a front-end can ask before running it again.
+    pub dangerous: bool,
+    /// The program the command was run from, such as `vim` or `tmux`, if it was recorded.
+    pub parent: Option<String>,
+    /// Where the cursor was when the command was saved for editing, if a custom shell integration
+    /// passed it as a `#mcfly:edit:` marker.
+    pub cursor_pos: Option<i64>,
+    /// A note the user attached to the command, such as a reminder of what it's for.
+    pub note: Option<String>,
+    /// Where an imported command came from, such as `bash` or `zsh`, or the name of the overlay
+    /// it was found in. `None` for recorded commands.
+    pub source: Option<String>,
+    /// The start of what a failed command printed to stderr, if a custom shell hook passed it
+    /// along. The bundled shell scripts don't.
+    pub error_snippet: Option<String>,
     pub features: Features,
     pub match_bounds: Vec<(usize, usize)>,
 }
 
+/// One row per distinct command template, for browsing command patterns.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateSummary {
+    pub cmd_tpl: String,
+    /// How many times a command with this template has been run.
+    pub count: i64,
+    /// When a command with this template was last run.
+    pub last_run: Option<i64>,
+    /// The most recently run command with this template.
+    pub sample_cmd: String,
+}
+
+/// Commands run more than once in the same directory, as a preview of what a dedup would remove.
+#[derive(Debug, Clone, Default)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// Rows a dedup would delete, keeping one row per group.
+    pub redundant_rows: i64,
+    /// Command and directory text stored in those redundant rows.
+    pub reclaimable_bytes: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DuplicateGroup {
+    pub cmd: String,
+    pub dir: Option<String>,
+    pub count: i64,
+}
+
+/// How well the ranking predicted recent commands, from `History::evaluate`.
+#[derive(Debug, Clone, Default)]
+pub struct EvalReport {
+    /// Held-out commands that had enough context to be scored.
+    pub evaluated: usize,
+    /// Fraction of those that appeared in the top N suggestions.
+    pub recall: f64,
+    /// Mean of 1 / position of each command in the suggestions, counting 0 when it was missing.
+    pub mean_reciprocal_rank: f64,
+}
+
+/// How fast the history is growing, from `History::growth_stats`, for deciding when to prune.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GrowthStats {
+    /// Commands run within the window.
+    pub added: i64,
+    /// Commands in the history altogether.
+    pub total: i64,
+}
+
+/// A frequently run command worth an alias, from `History::suggest_aliases`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasSuggestion {
+    /// The initials of the command's words, with a number added if another suggestion has them.
+    pub name: String,
+    /// The command the alias would run.
+    pub command: String,
+    /// How many times the command has been run.
+    pub occurrences: i64,
+}
+
+impl AliasSuggestion {
+    /// An `alias` line that bash and zsh can source.
+    pub fn shell_alias(&self) -> String {
+        format!(
+            "alias {}='{}'",
+            self.name,
+            self.command.replace('\'', "'\\''")
+        )
+    }
+}
+
+/// How a command has exited in the past, from `History::likely_exit`, for warning before it's run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExitStats {
+    /// Runs with a known exit code.
+    pub runs: i64,
+    /// Fraction of those runs that exited 0.
+    pub success_rate: f64,
+    /// The exit code seen most often, the lowest one on a tie.
+    pub most_common_exit_code: i32,
+}
+
+/// How to open the history database, for when `~/.mcfly` is on a network filesystem where
+/// SQLite's default locking is slow or unreliable.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbOptions {
+    /// Take the database lock once and hold it until McFly exits (`PRAGMA locking_mode =
+    /// EXCLUSIVE`), so other McFly processes wait on it. `rebuild` can't attach a database
+    /// locked this way.
+    pub exclusive_locking: bool,
+    /// SQLite VFS to open the database with, such as `unix-dotfile`.
+    pub vfs: Option<String>,
+}
+
+/// A fingerprint of every row in the commands table, from `History::snapshot`, for checking what
+/// a sync or merge changed.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub count: usize,
+    /// How many rows hash to each value, so identical rows are still counted.
+    row_hashes: HashMap<u64, usize>,
+}
+
+/// Rows added and removed between two snapshots.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl Snapshot {
+    /// What changed going from this snapshot to `other`. A row whose contents changed counts as
+    /// one removal and one addition.
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        let missing_from = |from: &Snapshot, to: &Snapshot| -> usize {
+            from.row_hashes
+                .iter()
+                .map(|(hash, count)| {
+                    count.saturating_sub(to.row_hashes.get(hash).cloned().unwrap_or(0))
+                })
+                .sum()
+        };
+        SnapshotDiff {
+            added: missing_from(other, self),
+            removed: missing_from(self, other),
+        }
+    }
+}
+
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.cmd.fmt(f)
@@ -60,8 +357,183 @@ impl From<Command> for String {
 pub struct History {
     pub connection: Connection,
     pub network: Network,
+    /// Session used by `add` and `build_cache_table` when the caller doesn't pass one.
+    pub session_id: Option<String>,
+    /// Directory used by `add` and `build_cache_table` when the caller doesn't pass one.
+    pub dir: Option<String>,
+    /// Record templates with numbers and hashes replaced by placeholders.
+    pub template_placeholders: bool,
+    /// Shell command that reads a command on stdin and writes its template to stdout, used in
+    /// place of the built-in templates by `add`, `rebuild` and imports.
+    pub template_command: Option<String>,
+    /// Commands matching any of these are flagged as dangerous when added.
+    pub dangerous_patterns: Vec<Regex>,
+    /// Invoking program used by `add` and `build_cache_table` when the caller doesn't pass one.
+    pub parent: Option<String>,
+    /// Curated commands, keyed by prefix, that `find_matches` puts first (in the given order)
+    /// whenever the search starts with that prefix, whether or not they're in the history.
+    pub preferred_commands: HashMap<String, Vec<String>>,
+    /// Commands run fewer times than this are left out of the contextual cache, and so never
+    /// suggested. The default of 1 keeps every command.
+    pub min_occurrences: i64,
+    /// Commands `should_add` and imports leave out. An entry ending in `*` ignores every command
+    /// starting with the rest of it; any other entry only ignores that exact command.
+    pub ignored_commands: Vec<String>,
+    /// Leave out shell comments (lines starting with `#`, other than `#mcfly:` markers) when
+    /// importing or syncing shell history. Off by default.
+    pub skip_comments: bool,
+    /// Shell nesting level of the command being added, as reported by the hook.
+    pub shlvl: Option<u32>,
+    /// Commands from shells nested deeper than this (subshells, scripts) aren't recorded.
+    pub max_shlvl: Option<u32>,
+    /// How many previous commands `build_cache_table` compares against for the overlap factors.
+    pub lookback: u16,
+    /// Added to the rank of commands that start with the same program as the last command, so
+    /// other `git` commands come up after a `git` command. Off (0) by default.
+    pub same_program_weight: f64,
+    /// Programs that usually change files, like `vim` or `mv`, for `modifier_weight`.
+    pub modifier_programs: Vec<String>,
+    /// Directories that count as the current one for `dir_factor`, such as the host path of a
+    /// project that a dev container mounts at `/app`.
+    pub equivalent_dirs: Vec<String>,
+    /// Directory prefixes whose commands are left out of search, e.g. client work that shouldn't
+    /// come up while screen-sharing. They're still recorded and still listed by `commands`.
+    pub private_dirs: Vec<String>,
+    /// Store directories under this one, normally `$HOME`, as `~/...`, so directory context
+    /// carries over between machines where the home directory differs. Off (`None`) by default.
+    pub relative_home: Option<String>,
+    /// Commands run longer ago than this many days are deleted by `maintain`. Off (`None`,
+    /// keeping everything) by default.
+    pub max_age_days: Option<u32>,
+    /// Treat search words starting with `-` as terms to exclude, so `docker -prune` finds docker
+    /// commands except the ones mentioning `prune`. Off by default, since it changes what searches
+    /// like `ls -la` mean.
+    pub negative_terms: bool,
+    /// Only compare context against the current session's previous commands, rather than falling
+    /// back to the whole history when the session has fewer than `lookback`, so a new terminal
+    /// doesn't get suggestions based on what was run elsewhere. Off by default.
+    pub session_context_only: bool,
+    /// Record each command's directory by device and inode as well as by path, and give commands
+    /// run in the current directory under an old name `dir_factor` credit, so renaming a project
+    /// keeps its history. Off by default, since it costs a `stat` per command.
+    pub dir_inodes: bool,
+    /// Have `find_matches` only return commands that were run at least once with this `env_hash`,
+    /// such as ones run with the current `KUBECONFIG`. Off (`None`) by default.
+    pub match_env_hash: Option<String>,
+    /// Have `find_matches` return only the highest-ranked command for each template, so several
+    /// `git commit -m` messages don't crowd out everything else. Off by default.
+    pub unique_templates: bool,
+    /// Added to the rank of commands, scaled by how often they were a `modifier_programs` command
+    /// run in the current directory, to favor editing commands while working in a project. Off (0)
+    /// by default.
+    pub modifier_weight: f64,
+    /// Only rank the most recent this many commands in `build_cache_table`, so histories with
+    /// millions of rows build the cache quickly. Older commands can't be suggested. Off (`None`,
+    /// ranking everything) by default.
+    pub max_history_rows: Option<u32>,
+    /// Only count runs from this many days back in `occurrences_factor`, so suggestions follow
+    /// changing habits instead of how often something was run years ago. Off (`None`, counting
+    /// every run) by default.
+    pub occurrence_window_days: Option<u32>,
+    /// How many of the most recently active sessions `session_recency_weight` favors.
+    pub recent_sessions: u16,
+    /// Added to the rank of commands run in one of the `recent_sessions` most recently active
+    /// sessions, wherever they were run, to pick up where that work left off. Off (0) by default.
+    pub session_recency_weight: f64,
+    /// Added to the rank of matches, scaled by how close their argument count is to the search's,
+    /// so matches shaped like what's being typed come first. Off (0) by default.
+    pub arg_count_weight: f64,
+    /// Subtracted from the rank of matches with an absolute path argument that no longer exists,
+    /// since they're likely stale. Checking costs a filesystem lookup per path, so it's off (0) by
+    /// default.
+    pub missing_path_weight: f64,
+    /// Added to the rank of matches, scaled by how much of the match the search covers, so a long
+    /// search favors the long commands it nearly spells out over even longer ones. Off (0) by
+    /// default.
+    pub query_length_weight: f64,
+    /// Rescale factors across the matching commands before ranking them. Off by default.
+    pub normalization: Option<Normalization>,
+    /// Record how long searches take, for `timings`.
+    pub timing: bool,
+    timings: Cell<Timings>,
+    db_options: DbOptions,
+    // Attached overlays, by name and path, so `rebuild` can attach them to its new connection.
+    overlays: Vec<(String, PathBuf)>,
+}
+
+/// How long the most recent `build_cache_table` and `find_matches` calls took, when timing is on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub build_cache_table: Option<Duration>,
+    pub find_matches: Option<Duration>,
 }
 
+/// The highest signal number we recognize when splitting signal terminations out of exit codes.
+const MAX_SIGNAL: i32 = 64;
+
+/// Added to the rank of commands always run from the current parent program. Ranks fall between
+/// -1 and 1, so this nudges ties rather than overriding the network.
+const PARENT_BONUS: f64 = 0.05;
+
+/// How soon after a failure a successful command has to run for `fix_for` to treat it as the fix.
+const FIX_WINDOW_SECS: i64 = 5 * 60;
+
+/// Default for `History::modifier_programs`.
+const MODIFIER_PROGRAMS: [&str; 10] = [
+    "vim", "vi", "nvim", "emacs", "nano", "cp", "mv", "rm", "touch", "mkdir",
+];
+
+/// `random_useful` only picks commands run at least this many times...
+const RANDOM_USEFUL_MIN_OCCURRENCES: i64 = 3;
+
+/// ...and not within this many seconds.
+const RANDOM_USEFUL_MIN_AGE_SECS: i64 = 24 * 60 * 60;
+
+/// At least this many imported commands sharing one `when_run` are taken by
+/// `redistribute_timestamps` to be an import that had no timestamps.
+const TIMESTAMP_CLUSTER_MIN_ROWS: i64 = 20;
+
+/// How far back `redistribute_timestamps` spreads such a cluster.
+const REDISTRIBUTE_WINDOW_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Stands in for the current directory when `find_matches_global` builds the cache. `/dev/null`
+/// isn't a directory, so no command is run from here and every directory factor comes out 0,
+/// including for commands whose directory is unknown.
+const NO_DIR: &str = "/dev/null/";
+
+/// Longest stderr snippet `add` stores with a failed command, in characters.
+const ERROR_SNIPPET_MAX_CHARS: usize = 200;
+
+/// How long `add` waits for `History::template_command` before using the built-in template.
+const TEMPLATE_COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Columns `build_cache_table` reads from overlays, besides `id`, `cmd`, and `source`.
+const OVERLAY_COLUMNS: &str = "cmd_tpl, session_id, when_run, exit_code, exit_signal, selected, dir, \
+                               interactive, dangerous, parent, cursor_pos, note, hidden, arg_count, error_snippet";
+
+/// How many results `serve_line_protocol` answers each query with.
+const LINE_PROTOCOL_RESULTS: i16 = 10;
+
+/// How many distinct recent commands `closest` scores against the query.
+const CLOSEST_CANDIDATES: i16 = 5000;
+
+const DANGEROUS_PATTERNS: [&str; 7] = [
+    r"\brm\s+(.*\s)?-[a-zA-Z]*([rR][a-zA-Z]*f|f[a-zA-Z]*[rR])",
+    r"\bmkfs\b",
+    r"\bdd\s+(.*\s)?of=/dev/",
+    r">\s*/dev/sd[a-z]",
+    r"\bgit\s+push\s+(.*\s)?(-f|--force)\b",
+    r"\bgit\s+reset\s+(.*\s)?--hard\b",
+    r"\bchmod\s+(.*\s)?-R\s+(.*\s)?777\b",
+];
+
+/// Prefix of every marker line in the history file; see `EditRequest`.
+const MARKER: &str = "#mcfly:";
+
+/// Prefix of a command saved for editing rather than run: `#mcfly:edit:<cursor_pos> <command>`.
+/// The bundled shell scripts never write these, but `add` accepts them from custom integrations.
+const EDIT_MARKER: &str = "#mcfly:edit:";
+
 const IGNORED_COMMANDS: [&str; 7] = [
     "pwd",
     "ls",
@@ -72,26 +544,179 @@ const IGNORED_COMMANDS: [&str; 7] = [
     "mcfly search",
 ];
 
+/// Subcommands McFly's shell integration runs on its own, which `should_add` never records.
+const MCFLY_INTERNAL_SUBCOMMANDS: [&str; 3] = ["init", "search", "add"];
+
 impl History {
-    pub fn load(history_format: HistoryFormat) -> History {
+    /// Open the history database, importing the shell history into a new one the first time.
+    /// `skip_comments` turns on `History::skip_comments` whatever the config file says.
+    pub fn load(history_format: HistoryFormat, skip_comments: bool) -> History {
+        let config = Config::load_or_default();
         let db_path = Settings::mcfly_db_path();
-        let history = if db_path.exists() {
-            History::from_db_path(db_path)
+        let mut history = if db_path.exists() {
+            History::from_db_path(db_path, &config.database)
         } else {
-            History::from_shell_history(history_format)
+            History::from_shell_history(
+                history_format,
+                history_format.name(),
+                &config,
+                skip_comments,
+            )
         };
         schema::migrate(&history.connection);
+        #[cfg(feature = "compression")]
+        compression::install(&history.connection);
+        history.configure(&config, skip_comments);
         history
     }
 
-    pub fn should_add(&self, command: &str) -> bool {
+    // Apply the config file, then the environment, then `skip_comments` from the command line.
+    fn configure(&mut self, config: &Config, skip_comments: bool) {
+        self.apply_config(config);
+        self.read_env();
+        if skip_comments {
+            self.skip_comments = true;
+        }
+    }
+
+    /// Override the defaults with whatever the config file sets. Nothing in the database changes;
+    /// `maintain` does the retention and directory clean-up the config asks for.
+    pub fn apply_config(&mut self, config: &Config) {
+        if let Some(same_program_weight) = config.weights.same_program {
+            self.same_program_weight = same_program_weight;
+        }
+        if let Some(modifier_weight) = config.weights.modifier {
+            self.modifier_weight = modifier_weight;
+        }
+        if let Some(session_recency_weight) = config.weights.session_recency {
+            self.session_recency_weight = session_recency_weight;
+        }
+        if let Some(arg_count_weight) = config.weights.arg_count {
+            self.arg_count_weight = arg_count_weight;
+        }
+        if let Some(missing_path_weight) = config.weights.missing_path {
+            self.missing_path_weight = missing_path_weight;
+        }
+        if let Some(query_length_weight) = config.weights.query_length {
+            self.query_length_weight = query_length_weight;
+        }
+        if let Some(modifier_programs) = &config.search.modifier_programs {
+            self.modifier_programs = modifier_programs.to_owned();
+        }
+        if let Some(recent_sessions) = config.search.recent_sessions {
+            self.recent_sessions = recent_sessions;
+        }
+        if let Some(equivalent_dirs) = &config.search.equivalent_dirs {
+            self.equivalent_dirs = equivalent_dirs.to_owned();
+        }
+        if let Some(private_dirs) = &config.search.private_dirs {
+            self.private_dirs = private_dirs.to_owned();
+        }
+        if let Some(negative_terms) = config.search.negative_terms {
+            self.negative_terms = negative_terms;
+        }
+        if let Some(unique_templates) = config.search.unique_templates {
+            self.unique_templates = unique_templates;
+        }
+        if let Some(session_context_only) = config.search.session_context_only {
+            self.session_context_only = session_context_only;
+        }
+        if let Some(dir_inodes) = config.search.dir_inodes {
+            self.dir_inodes = dir_inodes;
+        }
+        if let Some(max_history_rows) = config.search.max_history_rows {
+            self.max_history_rows = Some(max_history_rows);
+        }
+        if let Some(occurrence_window_days) = config.search.occurrence_window_days {
+            self.occurrence_window_days = Some(occurrence_window_days);
+        }
+        if let Some(ignored_commands) = &config.ignore.commands {
+            self.ignored_commands = ignored_commands.to_owned();
+        }
+        if let Some(min_occurrences) = config.search.min_occurrences {
+            self.min_occurrences = min_occurrences;
+        }
+        if let Some(lookback) = config.search.lookback {
+            self.lookback = lookback;
+        }
+        if let Some(normalization) = config.search.normalization {
+            self.normalization = Some(normalization);
+        }
+        if let Some(template_placeholders) = config.search.template_placeholders {
+            self.template_placeholders = template_placeholders;
+        }
+        if let Some(template_command) = &config.search.template_command {
+            self.template_command = Some(template_command.to_owned());
+        }
+        if let Some(skip_comments) = config.import.skip_comments {
+            self.skip_comments = skip_comments;
+        }
+        if config.search.home_relative_dirs == Some(true) {
+            self.relative_home = env::var("HOME").ok();
+        }
+        if let Some(max_age_days) = config.retention.max_age_days {
+            self.max_age_days = Some(max_age_days);
+        }
+    }
+
+    /// Delete commands older than `max_age_days` and convert directories already stored under
+    /// `relative_home`, returning how many commands each changed. Both scan the whole history, so
+    /// they're left to `mcfly maintain` rather than done on every start.
+    pub fn maintain(&self, now: i64) -> (usize, usize) {
+        let expired = self.max_age_days.map_or(0, |max_age_days| {
+            self.expire(now - i64::from(max_age_days) * 24 * 60 * 60)
+        });
+        (expired, self.relativize_dirs())
+    }
+
+    // The shell integration exports these for every command, so pick them up once here. Any that
+    // are set win over the config file.
+    fn read_env(&mut self) {
+        self.read_env_from(|name| env::var(name).ok());
+    }
+
+    // `read_env`, looking variables up with `var` so tests don't have to change the process
+    // environment.
+    fn read_env_from<F: Fn(&str) -> Option<String>>(&mut self, var: F) {
+        self.session_id = var("MCFLY_SESSION_ID");
+        self.dir = var("MCFLY_DIR").or_else(|| var("PWD"));
+        if var("MCFLY_TEMPLATE_PLACEHOLDERS").is_some() {
+            self.template_placeholders = true;
+        }
+        self.parent = var("MCFLY_PARENT");
+        self.shlvl = var("MCFLY_SHLVL")
+            .or_else(|| var("SHLVL"))
+            .and_then(|shlvl| shlvl.parse().ok());
+        self.max_shlvl = var("MCFLY_MAX_SHLVL").and_then(|max_shlvl| max_shlvl.parse().ok());
+        self.timing = var("MCFLY_TIMING").is_some();
+        match var("MCFLY_NORMALIZE").as_deref() {
+            Some("minmax") => self.normalization = Some(Normalization::MinMax),
+            Some("zscore") => self.normalization = Some(Normalization::ZScore),
+            _ => {}
+        }
+        if let Some(min_occurrences) =
+            var("MCFLY_MIN_OCCURRENCES").and_then(|min_occurrences| min_occurrences.parse().ok())
+        {
+            self.min_occurrences = min_occurrences;
+        }
+    }
+
+    pub fn should_add(
+        &self,
+        command: &str,
+        session_id: &str,
+        ignore_previous: IgnorePrevious,
+    ) -> bool {
+        // Commands saved for editing are judged by the command itself.
+        let command = History::parse_edit_marker(command).map_or(command, |(command, _)| command);
+
         // Ignore empty commands.
         if command.is_empty() {
             return false;
         }
 
         // Ignore commands added via a ctrl-r search.
-        if command.starts_with("#mcfly:") {
+        if command.starts_with(MARKER) {
             return false;
         }
 
@@ -101,44 +726,429 @@ impl History {
         }
 
         // Ignore blacklisted commands.
-        if IGNORED_COMMANDS.contains(&command) {
+        if self.is_ignored(command) {
+            return false;
+        }
+
+        // Ignore McFly's own setup and bookkeeping commands.
+        if History::is_mcfly_internal(command) {
             return false;
         }
 
-        // Ignore the previous command (independent of Session ID) so that opening a new terminal
-        // window won't replay the last command in the history.
-        let last_command = self.last_command(&None);
+        // Ignore commands from nested shells, if configured.
+        if let (Some(shlvl), Some(max_shlvl)) = (self.shlvl, self.max_shlvl) {
+            if shlvl > max_shlvl {
+                return false;
+            }
+        }
+
+        // Ignore a repeat of the previous command, either within this session or (by default)
+        // independent of Session ID so that opening a new terminal window won't replay the last
+        // command in the history.
+        let last_command = match ignore_previous {
+            IgnorePrevious::Off => return true,
+            IgnorePrevious::Session => self.last_command(&Some(session_id.to_owned())),
+            IgnorePrevious::Global => self.last_command(&None),
+        };
         if last_command.is_none() {
             return true;
         }
         !command.eq(&last_command.unwrap().cmd)
     }
 
+    // True for `mcfly <internal subcommand> ...`, also when wrapped as `eval "$(mcfly init bash)"`.
+    // Commands that only mention mcfly, like `echo mcfly` or `mcfly train`, don't count.
+    fn is_mcfly_internal(command: &str) -> bool {
+        let invocation = command
+            .trim_start_matches("eval ")
+            .trim_start_matches(&['"', '\'', '$', '(', '`'][..]);
+        if !invocation.starts_with("mcfly ") {
+            return false;
+        }
+        invocation
+            .split_whitespace()
+            .nth(1)
+            .into_iter()
+            .any(|subcommand| {
+                let subcommand = subcommand.trim_end_matches(&['"', ')', '`'][..]);
+                MCFLY_INTERNAL_SUBCOMMANDS.contains(&subcommand)
+            })
+    }
+
+    fn is_ignored(&self, command: &str) -> bool {
+        self.ignored_commands.iter().any(|ignored| {
+            if ignored.ends_with('*') {
+                command.starts_with(&ignored[..ignored.len() - 1])
+            } else {
+                command == ignored
+            }
+        })
+    }
+
+    /// Record a command run from `dir`. For `cd` and `pushd`, `dir` is where the command ran and
+    /// `old_dir` is stored as where it went: parsed from the command when possible, otherwise the
+    /// `old_dir` passed in. For other commands `old_dir` is stored as given. An empty or unknown
+    /// `dir` is stored as NULL, which `dir_factor` treats as neither here nor elsewhere.
+    /// `env_hash` is an optional hash of whichever environment variables a custom shell hook cares
+    /// about, for `match_env_hash`; the bundled shell scripts don't pass one.
+    #[allow(clippy::too_many_arguments)]
     pub fn add(
         &self,
         command: &str,
-        session_id: &str,
-        dir: &str,
+        session_id: Option<&str>,
+        dir: Option<&str>,
+        when_run: &Option<i64>,
+        exit_code: Option<i32>,
+        old_dir: &Option<String>,
+        interactive: bool,
+        parent: Option<&str>,
+        error_snippet: Option<&str>,
+        env_hash: Option<&str>,
+    ) {
+        self.insert_command(
+            None,
+            command,
+            session_id,
+            dir,
+            when_run,
+            exit_code,
+            old_dir,
+            interactive,
+            parent,
+            error_snippet,
+            env_hash,
+        );
+    }
+
+    /// `add`, storing the command under `id` rather than the next id, so restoring or merging a
+    /// history keeps commands next to the ones they followed. Returns false, without adding
+    /// anything, if `id` is already taken.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_with_id(
+        &self,
+        id: i64,
+        command: &str,
+        session_id: Option<&str>,
+        dir: Option<&str>,
+        when_run: &Option<i64>,
+        exit_code: Option<i32>,
+        old_dir: &Option<String>,
+        interactive: bool,
+        parent: Option<&str>,
+        error_snippet: Option<&str>,
+        env_hash: Option<&str>,
+    ) -> bool {
+        let taken: bool = self
+            .connection
+            .query_row_named(
+                "SELECT COUNT(*) > 0 FROM main.commands WHERE id = :id",
+                &[(":id", &id)],
+                |row| row.get(0),
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
+        if taken {
+            return false;
+        }
+        self.insert_command(
+            Some(id),
+            command,
+            session_id,
+            dir,
+            when_run,
+            exit_code,
+            old_dir,
+            interactive,
+            parent,
+            error_snippet,
+            env_hash,
+        );
+        true
+    }
+
+    // `add`, with SQLite choosing the id when `id` is `None`.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_command(
+        &self,
+        id: Option<i64>,
+        command: &str,
+        session_id: Option<&str>,
+        dir: Option<&str>,
         when_run: &Option<i64>,
         exit_code: Option<i32>,
         old_dir: &Option<String>,
+        interactive: bool,
+        parent: Option<&str>,
+        error_snippet: Option<&str>,
+        env_hash: Option<&str>,
     ) {
+        let session_id = session_id
+            .or(self.session_id.as_deref())
+            .unwrap_or_else(|| {
+                panic!(
+                    "McFly error: Please ensure that MCFLY_SESSION_ID contains a random session ID"
+                )
+            });
+        let dir = dir.or(self.dir.as_deref()).filter(|dir| !dir.is_empty());
+        let parent = parent.or(self.parent.as_deref());
+        let (command, cursor_pos) = match History::parse_edit_marker(command) {
+            Some((command, cursor_pos)) => (command, Some(cursor_pos)),
+            None => (command, None),
+        };
         self.possibly_update_paths(command, exit_code);
-        let selected = self.determine_if_selected_from_ui(command, session_id, dir);
-        let simplified_command = SimplifiedCommand::new(command, true);
-        self.connection.execute_named("INSERT INTO commands (cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, old_dir) VALUES (:cmd, :cmd_tpl, :session_id, :when_run, :exit_code, :selected, :dir, :old_dir)",
+        let old_dir = dir
+            .and_then(|dir| path_update_helpers::parse_cd_command(command, dir))
+            .or_else(|| old_dir.to_owned());
+        let selected = self.determine_if_selected_from_ui(command, session_id, dir.unwrap_or(""));
+        let dir_inode = dir.and_then(|dir| self.dir_inode(dir));
+        let dir = dir.map(|dir| self.home_relative(dir));
+        let old_dir = old_dir.map(|old_dir| self.home_relative(&old_dir));
+        let cmd_tpl = self.template(command);
+        let exit_signal = exit_code.and_then(History::signal_from_exit_code);
+        let dangerous = History::is_dangerous(command, &self.dangerous_patterns);
+        let error_snippet = error_snippet
+            .filter(|_| exit_code != Some(0))
+            .and_then(History::truncate_error_snippet);
+        self.connection.execute_named("INSERT INTO main.commands (id, cmd, cmd_tpl, session_id, when_run, exit_code, exit_signal, selected, dir, old_dir, interactive, dangerous, parent, cursor_pos, arg_count, error_snippet, env_hash, dir_inode) VALUES (:id, mcfly_compress(:cmd), :cmd_tpl, :session_id, :when_run, :exit_code, :exit_signal, :selected, :dir, :old_dir, :interactive, :dangerous, :parent, :cursor_pos, :arg_count, :error_snippet, :env_hash, :dir_inode)",
                                       &[
+                                          (":id", &id),
                                           (":cmd", &command.to_owned()),
-                                          (":cmd_tpl", &simplified_command.result.to_owned()),
+                                          (":cmd_tpl", &cmd_tpl),
                                           (":session_id", &session_id.to_owned()),
                                           (":when_run", &when_run.to_owned()),
                                           (":exit_code", &exit_code.to_owned()),
+                                          (":exit_signal", &exit_signal),
                                           (":selected", &selected),
-                                          (":dir", &dir.to_owned()),
-                                          (":old_dir", &old_dir.to_owned()),
+                                          (":dir", &dir),
+                                          (":old_dir", &old_dir),
+                                          (":interactive", &interactive),
+                                          (":dangerous", &dangerous),
+                                          (":parent", &parent),
+                                          (":cursor_pos", &cursor_pos),
+                                          (":arg_count", &History::arg_count(command)),
+                                          (":error_snippet", &error_snippet),
+                                          (":env_hash", &env_hash),
+                                          (":dir_inode", &dir_inode),
                                       ]).unwrap_or_else(|err| panic!(format!("McFly error: Insert into commands to work ({})", err)));
     }
 
+    // The first non-blank line of `stderr`, cut to `ERROR_SNIPPET_MAX_CHARS` characters.
+    fn truncate_error_snippet(stderr: &str) -> Option<String> {
+        stderr
+            .lines()
+            .map(str::trim_end)
+            .find(|line| !line.trim().is_empty())
+            .map(|line| line.chars().take(ERROR_SNIPPET_MAX_CHARS).collect())
+    }
+
+    /// The template `add` records for `command`: the output of `template_command` if one is set
+    /// and it succeeds in time, otherwise the built-in `SimplifiedCommand`.
+    pub fn template(&self, command: &str) -> String {
+        self.template_command
+            .as_ref()
+            .and_then(|program| History::run_template_command(program, command).ok())
+            .unwrap_or_else(|| {
+                SimplifiedCommand::with_placeholders(command, true, self.template_placeholders)
+                    .result
+            })
+    }
+
+    // Run `program` through `sh -c`, writing the command to its stdin and reading the template
+    // from its stdout. Anything but a quick, successful, non-empty answer is an error.
+    fn run_template_command(program: &str, command: &str) -> io::Result<String> {
+        let mut child = process::Command::new("sh")
+            .arg("-c")
+            .arg(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(command.as_bytes())?;
+        }
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() > TEMPLATE_COMMAND_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "McFly error: Template command took too long",
+                ));
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        let mut template = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_string(&mut template)?;
+        }
+        let template = template.trim_end_matches('\n');
+        if !status.success() || template.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "McFly error: Template command failed",
+            ));
+        }
+        Ok(template.to_string())
+    }
+
+    /// How many arguments follow the program in `command`. Quoted strings count as one argument
+    /// however many spaces they hold, and a backslash escapes the character after it.
+    pub fn arg_count(command: &str) -> i64 {
+        let mut tokens = 0;
+        let mut in_token = false;
+        let mut quote = None;
+        let mut escaped = false;
+        for c in command.chars() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && quote != Some('\'') {
+                escaped = true;
+            } else if Some(c) == quote {
+                quote = None;
+                continue;
+            } else if quote.is_none() && (c == '"' || c == '\'') {
+                quote = Some(c);
+            } else if quote.is_none() && c.is_whitespace() {
+                in_token = false;
+                continue;
+            }
+            if !in_token {
+                tokens += 1;
+                in_token = true;
+            }
+        }
+        (tokens - 1).max(0)
+    }
+
+    /// Split a `#mcfly:edit:<cursor_pos> <command>` marker into the command and cursor position.
+    /// Returns `None` for anything else, including malformed markers.
+    pub fn parse_edit_marker(command: &str) -> Option<(&str, i64)> {
+        if !command.starts_with(EDIT_MARKER) {
+            return None;
+        }
+        let mut parts = command[EDIT_MARKER.len()..].splitn(2, ' ');
+        let cursor_pos = parts.next()?.parse::<i64>().ok()?;
+        let command = parts.next()?;
+        if cursor_pos < 0 || cursor_pos as usize > command.len() {
+            return None;
+        }
+        Some((command, cursor_pos))
+    }
+
+    /// Decode a `#mcfly:` marker line into what it asks for. Returns `None` for lines that aren't
+    /// markers and for malformed edit markers.
+    pub fn parse_mcfly_marker(line: &str) -> Option<EditRequest> {
+        if line.starts_with(EDIT_MARKER) {
+            return History::parse_edit_marker(line).map(|(command, cursor_pos)| {
+                EditRequest::Edit {
+                    command: command.to_string(),
+                    cursor_pos,
+                }
+            });
+        }
+        if !line.starts_with(MARKER) {
+            return None;
+        }
+        let query = &line[MARKER.len()..];
+        Some(EditRequest::Search {
+            query: query.trim_start_matches(' ').to_string(),
+        })
+    }
+
+    pub fn default_dangerous_patterns() -> Vec<Regex> {
+        DANGEROUS_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect()
+    }
+
+    pub fn is_dangerous(command: &str, patterns: &[Regex]) -> bool {
+        patterns.iter().any(|pattern| pattern.is_match(command))
+    }
+
+    /// Override the dangerous flag that `add` set for a command.
+    pub fn set_dangerous(&self, id: i64, dangerous: bool) {
+        self.connection
+            .execute_named(
+                "UPDATE main.commands SET dangerous = :dangerous WHERE id = :id",
+                &[(":dangerous", &dangerous), (":id", &id)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: UPDATE of dangerous to work ({})",
+                    err
+                ))
+            });
+    }
+
+    /// Hide a command from suggestions without deleting it: every run of the command with this id
+    /// is left out of the contextual cache, but still counts everywhere else.
+    pub fn hide(&self, id: i64) {
+        self.set_hidden(id, true);
+    }
+
+    pub fn unhide(&self, id: i64) {
+        self.set_hidden(id, false);
+    }
+
+    fn set_hidden(&self, id: i64, hidden: bool) {
+        self.connection
+            .execute_named(
+                "UPDATE main.commands SET hidden = :hidden WHERE cmd = (SELECT cmd FROM main.commands WHERE id = :id)",
+                &[(":hidden", &hidden), (":id", &id)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!("McFly error: UPDATE of hidden to work ({})", err))
+            });
+    }
+
+    /// Attach a note to a command, or clear it with `None`.
+    pub fn set_note(&self, id: i64, note: Option<&str>) {
+        self.connection
+            .execute_named(
+                "UPDATE main.commands SET note = :note WHERE id = :id",
+                &[(":note", &note), (":id", &id)],
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: UPDATE of note to work ({})", err)));
+    }
+
+    /// Tag every command containing `query`, returning how many commands gained the tag.
+    pub fn tag_matching(&self, query: &str, tag: &str) -> rusqlite::Result<usize> {
+        let like_query = format!("%{}%", History::escape_like(query));
+        self.connection.execute_named(
+            "INSERT OR IGNORE INTO command_tags (command_id, tag)
+             SELECT id, :tag FROM commands WHERE cmd LIKE (:like) ESCAPE '\\'",
+            &[(":tag", &tag), (":like", &like_query)],
+        )
+    }
+
+    /// The tags on a command, alphabetically.
+    pub fn tags(&self, id: i64) -> Vec<String> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT tag FROM command_tags WHERE command_id = :id ORDER BY tag")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let tag_iter = statement
+            .query_map_named(&[(":id", &id)], |row| row.get(0))
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        tag_iter.flatten().collect()
+    }
+
+    // Shells report a command killed by signal N (e.g. SIGINT from Ctrl-C) as exit code 128 + N.
+    fn signal_from_exit_code(exit_code: i32) -> Option<i32> {
+        if exit_code > 128 && exit_code <= 128 + MAX_SIGNAL {
+            Some(exit_code - 128)
+        } else {
+            None
+        }
+    }
+
     fn determine_if_selected_from_ui(&self, command: &str, session_id: &str, dir: &str) -> bool {
         let rows_affected = self
             .connection
@@ -233,30 +1243,146 @@ impl History {
         }
     }
 
-    pub fn find_matches(&self, cmd: &str, num: i16, fuzzy: bool) -> Vec<Command> {
-        let mut like_query = "%".to_string();
-
-        if fuzzy {
-            like_query.push_str(&cmd.split("").collect::<Vec<&str>>().join("%"));
-        } else {
-            like_query.push_str(cmd);
-        }
-
-        like_query.push_str("%");
+    pub fn find_matches(
+        &self,
+        cmd: &str,
+        num: i16,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+    ) -> Vec<Command> {
+        let weights = self.rank_weights(&Weights::default());
+        self.find_matches_weighted(cmd, num, fuzzy, interactive_only, match_mode, &weights)
+    }
 
-        let query = "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, rank,
-                                  age_factor, length_factor, exit_factor, recent_failure_factor,
-                                  selected_dir_factor, dir_factor, overlap_factor, immediate_overlap_factor,
-                                  selected_occurrences_factor, occurrences_factor
+    /// The top `num` substring matches for `cmd` from `dir`, ranked as if the `[weights]` config
+    /// were `weights` layered over the current ones, for previewing a change before saving it.
+    /// Only those bonus weights can be previewed; the network's weights, including the one for
+    /// `dir_factor`, stay as they are. Ranking happens in a scratch table, so neither `self` nor
+    /// the cache from the last `build_cache_table` changes.
+    pub fn find_matches_with_weights(
+        &self,
+        cmd: &str,
+        dir: Option<&str>,
+        weights: &Weights,
+        num: i16,
+    ) -> Vec<Command> {
+        let cached: bool = self
+            .connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_temp_master WHERE type = 'table' AND name = 'contextual_commands'",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
+        if cached {
+            self.run_batch(
+                "ALTER TABLE temp.contextual_commands RENAME TO contextual_commands_saved;",
+                "Renaming of temp table",
+            );
+        }
+
+        let weights = self.rank_weights(weights);
+        self.build_cache_table_matching(dir, &None, None, None, None, None, None, &weights);
+        let matches =
+            self.find_matches_weighted(cmd, num, false, false, MatchMode::Substring, &weights);
+
+        self.run_batch(
+            "DROP TABLE temp.contextual_commands;",
+            "Removal of temp table",
+        );
+        if cached {
+            self.run_batch(
+                "ALTER TABLE temp.contextual_commands_saved RENAME TO contextual_commands;",
+                "Renaming of temp table",
+            );
+        }
+        matches
+    }
+
+    fn run_batch(&self, sql: &str, what: &str) {
+        self.connection
+            .execute_batch(sql)
+            .unwrap_or_else(|err| panic!(format!("McFly error: {} to work ({})", what, err)));
+    }
+
+    // The weights in `overrides`, with the current ones for any it leaves unset.
+    fn rank_weights(&self, overrides: &Weights) -> RankWeights {
+        RankWeights {
+            same_program: overrides.same_program.unwrap_or(self.same_program_weight),
+            modifier: overrides.modifier.unwrap_or(self.modifier_weight),
+            session_recency: overrides
+                .session_recency
+                .unwrap_or(self.session_recency_weight),
+            arg_count: overrides.arg_count.unwrap_or(self.arg_count_weight),
+            missing_path: overrides.missing_path.unwrap_or(self.missing_path_weight),
+            query_length: overrides.query_length.unwrap_or(self.query_length_weight),
+        }
+    }
+
+    fn find_matches_weighted(
+        &self,
+        cmd: &str,
+        num: i16,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+        weights: &RankWeights,
+    ) -> Vec<Command> {
+        let started = Instant::now();
+        let (cmd, excluded_terms) = self.split_excluded_terms(cmd);
+        let cmd = cmd.as_str();
+        let like_query = History::like_query(cmd, fuzzy, match_mode);
+        let exclude_names: Vec<String> = (0..excluded_terms.len())
+            .map(|i| format!(":exclude{}", i))
+            .collect();
+        let exclusions: String = exclude_names
+            .iter()
+            .map(|name| format!(" AND cmd NOT LIKE ({}) ESCAPE '\\'", name))
+            .collect();
+
+        // Break ties in rank by recency (a smaller age_factor), then by id, so equally ranked
+        // results don't jitter between searches. `rank` in ORDER BY is the adjusted result column.
+        let query = format!("SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir,
+                                  rank + CASE WHEN arg_count IS NULL THEN 0.0
+                                              ELSE :arg_count_weight / (1.0 + ABS(arg_count - :arg_count)) END AS rank,
+                                  age_factor, length_factor, exit_factor, recent_failure_factor,
+                                  selected_dir_factor, dir_factor, overlap_factor, immediate_overlap_factor,
+                                  selected_occurrences_factor, occurrences_factor, dangerous, parent, cursor_pos, note, source, error_snippet
                            FROM contextual_commands
-                           WHERE cmd LIKE (:like)
-                           ORDER BY rank DESC LIMIT :limit";
+                           WHERE cmd LIKE (:like) ESCAPE '\\'{}
+                           AND (:interactive_only = 0 OR interactive = 1)
+                           AND (:env_hash IS NULL OR cmd IN (SELECT cmd FROM commands WHERE env_hash = :env_hash))
+                           ORDER BY rank DESC, age_factor ASC, id DESC LIMIT :limit", exclusions);
+        // Normalizing, adjusting ranks in Rust, and dropping repeated templates need every match,
+        // not just the ones that currently rank highest.
+        let limit = if self.normalization.is_some()
+            || weights.missing_path != 0.0
+            || weights.query_length != 0.0
+            || self.unique_templates
+        {
+            -1
+        } else {
+            num
+        };
+        let arg_count = History::arg_count(cmd);
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![
+            (":like", &like_query),
+            (":interactive_only", &interactive_only),
+            (":env_hash", &self.match_env_hash),
+            (":arg_count_weight", &weights.arg_count),
+            (":arg_count", &arg_count),
+            (":limit", &limit),
+        ];
+        for (name, term) in exclude_names.iter().zip(&excluded_terms) {
+            params.push((name, term));
+        }
         let mut statement = self
             .connection
-            .prepare(query)
+            .prepare(&query)
             .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
         let command_iter = statement
-            .query_map_named(&[(":like", &like_query), (":limit", &num)], |row| {
+            .query_map_named(&params, |row| {
                 let text: String = row.get_checked(1).unwrap_or_else(|err| {
                     panic!(format!("McFly error: cmd to be readable ({})", err))
                 });
@@ -285,6 +1411,9 @@ impl History {
 
                         vec![(start, end)]
                     }
+                    false if cmd.contains('*') => {
+                        History::wildcard_bounds(&lowercase_text, &lowercase_cmd)
+                    }
                     false => lowercase_text
                         .match_indices(&lowercase_cmd)
                         .map(|(index, _)| (index, index + cmd.len()))
@@ -317,6 +1446,27 @@ impl History {
                     rank: row.get_checked(8).unwrap_or_else(|err| {
                         panic!(format!("McFly error: rank to be readable ({})", err))
                     }),
+                    dangerous: row.get_checked(19).unwrap_or_else(|err| {
+                        panic!(format!("McFly error: dangerous to be readable ({})", err))
+                    }),
+                    parent: row.get_checked(20).unwrap_or_else(|err| {
+                        panic!(format!("McFly error: parent to be readable ({})", err))
+                    }),
+                    cursor_pos: row.get_checked(21).unwrap_or_else(|err| {
+                        panic!(format!("McFly error: cursor_pos to be readable ({})", err))
+                    }),
+                    note: row.get_checked(22).unwrap_or_else(|err| {
+                        panic!(format!("McFly error: note to be readable ({})", err))
+                    }),
+                    source: row.get_checked(23).unwrap_or_else(|err| {
+                        panic!(format!("McFly error: source to be readable ({})", err))
+                    }),
+                    error_snippet: row.get_checked(24).unwrap_or_else(|err| {
+                        panic!(format!(
+                            "McFly error: error_snippet to be readable ({})",
+                            err
+                        ))
+                    }),
                     match_bounds: bounds,
                     features: Features {
                         age_factor: row.get_checked(9).unwrap_or_else(|err| {
@@ -385,6 +1535,18 @@ impl History {
             }));
         }
 
+        if let Some(normalization) = self.normalization {
+            self.normalize(&mut names, normalization);
+        }
+
+        if weights.missing_path != 0.0 {
+            History::penalize_missing_paths(&mut names, weights.missing_path);
+        }
+
+        if weights.query_length != 0.0 {
+            History::favor_similar_lengths(&mut names, cmd, weights.query_length);
+        }
+
         if fuzzy {
             names = names
                 .into_iter()
@@ -407,401 +1569,5154 @@ impl History {
                 .collect()
         }
 
+        if self.unique_templates {
+            let mut seen = HashSet::new();
+            names.retain(|command| seen.insert(command.cmd_tpl.to_owned()));
+        }
+
+        names = self.put_preferred_first(cmd, names);
+        if num >= 0 {
+            names.truncate(num as usize);
+        }
+
+        if self.timing {
+            self.timings.set(Timings {
+                find_matches: Some(started.elapsed()),
+                ..self.timings.get()
+            });
+        }
+
         names
     }
 
-    pub fn build_cache_table(
-        &self,
-        dir: &str,
-        session_id: &Option<String>,
-        start_time: Option<i64>,
-        end_time: Option<i64>,
-        now: Option<i64>,
-    ) {
-        let lookback: u16 = 3;
+    // Rescale each factor across `commands`, adjust their ranks by the difference that makes to
+    // the network's output (keeping any bonuses added to the rank), and re-sort by rank.
+    fn normalize(&self, commands: &mut [Command], normalization: Normalization) {
+        if commands.len() < 2 {
+            return;
+        }
 
-        let mut last_commands = self.last_command_templates(session_id, lookback as i16, 0);
-        if last_commands.len() < lookback as usize {
-            last_commands = self.last_command_templates(&None, lookback as i16, 0);
-            while last_commands.len() < lookback as usize {
-                last_commands.push(String::from(""));
+        let raw_outputs: Vec<f64> = commands
+            .iter()
+            .map(|command| self.network.output(&command.features))
+            .collect();
+        let factor_count = Features::default().factors_mut().len();
+        for factor in 0..factor_count {
+            let values: Vec<f64> = commands
+                .iter_mut()
+                .map(|command| *command.features.factors_mut()[factor])
+                .collect();
+            let (shift, scale) = match normalization {
+                Normalization::MinMax => {
+                    let min = values.iter().cloned().fold(std::f64::INFINITY, f64::min);
+                    let max = values
+                        .iter()
+                        .cloned()
+                        .fold(std::f64::NEG_INFINITY, f64::max);
+                    (min, max - min)
+                }
+                Normalization::ZScore => {
+                    let mean = values.iter().sum::<f64>() / values.len() as f64;
+                    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()
+                        / values.len() as f64;
+                    (mean, variance.sqrt())
+                }
+            };
+            for (command, value) in commands.iter_mut().zip(values) {
+                *command.features.factors_mut()[factor] = if scale > std::f64::EPSILON {
+                    (value - shift) / scale
+                } else {
+                    0.0
+                };
             }
         }
 
-        self.connection
-            .execute("DROP TABLE IF EXISTS temp.contextual_commands;", NO_PARAMS)
-            .unwrap_or_else(|err| {
-                panic!(format!(
-                    "McFly error: Removal of temp table to work ({})",
-                    err
-                ))
-            });
-
-        let (mut when_run_min, when_run_max): (f64, f64) = self
-            .connection
-            .query_row(
-                "SELECT MIN(when_run), MAX(when_run) FROM commands",
-                NO_PARAMS,
-                |row| (row.get(0), row.get(1)),
-            )
-            .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
-
-        if (when_run_min - when_run_max).abs() < std::f64::EPSILON {
-            when_run_min -= 60.0 * 60.0;
+        for (command, raw_output) in commands.iter_mut().zip(raw_outputs) {
+            command.rank += self.network.output(&command.features) - raw_output;
         }
+        // Stable, so ties keep the recency order from the query.
+        commands.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal));
+    }
 
-        let max_occurrences: f64 = self
-            .connection
-            .query_row(
-                "SELECT COUNT(*) AS c FROM commands GROUP BY cmd ORDER BY c DESC LIMIT 1",
-                NO_PARAMS,
-                |row| row.get(0),
-            )
-            .unwrap_or(1.0);
+    // Every match contains the search, so the ratio of their lengths is at most 1, reached when
+    // the search is the whole command.
+    fn favor_similar_lengths(commands: &mut [Command], query: &str, weight: f64) {
+        let query_length = query.chars().count() as f64;
+        for command in commands.iter_mut() {
+            let length = command.cmd.chars().count().max(1) as f64;
+            command.rank += weight * (query_length / length).min(1.0);
+        }
+        commands.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal));
+    }
 
-        let max_selected_occurrences: f64 = self.connection
-            .query_row("SELECT COUNT(*) AS c FROM commands WHERE selected = 1 GROUP BY cmd ORDER BY c DESC LIMIT 1", NO_PARAMS,
-                       |row| row.get(0)).unwrap_or(1.0);
+    // Lower the rank of commands with an absolute path argument that doesn't exist, and re-sort by
+    // rank. Each path is only looked up once.
+    fn penalize_missing_paths(commands: &mut [Command], weight: f64) {
+        let mut exists: HashMap<String, bool> = HashMap::new();
+        for command in commands.iter_mut() {
+            let missing = History::absolute_paths(&command.cmd)
+                .into_iter()
+                .any(|path| {
+                    !*exists
+                        .entry(path.to_string())
+                        .or_insert_with(|| Path::new(path).exists())
+                });
+            if missing {
+                command.rank -= weight;
+            }
+        }
+        // Stable, so ties keep the recency order from the query.
+        commands.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal));
+    }
 
-        let max_length: f64 = self
-            .connection
-            .query_row("SELECT MAX(LENGTH(cmd)) FROM commands", NO_PARAMS, |row| {
-                row.get(0)
-            })
-            .unwrap_or(100.0);
+    // Arguments of `cmd` that look like absolute paths, including `--opt=/path` values.
+    fn absolute_paths(cmd: &str) -> Vec<&str> {
+        cmd.split_whitespace()
+            .skip(1)
+            .map(|arg| arg.trim_matches(&['\'', '"'][..]))
+            .map(|arg| arg.splitn(2, '=').last().unwrap_or(arg))
+            .filter(|arg| arg.len() > 1 && arg.starts_with('/'))
+            .collect()
+    }
 
-        #[allow(unused_variables)]
-        let beginning_of_execution = Instant::now();
-        self.connection.execute_named(
-            "CREATE TEMP TABLE contextual_commands AS SELECT
-                  id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir,
+    // Bounds of each `*`-separated part of `query`, matched left to right in `text`.
+    fn wildcard_bounds(text: &str, query: &str) -> Vec<(usize, usize)> {
+        let mut bounds = Vec::new();
+        let mut from = 0;
+        for part in query.split('*').filter(|part| !part.is_empty()) {
+            if let Some(index) = text[from..].find(part) {
+                bounds.push((from + index, from + index + part.len()));
+                from += index + part.len();
+            }
+        }
+        bounds
+    }
 
-                  /* to be filled in later */
-                  0.0 AS rank,
+    // Move the preferred commands for any prefix of `cmd` to the front, longest prefix first.
+    // Preferred commands that were never run are added as long as they contain `cmd`.
+    fn put_preferred_first(&self, cmd: &str, mut names: Vec<Command>) -> Vec<Command> {
+        let lowercase_cmd = cmd.to_lowercase();
+        let preferred = self
+            .preferred_commands
+            .iter()
+            .filter(|(prefix, _)| !prefix.is_empty() && cmd.starts_with(prefix.as_str()))
+            .sorted_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()))
+            .flat_map(|(_, commands)| commands.iter())
+            .unique()
+            .collect::<Vec<_>>();
 
-                  /* length of the command string */
-                  LENGTH(c.cmd) / :max_length AS length_factor,
+        let mut first = Vec::new();
+        for preferred_cmd in preferred {
+            if let Some(index) = names.iter().position(|c| &c.cmd == preferred_cmd) {
+                first.push(names.remove(index));
+            } else if preferred_cmd.to_lowercase().contains(&lowercase_cmd) {
+                first.push(Command {
+                    cmd: preferred_cmd.to_owned(),
+                    cmd_tpl: SimplifiedCommand::new(preferred_cmd.as_str(), true).result,
+                    match_bounds: preferred_cmd
+                        .to_lowercase()
+                        .match_indices(&lowercase_cmd)
+                        .map(|(index, _)| (index, index + cmd.len()))
+                        .collect(),
+                    ..Command::default()
+                });
+            }
+        }
 
-                  /* age of the last execution of this command (0.0 is new, 1.0 is old) */
-                  MIN((:when_run_max - when_run) / :history_duration) AS age_factor,
+        first.extend(names);
+        first
+    }
 
-                  /* average error state (1: always successful, 0: always errors) */
-                  SUM(CASE WHEN exit_code = 0 THEN 1.0 ELSE 0.0 END) / COUNT(*) as exit_factor,
+    // Split `-term` words out of a search when `negative_terms` is on, returning the rest of the
+    // search and a LIKE pattern for each excluded term.
+    fn split_excluded_terms(&self, cmd: &str) -> (String, Vec<String>) {
+        if !self.negative_terms {
+            return (cmd.to_string(), Vec::new());
+        }
+        let (excluded, kept): (Vec<&str>, Vec<&str>) = cmd
+            .split(' ')
+            .partition(|word| word.len() > 1 && word.starts_with('-'));
+        let patterns = excluded
+            .iter()
+            .map(|word| format!("%{}%", History::escape_like(&word[1..])))
+            .collect();
+        (kept.join(" "), patterns)
+    }
 
-                  /* recent failure (1 if failed recently, 0 if not) */
-                  MAX(CASE WHEN exit_code != 0 AND :now - when_run < 120 THEN 1.0 ELSE 0.0 END) AS recent_failure_factor,
+    // The LIKE pattern `find_matches` filters on.
+    fn like_query(cmd: &str, fuzzy: bool, match_mode: MatchMode) -> String {
+        let mut like_query = match match_mode {
+            MatchMode::Substring => "%".to_string(),
+            MatchMode::Prefix => String::new(),
+        };
 
-                  /* percentage run in this directory (1: always run in this directory, 0: never run in this directory) */
-                  SUM(CASE WHEN dir = :directory THEN 1.0 ELSE 0.0 END) / COUNT(*) as dir_factor,
+        if fuzzy {
+            like_query.push_str(
+                &cmd.chars()
+                    .map(|c| History::escape_like(&c.to_string()))
+                    .join("%"),
+            );
+        } else {
+            // `*` matches any run of characters, so `git*push` finds `git ... push`.
+            like_query.push_str(&cmd.split('*').map(History::escape_like).join("%"));
+        }
 
-                  /* percentage of time selected in this directory (1: only selected in this dir, 0: only selected elsewhere) */
-                  SUM(CASE WHEN dir = :directory AND selected = 1 THEN 1.0 ELSE 0.0 END) / (SUM(CASE WHEN selected = 1 THEN 1.0 ELSE 0.0 END) + 1) as selected_dir_factor,
+        like_query.push_str("%");
+        like_query
+    }
 
-                  /* average contextual overlap of this command (0: none of the last 3 commands has ever overlapped with this command, 1: all of the last three commands always overlap with this command) */
-                  SUM((
-                    SELECT COUNT(DISTINCT c2.cmd_tpl) FROM commands c2
-                    WHERE c2.id >= c.id - :lookback AND c2.id < c.id AND c2.cmd_tpl IN (:last_commands0, :last_commands1, :last_commands2)
-                  ) / :lookback_f64) / COUNT(*) AS overlap_factor,
+    // Make `%`, `_`, and `\` in a search term match literally in a LIKE pattern with ESCAPE '\'.
+    fn escape_like(term: &str) -> String {
+        let mut escaped = String::with_capacity(term.len());
+        for c in term.chars() {
+            if c == '%' || c == '_' || c == '\\' {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
 
-                  /* average overlap with the last command (0: this command never follows the last command, 1: this command always follows the last command) */
-                  SUM((SELECT COUNT(*) FROM commands c2 WHERE c2.id = c.id - 1 AND c2.cmd_tpl = :last_commands0)) / COUNT(*) AS immediate_overlap_factor,
+    /// "Did you mean" suggestions: the `num` distinct commands closest to `query` by edit distance,
+    /// drawn from the most recently run commands and returned with their distances.
+    pub fn closest(&self, query: &str, num: usize) -> Vec<(Command, usize)> {
+        let candidates = self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             GROUP BY cmd
+             ORDER BY MAX(id) DESC
+             LIMIT :limit",
+            &[(":limit", &CLOSEST_CANDIDATES)],
+        );
 
-                  /* percentage selected (1: this is the most commonly selected command, 0: this command is never selected) */
-                  SUM(CASE WHEN selected = 1 THEN 1.0 ELSE 0.0 END) / :max_selected_occurrences AS selected_occurrences_factor,
+        // The sort is stable, so equally close commands stay in most-recent-first order.
+        candidates
+            .into_iter()
+            .map(|command| {
+                let distance = edit_distance::levenshtein(query, &command.cmd);
+                (command, distance)
+            })
+            .sorted_by_key(|(_, distance)| *distance)
+            .take(num)
+            .collect()
+    }
 
-                  /* percentage of time this command is run relative to the most common command (1: this is the most common command, 0: this is the least common command) */
-                  COUNT(*) / :max_occurrences AS occurrences_factor
+    /// Prefetch: rank every command for `dir` and `session_id` ahead of time (e.g. on `cd`), so
+    /// the next search only has to read the cache. Returns without fetching any matches.
+    pub fn warm_cache(&self, dir: Option<&str>, session_id: &Option<String>) {
+        self.build_cache_table(dir, session_id, None, None, None);
+    }
 
-                  FROM commands c WHERE when_run > :start_time AND when_run < :end_time GROUP BY cmd ORDER BY id DESC;",
-            &[
-                (":when_run_max", &when_run_max),
-                (":history_duration", &(when_run_max - when_run_min)),
-                (":directory", &dir.to_owned()),
-                (":max_occurrences", &max_occurrences),
-                (":max_length", &max_length),
-                (":max_selected_occurrences", &max_selected_occurrences),
-                (":lookback", &lookback),
-                (":lookback_f64", &(lookback as f64)),
-                (":last_commands0", &last_commands[0].to_owned()),
-                (":last_commands1", &last_commands[1].to_owned()),
-                (":last_commands2", &last_commands[2].to_owned()),
-                (":start_time", &start_time.unwrap_or(0).to_owned()),
-                (":end_time", &end_time.unwrap_or(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err))).as_secs() as i64).to_owned()),
-                (":now", &now.unwrap_or(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err))).as_secs() as i64).to_owned())
-            ]).unwrap_or_else(|err| panic!(format!("McFly error: Creation of temp table to work ({})", err)));
+    /// Durations of the last cache build and search. Always empty unless `timing` is set.
+    pub fn timings(&self) -> Timings {
+        self.timings.get()
+    }
 
-        self.connection
-            .execute(
-                "UPDATE contextual_commands
-                 SET rank = nn_rank(age_factor, length_factor, exit_factor,
-                                    recent_failure_factor, selected_dir_factor, dir_factor,
-                                    overlap_factor, immediate_overlap_factor,
-                                    selected_occurrences_factor, occurrences_factor);",
-                NO_PARAMS,
+    /// The top `num` commands for `dir`, wherever the user is now: commands that have been run in
+    /// `dir` (or one of `equivalent_dirs`) come first, each group ordered by rank. Rebuilds the
+    /// contextual cache for `dir`.
+    pub fn palette_for(&self, dir: &str, num: usize) -> Vec<Command> {
+        self.build_cache_table(Some(dir), &None, None, None, None);
+        let mut statement = self
+            .connection
+            .prepare("SELECT DISTINCT cmd FROM commands WHERE dir = :directory OR dir IN (SELECT dir FROM temp.equivalent_dirs) OR dir_inode = :dir_inode")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let run_here: HashSet<String> = statement
+            .query_map_named(
+                &[
+                    (":directory", &self.home_relative(dir)),
+                    (":dir_inode", &self.dir_inode(dir)),
+                ],
+                |row| row.get(0),
             )
-            .unwrap_or_else(|err| {
-                panic!(format!(
-                    "McFly error: Ranking of temp table to work ({})",
-                    err
-                ))
-            });
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)))
+            .flatten()
+            .collect();
+        let (mut palette, elsewhere): (Vec<Command>, Vec<Command>) = self
+            .find_matches("", -1, false, false, MatchMode::Substring)
+            .into_iter()
+            .partition(|command| run_here.contains(&command.cmd));
+        palette.extend(elsewhere);
+        palette.truncate(num);
+        palette
+    }
 
-        self.connection
-            .execute(
-                "CREATE INDEX temp.MyIndex ON contextual_commands(id);",
-                NO_PARAMS,
-            )
-            .unwrap_or_else(|err| {
-                panic!(format!(
-                    "McFly error: Creation of index on temp table to work ({})",
-                    err
-                ))
-            });
+    /// `find_matches`, handling commands flagged as dangerous according to `policy`. With
+    /// `Exclude` and `Separate`, `num` applies to each list, so leaving out dangerous commands
+    /// doesn't leave fewer safe ones.
+    pub fn find_matches_with_policy(
+        &self,
+        cmd: &str,
+        num: i16,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+        policy: DangerPolicy,
+    ) -> SplitMatches {
+        if policy == DangerPolicy::Include {
+            return SplitMatches {
+                matches: self.find_matches(cmd, num, fuzzy, interactive_only, match_mode),
+                dangerous: Vec::new(),
+            };
+        }
 
-        // println!("Seconds: {}", (beginning_of_execution.elapsed().as_secs() as f64) + (beginning_of_execution.elapsed().subsec_nanos() as f64 / 1000_000_000.0));
+        let (mut dangerous, mut matches): (Vec<Command>, Vec<Command>) = self
+            .find_matches(cmd, -1, fuzzy, interactive_only, match_mode)
+            .into_iter()
+            .partition(|command| command.dangerous);
+        if num >= 0 {
+            matches.truncate(num as usize);
+            dangerous.truncate(num as usize);
+        }
+        if policy == DangerPolicy::Exclude {
+            dangerous.clear();
+        }
+        SplitMatches { matches, dangerous }
     }
 
-    pub fn commands(
+    /// `find_matches`, first rebuilding the cache from only the commands matching `cmd`. On a large
+    /// history this is much faster than ranking everything, but occurrences and length are then
+    /// scaled against the matching commands instead of the whole history, so ranks can differ a
+    /// little from `build_cache_table` followed by `find_matches`. Like `find_matches_global`, this
+    /// replaces the cache table.
+    pub fn find_matches_prefiltered(
         &self,
-        session_id: &Option<String>,
+        cmd: &str,
         num: i16,
-        offset: u16,
-        random: bool,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
     ) -> Vec<Command> {
-        let order = if random { "RANDOM()" } else { "id" };
-        let query = if session_id.is_none() {
-            format!("SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir FROM commands ORDER BY {} DESC LIMIT :limit OFFSET :offset", order)
-        } else {
-            format!("SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir FROM commands WHERE session_id = :session_id ORDER BY {} DESC LIMIT :limit OFFSET :offset", order)
-        };
+        let (positive, _) = self.split_excluded_terms(cmd);
+        let like_query = History::like_query(&positive, fuzzy, match_mode);
+        let weights = self.rank_weights(&Weights::default());
+        self.build_cache_table_matching(
+            None,
+            &None,
+            None,
+            None,
+            None,
+            Some(&like_query),
+            None,
+            &weights,
+        );
+        self.find_matches(cmd, num, fuzzy, interactive_only, match_mode)
+    }
 
-        if session_id.is_none() {
-            self.run_query(&query, &[(":limit", &num), (":offset", &offset)])
-        } else {
-            self.run_query(
-                &query,
-                &[
-                    (":session_id", &session_id.to_owned().unwrap()),
-                    (":limit", &num),
-                    (":offset", &offset),
-                ],
-            )
+    /// Answer searches for a long-running helper process, such as an editor integration, without
+    /// paying McFly's startup cost each time. Each line read from `reader` is a query. For each
+    /// one, this rebuilds the cache for the current context and writes one line of JSON to `writer`:
+    ///
+    /// ```text
+    /// {"query":"git","results":[{"rank":0.9,"cmd":"git status","dir":"/src","factors":{"age_factor":0.1,...}}]}
+    /// ```
+    ///
+    /// It returns when `reader` runs out.
+    pub fn serve_line_protocol<R: BufRead, W: Write>(
+        &self,
+        reader: R,
+        mut writer: W,
+    ) -> io::Result<()> {
+        for line in reader.lines() {
+            let query = line?;
+            let query = query.trim_end_matches('\r');
+            self.build_cache_table(None, &None, None, None, None);
+            let results = self
+                .find_matches(
+                    query,
+                    LINE_PROTOCOL_RESULTS,
+                    false,
+                    false,
+                    MatchMode::Substring,
+                )
+                .into_iter()
+                .map(|mut command| {
+                    let factors = Features::NAMES
+                        .iter()
+                        .zip(command.features.factors_mut())
+                        .map(|(name, value)| {
+                            format!("\"{}\":{}", name, History::json_number(*value))
+                        })
+                        .join(",");
+                    format!(
+                        "{{\"rank\":{},\"cmd\":{},\"dir\":{},\"factors\":{{{}}}}}",
+                        History::json_number(command.rank),
+                        History::json_string(&command.cmd),
+                        command
+                            .dir
+                            .as_ref()
+                            .map_or("null".to_string(), |dir| History::json_string(dir)),
+                        factors
+                    )
+                })
+                .join(",");
+            writeln!(
+                writer,
+                "{{\"query\":{},\"results\":[{}]}}",
+                History::json_string(query),
+                results
+            )?;
+            writer.flush()?;
         }
+        Ok(())
     }
 
-    fn run_query(&self, query: &str, params: &[(&str, &dyn ToSql)]) -> Vec<Command> {
-        let mut statement = self.connection.prepare(query).unwrap();
-
-        let closure: fn(&Row) -> Command = |row| Command {
-            id: row.get(0),
-            cmd: row.get(1),
-            cmd_tpl: row.get(2),
-            session_id: row.get(3),
-            when_run: row.get(4),
-            exit_code: row.get(5),
-            selected: row.get(6),
-            dir: row.get(7),
-            ..Command::default()
-        };
-
-        let command_iter: MappedRows<_> = statement
-            .query_map_named(params, closure)
-            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+    // `value` as a JSON number, or null when JSON can't represent it.
+    fn json_number(value: f64) -> String {
+        if value.is_finite() {
+            value.to_string()
+        } else {
+            "null".to_string()
+        }
+    }
 
-        let mut vec = Vec::new();
-        for result in command_iter {
-            if let Ok(command) = result {
-                vec.push(command);
+    // `text` as a quoted JSON string.
+    fn json_string(text: &str) -> String {
+        let mut quoted = String::with_capacity(text.len() + 2);
+        quoted.push('"');
+        for c in text.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
             }
         }
+        quoted.push('"');
+        quoted
+    }
 
-        vec
+    /// `find_matches` across every directory alike: the cache is rebuilt with no current
+    /// directory, so results reflect frequency, recency and overlap alone. This replaces the cache
+    /// table, so rebuild it before going back to `find_matches`.
+    pub fn find_matches_global(
+        &self,
+        cmd: &str,
+        num: i16,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+    ) -> Vec<Command> {
+        self.build_cache_table(Some(NO_DIR), &None, None, None, None);
+        self.find_matches(cmd, num, fuzzy, interactive_only, match_mode)
     }
 
-    pub fn last_command(&self, session_id: &Option<String>) -> Option<Command> {
-        self.commands(session_id, 1, 0, false).get(0).cloned()
+    // The lowest id within `max_history_rows` of the newest in `source`; None when every row is in
+    // range.
+    fn min_id(&self, source: &str) -> Option<i64> {
+        match self.max_history_rows {
+            Some(max_history_rows) if max_history_rows > 0 => self
+                .connection
+                .query_row_named(
+                    &format!(
+                        "SELECT id FROM {} ORDER BY id DESC LIMIT 1 OFFSET :offset",
+                        source
+                    ),
+                    &[(":offset", &(i64::from(max_history_rows) - 1))],
+                    |row| row.get(0),
+                )
+                .ok(),
+            _ => None,
+        }
     }
 
-    pub fn last_command_templates(
+    /// `find_matches`, along with every other command matching `cmd` and why it was left out.
+    /// Meant for working out why an expected command doesn't show up, not for searching.
+    pub fn find_matches_debug(
         &self,
-        session_id: &Option<String>,
+        cmd: &str,
         num: i16,
-        offset: u16,
-    ) -> Vec<String> {
-        self.commands(session_id, num, offset, false)
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+    ) -> (Vec<Command>, Vec<(Command, FilterReason)>) {
+        let matches = self.find_matches(cmd, num, fuzzy, interactive_only, match_mode);
+        let mut rejected = Vec::new();
+        let (positive, excluded_terms) = self.split_excluded_terms(cmd);
+        let like_query = History::like_query(&positive, fuzzy, match_mode);
+
+        // Candidates that made it into the cache table but not into the results.
+        let scripted: Vec<String> = self
+            .connection
+            .prepare("SELECT cmd FROM contextual_commands WHERE interactive = 0")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)))
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)))
+            .filter_map(Result::ok)
+            .collect();
+        for command in self.find_matches(cmd, -1, fuzzy, false, match_mode) {
+            if matches.iter().any(|c| c.cmd == command.cmd) {
+                continue;
+            }
+            let reason = if interactive_only && scripted.contains(&command.cmd) {
+                FilterReason::NotInteractive
+            } else {
+                FilterReason::BelowLimit
+            };
+            rejected.push((command, reason));
+        }
+
+        // Candidates that `match_env_hash` kept out of both searches above.
+        if let Some(env_hash) = &self.match_env_hash {
+            let exclude_names: Vec<String> = (0..excluded_terms.len())
+                .map(|i| format!(":exclude{}", i))
+                .collect();
+            let exclusions: String = exclude_names
+                .iter()
+                .map(|name| format!(" AND cmd NOT LIKE ({}) ESCAPE '\\'", name))
+                .collect();
+            let mut params: Vec<(&str, &dyn ToSql)> =
+                vec![(":like", &like_query), (":env_hash", env_hash)];
+            for (name, term) in exclude_names.iter().zip(&excluded_terms) {
+                params.push((name, term));
+            }
+            let other_environments = self.run_query(
+                &format!(
+                    "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+                     FROM contextual_commands
+                     WHERE cmd LIKE (:like) ESCAPE '\\'{}
+                     AND cmd NOT IN (SELECT cmd FROM commands WHERE env_hash = :env_hash)
+                     ORDER BY rank DESC, age_factor ASC, id DESC",
+                    exclusions
+                ),
+                &params,
+            );
+            for command in other_environments {
+                let reason = if interactive_only && scripted.contains(&command.cmd) {
+                    FilterReason::NotInteractive
+                } else {
+                    FilterReason::OtherEnvironment
+                };
+                rejected.push((command, reason));
+            }
+        }
+
+        // Matching commands that build_cache_table left out, checked in the order it applies its
+        // filters: hidden ones, ones only run before the `max_history_rows` cap, ones only run in
+        // private directories, then ones run too rarely. The time window it was given isn't kept,
+        // so whatever is left must have been run outside it.
+        let missing = self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             WHERE cmd LIKE (:like) ESCAPE '\\' AND cmd NOT IN (SELECT cmd FROM contextual_commands)
+             GROUP BY cmd ORDER BY MAX(id) DESC",
+            &[(":like", &like_query)],
+        );
+        let min_id = self.min_id(&self.candidate_source());
+        for command in missing {
+            let (visible_runs, capped_runs, public_runs): (i64, i64, i64) = self
+                .connection
+                .query_row_named(
+                    "SELECT COUNT(*), SUM(:min_id IS NULL OR id >= :min_id),
+                            SUM((:min_id IS NULL OR id >= :min_id) AND NOT EXISTS (SELECT 1 FROM temp.private_dirs p WHERE c.dir LIKE p.prefix || '%' ESCAPE '\\'))
+                     FROM commands c WHERE cmd = :cmd AND hidden = 0",
+                    &[(":cmd", &command.cmd), (":min_id", &min_id)],
+                    |row| {
+                        (
+                            row.get(0),
+                            row.get::<_, Option<i64>>(1).unwrap_or(0),
+                            row.get::<_, Option<i64>>(2).unwrap_or(0),
+                        )
+                    },
+                )
+                .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
+            let reason = if visible_runs == 0 {
+                FilterReason::Hidden
+            } else if capped_runs == 0 {
+                FilterReason::BeyondRowLimit
+            } else if public_runs == 0 {
+                FilterReason::PrivateDir
+            } else if public_runs < self.min_occurrences {
+                FilterReason::TooFewOccurrences
+            } else {
+                FilterReason::OutsideTimeWindow
+            };
+            rejected.push((command, reason));
+        }
+
+        (matches, rejected)
+    }
+
+    /// `find_matches`, with how likely each match is to be the one wanted: a softmax over the
+    /// ranks of every match. The confidences of all matches sum to 1, so those returned sum to
+    /// less when `num` leaves some out.
+    pub fn find_matches_with_confidence(
+        &self,
+        cmd: &str,
+        num: i16,
+        fuzzy: bool,
+        interactive_only: bool,
+        match_mode: MatchMode,
+    ) -> Vec<(Command, f64)> {
+        let matches = self.find_matches(cmd, -1, fuzzy, interactive_only, match_mode);
+        let max_rank = matches
             .iter()
-            .map(|command| command.cmd_tpl.to_owned())
-            .collect()
+            .map(|command| command.rank)
+            .fold(std::f64::NEG_INFINITY, f64::max);
+        let weights: Vec<f64> = matches
+            .iter()
+            .map(|command| (command.rank - max_rank).exp())
+            .collect();
+        let total: f64 = weights.iter().sum();
+
+        let mut with_confidence: Vec<(Command, f64)> = matches
+            .into_iter()
+            .zip(weights)
+            .map(|(command, weight)| (command, weight / total))
+            .collect();
+        if num >= 0 {
+            with_confidence.truncate(num as usize);
+        }
+        with_confidence
     }
 
-    pub fn delete_command(&self, command: &str) {
-        self.connection
+    /// The top-ranked match for `cmd` in `dir`, for inline suggestions. Rebuilds the contextual
+    /// cache for `dir` and only fetches one row.
+    pub fn best_match(&self, cmd: &str, dir: &str) -> Option<Command> {
+        self.build_cache_table(Some(dir), &None, None, None, None);
+        self.find_matches(cmd, 1, false, false, MatchMode::Substring)
+            .into_iter()
+            .next()
+    }
+
+    /// How unexpected running `cmd` in `dir` would be, for flagging likely mistakes: `-ln(p)`, where
+    /// `p` is its share of a softmax over the ranks of every command in that context, as in
+    /// `find_matches_with_confidence`. A command never run before counts as one more command ranked
+    /// as low as the lowest, so it's more surprising than any that has been. Rebuilds the
+    /// contextual cache for `dir`.
+    pub fn surprise(&self, cmd: &str, dir: &str) -> f64 {
+        self.build_cache_table(Some(dir), &None, None, None, None);
+        let matches = self.find_matches("", -1, false, false, MatchMode::Substring);
+        let max_rank = matches
+            .iter()
+            .map(|command| command.rank)
+            .fold(std::f64::NEG_INFINITY, f64::max);
+        let weight = |rank: f64| (rank - max_rank).exp();
+        let total: f64 = matches.iter().map(|command| weight(command.rank)).sum();
+
+        let probability = match matches.iter().find(|command| command.cmd == cmd) {
+            Some(command) => weight(command.rank) / total,
+            None => {
+                let lowest = matches
+                    .iter()
+                    .map(|command| weight(command.rank))
+                    .fold(1.0, f64::min);
+                lowest / (total + lowest)
+            }
+        };
+        -probability.ln()
+    }
+
+    /// Which factor did the most to put `best_match(cmd, dir)` first, and by how much: the factor
+    /// whose removal would most change the network's output for that command.
+    pub fn dominant_factor(&self, cmd: &str, dir: &str) -> Option<(String, f64)> {
+        let top = self.best_match(cmd, dir)?;
+        let output = self.network.output(&top.features);
+        (0..Features::NAMES.len())
+            .map(|factor| {
+                let mut without = top.features.clone();
+                *without.factors_mut()[factor] = 0.0;
+                (factor, output - self.network.output(&without))
+            })
+            .max_by(|(_, a), (_, b)| a.abs().partial_cmp(&b.abs()).unwrap_or(Ordering::Equal))
+            .map(|(factor, contribution)| (Features::NAMES[factor].to_string(), contribution))
+    }
+
+    /// Write every command's factors and rank for `dir` as CSV, one row per command, for training
+    /// rankers outside McFly. `selected` is 1 if the command was ever chosen from the search UI.
+    pub fn export_features<W: Write>(&self, dir: &str, writer: W) -> io::Result<()> {
+        self.build_cache_table(Some(dir), &None, None, None, None);
+        let mut writer = csv::Writer::from_writer(writer);
+        let mut header = vec!["cmd", "rank"];
+        header.extend_from_slice(&Features::NAMES);
+        header.push("selected");
+        writer.write_record(&header)?;
+        for command in self.find_matches("", -1, false, false, MatchMode::Substring) {
+            let features = &command.features;
+            let mut record = vec![command.cmd.to_owned(), command.rank.to_string()];
+            record.extend(
+                [
+                    features.age_factor,
+                    features.length_factor,
+                    features.exit_factor,
+                    features.recent_failure_factor,
+                    features.selected_dir_factor,
+                    features.dir_factor,
+                    features.overlap_factor,
+                    features.immediate_overlap_factor,
+                    features.selected_occurrences_factor,
+                    features.occurrences_factor,
+                ]
+                .iter()
+                .map(f64::to_string),
+            );
+            let selected = if features.selected_occurrences_factor > 0.0 {
+                "1"
+            } else {
+                "0"
+            };
+            record.push(selected.to_string());
+            writer.write_record(&record)?;
+        }
+        writer.flush()
+    }
+
+    /// Search another McFly database, such as a shared set of vetted commands, alongside this one.
+    /// It's attached read-only under `name`, which has to be letters, digits, and underscores and
+    /// becomes the `source` of its commands in results. Its commands get negative ids so they
+    /// can't be mistaken for this history's. It must be at the same schema version as this
+    /// history, since it can't be migrated.
+    pub fn attach_overlay(&mut self, name: &str, path: &Path) {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            panic!(format!("McFly error: Invalid overlay name {:?}", name));
+        }
+        History::attach_read_only(&self.connection, name, path).unwrap_or_else(|err| {
+            panic!(format!(
+                "McFly error: Unable to attach {} ({})",
+                path.display(),
+                err
+            ))
+        });
+        self.overlays.push((name.to_string(), path.to_path_buf()));
+    }
+
+    fn attach_read_only(connection: &Connection, name: &str, path: &Path) -> rusqlite::Result<()> {
+        connection
             .execute_named(
-                "DELETE FROM selected_commands WHERE cmd = :command",
-                &[(":command", &command)],
+                &format!("ATTACH DATABASE :uri AS {}", name),
+                &[(":uri", &format!("file:{}?mode=ro", History::uri_path(path)))],
+            )
+            .map(|_| ())
+    }
+
+    // What build_cache_table ranks: `commands`, plus the commands of any overlays.
+    fn candidate_source(&self) -> String {
+        if self.overlays.is_empty() {
+            return "commands".to_string();
+        }
+        // Inodes from another machine mean nothing here, so overlays don't have any.
+        let mut source = format!(
+            "(SELECT id, cmd, source, {}, dir_inode FROM commands",
+            OVERLAY_COLUMNS
+        );
+        for (name, _) in &self.overlays {
+            source.push_str(&format!(
+                " UNION ALL SELECT -id, mcfly_decompress(cmd), '{}', {}, NULL FROM {}.commands",
+                name, OVERLAY_COLUMNS, name
+            ));
+        }
+        source.push(')');
+        source
+    }
+
+    /// Rank every command run between `start_time` and `end_time` for the given context into the
+    /// `contextual_commands` temp table. `now` is the time recent failures are measured from;
+    /// pinning it to a past timestamp (with `end_time` left as `None`, it also becomes the end of
+    /// the window) shows what would have been suggested then. Both default to the current time.
+    pub fn build_cache_table(
+        &self,
+        dir: Option<&str>,
+        session_id: &Option<String>,
+        start_time: Option<i64>,
+        end_time: Option<i64>,
+        now: Option<i64>,
+    ) {
+        let weights = self.rank_weights(&Weights::default());
+        self.build_cache_table_matching(
+            dir, session_id, start_time, end_time, now, None, None, &weights,
+        );
+    }
+
+    /// The context `build_cache_table` would score commands against for `session_id`, with no
+    /// explicit directory or time window.
+    pub fn current_context(&self, session_id: &Option<String>) -> Context {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+            .as_secs() as i64;
+        self.scoring_context(None, session_id, None, now, None)
+    }
+
+    // Runs before this are left out of `occurrences_factor`, per `occurrence_window_days`.
+    fn occurrence_cutoff(&self, now: i64) -> Option<i64> {
+        self.occurrence_window_days
+            .map(|days| now - i64::from(days) * 24 * 60 * 60)
+    }
+
+    // Only commands with ids below `before_id`, when given, count towards the context, so a past
+    // command can be ranked as if it hadn't been run yet.
+    fn scoring_context(
+        &self,
+        dir: Option<&str>,
+        session_id: &Option<String>,
+        like: Option<&str>,
+        now: i64,
+        before_id: Option<i64>,
+    ) -> Context {
+        let dir = self.home_relative(dir.or(self.dir.as_deref()).unwrap_or(""));
+        let session_id = &session_id.to_owned().or_else(|| self.session_id.to_owned());
+        let lookback = self.lookback.min(i16::max_value() as u16);
+
+        let mut last_commands = self.templates_before(session_id, lookback as i16, before_id);
+        if last_commands.len() < lookback as usize && !self.session_context_only {
+            last_commands = self.templates_before(&None, lookback as i16, before_id);
+            if last_commands.len() < lookback as usize {
+                last_commands.push(String::from(""));
+            }
+        }
+
+        let source = self.candidate_source();
+
+        // Both are NULL when there's no history yet.
+        let (mut when_run_min, when_run_max): (f64, f64) = self
+            .connection
+            .query_row_named(
+                &format!(
+                    "SELECT MIN(when_run), MAX(when_run) FROM {} WHERE (:before_id IS NULL OR id < :before_id)",
+                    source
+                ),
+                &[(":before_id", &before_id)],
+                |row| {
+                    (
+                        row.get::<_, Option<f64>>(0).unwrap_or(0.0),
+                        row.get::<_, Option<f64>>(1).unwrap_or(0.0),
+                    )
+                },
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
+
+        if (when_run_min - when_run_max).abs() < std::f64::EPSILON {
+            when_run_min -= 60.0 * 60.0;
+        }
+
+        // With a LIKE pattern, occurrences and length are scaled against the matching commands
+        // rather than the whole history.
+        let max_occurrences: f64 = self
+            .connection
+            .query_row_named(
+                &format!("SELECT COUNT(*) AS c FROM {} WHERE (:like IS NULL OR cmd LIKE (:like) ESCAPE '\\') AND (:occurrence_cutoff IS NULL OR when_run > :occurrence_cutoff) AND (:before_id IS NULL OR id < :before_id) GROUP BY cmd ORDER BY c DESC LIMIT 1", source),
+                &[(":like", &like), (":occurrence_cutoff", &self.occurrence_cutoff(now)), (":before_id", &before_id)],
+                |row| row.get(0),
+            )
+            .unwrap_or(1.0);
+
+        Context {
+            last_command_templates: last_commands,
+            dir,
+            max_occurrences,
+            when_run_min,
+            when_run_max,
+        }
+    }
+
+    // `build_cache_table`, optionally only over commands matching the LIKE pattern `like`, and
+    // only over (and in the context of) commands with ids below `before_id`.
+    #[allow(clippy::too_many_arguments)]
+    fn build_cache_table_matching(
+        &self,
+        dir: Option<&str>,
+        session_id: &Option<String>,
+        start_time: Option<i64>,
+        end_time: Option<i64>,
+        now: Option<i64>,
+        like: Option<&str>,
+        before_id: Option<i64>,
+        weights: &RankWeights,
+    ) {
+        let started = Instant::now();
+        let dir_inode = dir
+            .or(self.dir.as_deref())
+            .and_then(|dir| self.dir_inode(dir));
+        let now = now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+                .as_secs() as i64
+        });
+        let Context {
+            last_command_templates: last_commands,
+            dir,
+            max_occurrences,
+            when_run_min,
+            when_run_max,
+        } = self.scoring_context(dir, session_id, like, now, before_id);
+        let dir = dir.as_str();
+        let last_command = last_commands.first().cloned().unwrap_or_default();
+        let lookback = self.lookback.min(i16::max_value() as u16);
+        let end_time = end_time.unwrap_or(now);
+
+        // The last commands go in a table rather than bound parameters one by one, so a long
+        // lookback can't run into SQLite's limit on the number of bound parameters.
+        self.connection
+            .execute_batch(
+                "DROP TABLE IF EXISTS temp.last_commands;
+                 CREATE TEMP TABLE last_commands (cmd_tpl TEXT);
+                 DROP TABLE IF EXISTS temp.modifier_programs;
+                 CREATE TEMP TABLE modifier_programs (program TEXT);
+                 DROP TABLE IF EXISTS temp.equivalent_dirs;
+                 CREATE TEMP TABLE equivalent_dirs (dir TEXT);
+                 DROP TABLE IF EXISTS temp.private_dirs;
+                 CREATE TEMP TABLE private_dirs (prefix TEXT);",
             )
             .unwrap_or_else(|err| {
                 panic!(format!(
-                    "McFly error: DELETE from selected_commands to work ({})",
+                    "McFly error: Creation of last_commands temp table to work ({})",
                     err
                 ))
             });
+        {
+            let mut statement = self
+                .connection
+                .prepare("INSERT INTO temp.last_commands (cmd_tpl) VALUES (:cmd_tpl)")
+                .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+            for last_command in &last_commands {
+                statement
+                    .execute_named(&[(":cmd_tpl", last_command)])
+                    .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+            }
+
+            let mut statement = self
+                .connection
+                .prepare("INSERT INTO temp.modifier_programs (program) VALUES (:program)")
+                .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+            for program in &self.modifier_programs {
+                statement
+                    .execute_named(&[(":program", program)])
+                    .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+            }
+
+            let mut statement = self
+                .connection
+                .prepare("INSERT INTO temp.equivalent_dirs (dir) VALUES (:dir)")
+                .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+            let equivalent_dirs = self.equivalent_dirs.iter().filter(|_| dir != NO_DIR);
+            for equivalent_dir in equivalent_dirs {
+                statement
+                    .execute_named(&[(":dir", &self.home_relative(equivalent_dir))])
+                    .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+            }
+
+            // Stored escaped, ready to be matched with `LIKE prefix || '%'`.
+            let mut statement = self
+                .connection
+                .prepare("INSERT INTO temp.private_dirs (prefix) VALUES (:prefix)")
+                .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+            for private_dir in &self.private_dirs {
+                let private_dir = self.home_relative(private_dir);
+                statement
+                    .execute_named(&[(":prefix", &History::escape_like(&private_dir))])
+                    .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+            }
+        }
 
         self.connection
-            .execute_named(
-                "DELETE FROM commands WHERE cmd = :command",
-                &[(":command", &command)],
-            )
+            .execute("DROP TABLE IF EXISTS temp.contextual_commands;", NO_PARAMS)
             .unwrap_or_else(|err| {
                 panic!(format!(
-                    "McFly error: DELETE from commands to work ({})",
+                    "McFly error: Removal of temp table to work ({})",
                     err
                 ))
             });
-    }
 
-    pub fn update_paths(&self, old_path: &str, new_path: &str, print_output: bool) {
-        let normalized_old_path = path_update_helpers::normalize_path(old_path);
-        let normalized_new_path = path_update_helpers::normalize_path(new_path);
+        let source = self.candidate_source();
 
-        if normalized_old_path.len() > 1 && normalized_new_path.len() > 1 {
-            let like_query = normalized_old_path.to_string() + "/%";
+        let min_id = self.min_id(&source);
 
-            let mut dir_update_statement = self.connection.prepare(
-                "UPDATE commands SET dir = :new_dir || SUBSTR(dir, :length) WHERE dir = :exact OR dir LIKE (:like)"
-            ).unwrap();
+        let max_selected_occurrences: f64 = self.connection
+            .query_row_named(&format!("SELECT COUNT(*) AS c FROM {} WHERE selected = 1 AND (:like IS NULL OR cmd LIKE (:like) ESCAPE '\\') GROUP BY cmd ORDER BY c DESC LIMIT 1", source), &[(":like", &like)],
+                       |row| row.get(0)).unwrap_or(1.0);
 
-            let mut old_dir_update_statement = self.connection.prepare(
-                "UPDATE commands SET old_dir = :new_dir || SUBSTR(old_dir, :length) WHERE old_dir = :exact OR old_dir LIKE (:like)"
-            ).unwrap();
+        // Commands whose directory was never recorded, such as imported ones, get the share of all
+        // runs with a known directory that were in this one, so they neither stand out from nor
+        // sink below a typical command.
+        let directory = Some(dir).filter(|dir| !dir.is_empty());
+        let neutral_dir_factor: f64 = self
+            .connection
+            .query_row_named(
+                &format!("SELECT AVG(CASE WHEN dir = :directory OR dir IN (SELECT dir FROM temp.equivalent_dirs) OR dir_inode = :dir_inode THEN 1.0 ELSE 0.0 END) FROM {} WHERE dir IS NOT NULL AND dir != '' AND (:before_id IS NULL OR id < :before_id)", source),
+                &[(":directory", &directory), (":dir_inode", &dir_inode), (":before_id", &before_id)],
+                |row| row.get::<_, Option<f64>>(0),
+            )
+            .ok()
+            .and_then(|neutral_dir_factor| neutral_dir_factor)
+            .unwrap_or(0.0);
 
-            let affected = dir_update_statement
-                .execute_named(&[
-                    (":like", &like_query),
-                    (":exact", &normalized_old_path),
-                    (":new_dir", &normalized_new_path),
-                    (":length", &(normalized_old_path.chars().count() as u32 + 1)),
-                ])
-                .unwrap_or_else(|err| panic!(format!("McFly error: dir UPDATE to work ({})", err)));
+        let max_length: f64 = self
+            .connection
+            .query_row_named(
+                &format!("SELECT MAX(LENGTH(cmd)) FROM {} WHERE (:like IS NULL OR cmd LIKE (:like) ESCAPE '\\')", source),
+                &[(":like", &like)],
+                |row| row.get::<_, Option<f64>>(0),
+            )
+            .ok()
+            .and_then(|max_length| max_length)
+            .unwrap_or(100.0);
 
-            old_dir_update_statement
-                .execute_named(&[
-                    (":like", &like_query),
-                    (":exact", &normalized_old_path),
-                    (":new_dir", &normalized_new_path),
-                    (":length", &(normalized_old_path.chars().count() as u32 + 1)),
-                ])
-                .unwrap_or_else(|err| {
-                    panic!(format!("McFly error: old_dir UPDATE to work ({})", err))
-                });
+        self.connection.execute_named(
+            &format!("CREATE TEMP TABLE contextual_commands AS SELECT
+                  id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, parent, cursor_pos, source, arg_count, error_snippet,
 
-            if print_output {
-                println!(
-                    "McFly: Command database paths renamed from {} to {} (affected {} commands)",
-                    normalized_old_path, normalized_new_path, affected
-                );
-            }
-        } else if print_output {
-            println!("McFly: Not updating paths due to invalid options.");
-        }
-    }
+                  /* 1 if any run of this command was flagged as dangerous */
+                  MAX(dangerous) AS dangerous,
 
-    fn from_shell_history(history_format: HistoryFormat) -> History {
-        print!(
-            "McFly: Importing shell history for the first time. This may take a minute or two..."
-        );
-        io::stdout().flush().unwrap_or_else(|err| {
-            panic!(format!("McFly error: STDOUT flush should work ({})", err))
-        });
+                  /* a note attached to any run of this command */
+                  MAX(note) AS note,
 
-        // Load this first to make sure it works before we create the DB.
-        let commands =
-            shell_history::full_history(&shell_history::history_file_path(), history_format);
+                  /* 1 if this command was ever run interactively (or before we tracked it), 0 if only ever from scripts */
+                  MAX(CASE WHEN interactive = 0 THEN 0 ELSE 1 END) AS interactive,
 
-        // Make ~/.mcfly
-        fs::create_dir_all(Settings::storage_dir_path())
-            .unwrap_or_else(|_| panic!("Unable to create {:?}", Settings::storage_dir_path()));
+                  /* to be filled in later */
+                  0.0 AS rank,
 
-        // Make ~/.mcfly/history.db
-        let connection = Connection::open(Settings::mcfly_db_path()).unwrap_or_else(|_| {
-            panic!(
-                "Unable to create history DB at {:?}",
-                Settings::mcfly_db_path()
-            )
-        });
-        db_extensions::add_db_functions(&connection);
+                  /* length of the command string */
+                  LENGTH(c.cmd) / :max_length AS length_factor,
 
-        connection.execute_batch(
-            "CREATE TABLE commands( \
-                      id INTEGER PRIMARY KEY AUTOINCREMENT, \
-                      cmd TEXT NOT NULL, \
-                      cmd_tpl TEXT, \
-                      session_id TEXT NOT NULL, \
-                      when_run INTEGER NOT NULL, \
-                      exit_code INTEGER NOT NULL, \
-                      selected INTEGER NOT NULL, \
-                      dir TEXT, \
-                      old_dir TEXT \
-                  ); \
-                  CREATE INDEX command_cmds ON commands (cmd);\
-                  CREATE INDEX command_session_id ON commands (session_id);\
-                  CREATE INDEX command_dirs ON commands (dir);\
-                  \
-                  CREATE TABLE selected_commands( \
-                      id INTEGER PRIMARY KEY AUTOINCREMENT, \
-                      cmd TEXT NOT NULL, \
-                      session_id TEXT NOT NULL, \
-                      dir TEXT NOT NULL \
-                  ); \
-                  CREATE INDEX selected_command_session_cmds ON selected_commands (session_id, cmd);"
-        ).unwrap_or_else(|err| panic!(format!("McFly error: Unable to initialize history db ({})", err)));
+                  /* age of the last execution of this command (0.0 is new, 1.0 is old) */
+                  MIN((:when_run_max - when_run) / :history_duration) AS age_factor,
 
-        {
-            let mut statement = connection
-                .prepare("INSERT INTO commands (cmd, cmd_tpl, session_id, when_run, exit_code, selected) VALUES (:cmd, :cmd_tpl, :session_id, :when_run, :exit_code, :selected)")
-                .unwrap_or_else(|err| panic!(format!("McFly error: Unable to prepare insert ({})", err)));
-            for command in commands {
-                if !IGNORED_COMMANDS.contains(&command.command.as_str()) {
-                    let simplified_command = SimplifiedCommand::new(&command.command, true);
-                    if !command.command.is_empty() && !simplified_command.result.is_empty() {
-                        statement
-                            .execute_named(&[
-                                (":cmd", &command.command),
-                                (":cmd_tpl", &simplified_command.result.to_owned()),
-                                (":session_id", &"IMPORTED"),
-                                (":when_run", &command.when),
-                                (":exit_code", &0),
-                                (":selected", &0),
-                            ])
-                            .unwrap_or_else(|err| {
-                                panic!(format!("McFly error: Insert to work ({})", err))
-                            });
-                    }
-                }
-            }
-        }
+                  /* average error state (1: always successful, 0: always errors) */
+                  SUM(CASE WHEN exit_code = 0 THEN 1.0 ELSE 0.0 END) / COUNT(*) as exit_factor,
 
-        schema::first_time_setup(&connection);
+                  /* recent failure (1 if failed recently, 0 if not); commands killed by a signal were interrupted, not failed */
+                  MAX(CASE WHEN exit_code != 0 AND exit_signal IS NULL AND :now - when_run < 120 THEN 1.0 ELSE 0.0 END) AS recent_failure_factor,
 
-        println!("done.");
+                  /* percentage of runs with a known directory that were in this directory, an equivalent one, or this one under an earlier name (1: always run in this directory, 0: never run in this directory, the share of all runs in this directory if no run's directory is known) */
+                  COALESCE(
+                    SUM(CASE WHEN dir IS NULL OR dir = '' THEN NULL
+                             WHEN dir = :directory OR dir IN (SELECT dir FROM temp.equivalent_dirs) OR dir_inode = :dir_inode THEN 1.0
+                             ELSE 0.0 END)
+                    / SUM(CASE WHEN dir IS NULL OR dir = '' THEN 0.0 ELSE 1.0 END),
+                    :neutral_dir_factor) as dir_factor,
 
-        History {
-            connection,
-            network: Network::default(),
-        }
-    }
+                  /* percentage of time selected in this directory (1: only selected in this dir, 0: only selected elsewhere) */
+                  SUM(CASE WHEN dir = :directory AND selected = 1 THEN 1.0 ELSE 0.0 END) / (SUM(CASE WHEN selected = 1 THEN 1.0 ELSE 0.0 END) + 1) as selected_dir_factor,
 
-    fn from_db_path(path: PathBuf) -> History {
-        let connection = Connection::open(path).unwrap_or_else(|err| {
-            panic!(format!(
-                "McFly error: Unable to open history database ({})",
-                err
-            ))
-        });
-        db_extensions::add_db_functions(&connection);
-        History {
-            connection,
-            network: Network::default(),
-        }
+                  /* percentage run from the current parent program (1: always, 0: never or no parent known) */
+                  SUM(CASE WHEN parent = :parent THEN 1.0 ELSE 0.0 END) / COUNT(*) as parent_factor,
+
+                  /* 1 if this command starts with the same program as the last command, 0 if not */
+                  MAX(CASE WHEN :last_program != '' AND substr(cmd_tpl, 1, instr(cmd_tpl || ' ', ' ') - 1) = :last_program THEN 1.0 ELSE 0.0 END) AS same_program_factor,
+
+                  /* percentage run in this directory as a file-modifying program (1: always, 0: never) */
+                  SUM(CASE WHEN dir = :directory AND substr(cmd, 1, instr(cmd || ' ', ' ') - 1) IN (SELECT program FROM temp.modifier_programs) THEN 1.0 ELSE 0.0 END) / COUNT(*) AS modifier_factor,
+
+                  /* 1 if this command was run in one of the most recently active sessions, 0 if not */
+                  MAX(CASE WHEN session_id IN (
+                    SELECT session_id FROM commands WHERE when_run < :end_time
+                    GROUP BY session_id ORDER BY MAX(when_run) DESC LIMIT :recent_sessions
+                  ) THEN 1.0 ELSE 0.0 END) AS session_recency_factor,
+
+                  /* average contextual overlap of this command (0: none of the last 3 commands has ever overlapped with this command, 1: all of the last three commands always overlap with this command) */
+                  SUM((
+                    SELECT COUNT(DISTINCT c2.cmd_tpl) FROM commands c2
+                    WHERE c2.id >= c.id - :lookback AND c2.id < c.id AND c2.cmd_tpl IN (SELECT cmd_tpl FROM temp.last_commands)
+                  ) / :lookback_f64) / COUNT(*) AS overlap_factor,
+
+                  /* average overlap with the last command (0: this command never follows the last command, 1: this command always follows the last command) */
+                  SUM((SELECT COUNT(*) FROM commands c2 WHERE c2.id = c.id - 1 AND c2.cmd_tpl = :last_commands0)) / COUNT(*) AS immediate_overlap_factor,
+
+                  /* percentage selected (1: this is the most commonly selected command, 0: this command is never selected) */
+                  SUM(CASE WHEN selected = 1 THEN 1.0 ELSE 0.0 END) / :max_selected_occurrences AS selected_occurrences_factor,
+
+                  /* percentage of time this command is run relative to the most common command (1: this is the most common command, 0: this is the least common command), counting only runs within the occurrence window */
+                  SUM(CASE WHEN :occurrence_cutoff IS NULL OR when_run > :occurrence_cutoff THEN 1.0 ELSE 0.0 END) / :max_occurrences AS occurrences_factor
+
+                  FROM {} c WHERE hidden = 0 AND when_run > :start_time AND when_run < :end_time
+                  AND (:min_id IS NULL OR c.id >= :min_id)
+                  AND (:before_id IS NULL OR c.id < :before_id)
+                  AND (:like IS NULL OR cmd LIKE (:like) ESCAPE '\\')
+                  AND NOT EXISTS (SELECT 1 FROM temp.private_dirs p WHERE c.dir LIKE p.prefix || '%' ESCAPE '\\') GROUP BY cmd HAVING COUNT(*) >= :min_occurrences ORDER BY id DESC;", source),
+            &[
+                (":when_run_max", &when_run_max),
+                (":history_duration", &(when_run_max - when_run_min)),
+                (":directory", &directory),
+                (":dir_inode", &dir_inode),
+                (":neutral_dir_factor", &neutral_dir_factor),
+                (":parent", &self.parent),
+                (":min_occurrences", &self.min_occurrences),
+                (":max_occurrences", &max_occurrences),
+                (":occurrence_cutoff", &self.occurrence_cutoff(now)),
+                (":max_length", &max_length),
+                (":max_selected_occurrences", &max_selected_occurrences),
+                (":lookback", &lookback),
+                (":lookback_f64", &(lookback as f64)),
+                (":recent_sessions", &self.recent_sessions),
+                (":last_commands0", &last_command),
+                (":last_program", &last_command.split(' ').next().unwrap_or("")),
+                (":start_time", &start_time.unwrap_or(0).to_owned()),
+                (":end_time", &end_time),
+                (":now", &now),
+                (":like", &like),
+                (":min_id", &min_id),
+                (":before_id", &before_id)
+            ]).unwrap_or_else(|err| panic!(format!("McFly error: Creation of temp table to work ({})", err)));
+
+        self.connection
+            .execute_named(
+                "UPDATE contextual_commands
+                 SET rank = nn_rank(age_factor, length_factor, exit_factor,
+                                    recent_failure_factor, selected_dir_factor, dir_factor,
+                                    overlap_factor, immediate_overlap_factor,
+                                    selected_occurrences_factor, occurrences_factor)
+                            + :parent_bonus * parent_factor
+                            + :same_program_weight * same_program_factor
+                            + :modifier_weight * modifier_factor
+                            + :session_recency_weight * session_recency_factor;",
+                &[
+                    (":parent_bonus", &PARENT_BONUS),
+                    (":same_program_weight", &weights.same_program),
+                    (":modifier_weight", &weights.modifier),
+                    (":session_recency_weight", &weights.session_recency),
+                ],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Ranking of temp table to work ({})",
+                    err
+                ))
+            });
+
+        self.connection
+            .execute(
+                "CREATE INDEX IF NOT EXISTS temp.MyIndex ON contextual_commands(id);",
+                NO_PARAMS,
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Creation of index on temp table to work ({})",
+                    err
+                ))
+            });
+
+        if self.timing {
+            self.timings.set(Timings {
+                build_cache_table: Some(started.elapsed()),
+                ..self.timings.get()
+            });
+        }
+    }
+
+    /// Page through commands, newest first unless `ascending` is set. A `num` of -1 is an explicit
+    /// request for every command; any other negative `num` returns nothing rather than relying on
+    /// SQLite treating negative limits as unbounded.
+    pub fn commands(
+        &self,
+        session_id: &Option<String>,
+        num: i16,
+        offset: u16,
+        random: bool,
+        ascending: bool,
+    ) -> Vec<Command> {
+        if num < -1 {
+            return Vec::new();
+        }
+
+        let order = if random { "RANDOM()" } else { "id" };
+        let direction = if ascending { "ASC" } else { "DESC" };
+        let query = if session_id.is_none() {
+            format!("SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet FROM commands ORDER BY {} {} LIMIT :limit OFFSET :offset", order, direction)
+        } else {
+            format!("SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet FROM commands WHERE session_id = :session_id ORDER BY {} {} LIMIT :limit OFFSET :offset", order, direction)
+        };
+
+        if session_id.is_none() {
+            self.run_query(&query, &[(":limit", &num), (":offset", &offset)])
+        } else {
+            self.run_query(
+                &query,
+                &[
+                    (":session_id", &session_id.to_owned().unwrap()),
+                    (":limit", &num),
+                    (":offset", &offset),
+                ],
+            )
+        }
+    }
+
+    /// The last `num` commands of a session, oldest first, for replaying a workflow.
+    pub fn session_tail(&self, session_id: &str, num: i16) -> Vec<Command> {
+        let mut commands = self.commands(&Some(session_id.to_owned()), num, 0, false, false);
+        commands.reverse();
+        commands
+    }
+
+    /// Commands added after command `last_id`, oldest first. Polling with the id of the last
+    /// command seen tails the history.
+    pub fn since(&self, last_id: i64) -> Vec<Command> {
+        self.run_query(
+            "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             WHERE id > :last_id
+             ORDER BY id ASC",
+            &[(":last_id", &last_id)],
+        )
+    }
+
+    /// The command stored under `id`, if there is one.
+    pub fn command_by_id(&self, id: i64) -> Option<Command> {
+        self.run_query(
+            "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             WHERE id = :id",
+            &[(":id", &id)],
+        )
+        .pop()
+    }
+
+    /// The `k` commands run just before command `id`, oldest first. Commands from the same session
+    /// are preferred; if the session doesn't go back far enough, the rest come from any session.
+    pub fn preceding(&self, id: i64, k: usize) -> Vec<Command> {
+        let limit = k as i64;
+        let session_id: Option<String> = self
+            .connection
+            .query_row_named(
+                "SELECT session_id FROM commands WHERE id = :id",
+                &[(":id", &id)],
+                |row| row.get(0),
+            )
+            .ok();
+
+        let mut preceding = match session_id {
+            Some(session_id) => self.run_query(
+                "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet FROM commands WHERE id < :id AND session_id = :session_id ORDER BY id DESC LIMIT :limit",
+                &[(":id", &id), (":session_id", &session_id), (":limit", &limit)],
+            ),
+            None => Vec::new(),
+        };
+
+        if preceding.len() < k {
+            let seen: Vec<i64> = preceding.iter().map(|command| command.id).collect();
+            let remaining = self.run_query(
+                "SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet FROM commands WHERE id < :id ORDER BY id DESC LIMIT :limit",
+                &[(":id", &id), (":limit", &limit)],
+            );
+            preceding.extend(
+                remaining
+                    .into_iter()
+                    .filter(|command| !seen.contains(&command.id))
+                    .take(k - seen.len()),
+            );
+        }
+
+        preceding.sort_by_key(|command| command.id);
+        preceding
+    }
+
+    /// The command run most often with template `cmd_tpl`, as a concrete example of it. On a tie,
+    /// the one recorded most recently wins.
+    pub fn canonical_command(&self, cmd_tpl: &str) -> Option<Command> {
+        self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             WHERE cmd_tpl = :cmd_tpl AND hidden = 0
+             GROUP BY cmd
+             ORDER BY COUNT(*) DESC, MAX(id) DESC
+             LIMIT 1",
+            &[(":cmd_tpl", &cmd_tpl)],
+        )
+        .into_iter()
+        .next()
+    }
+
+    pub fn templates(&self, num: i16) -> Vec<TemplateSummary> {
+        // SQLite fills the bare `cmd` column from the row that supplied MAX(when_run).
+        let query = "SELECT cmd_tpl, COUNT(*) AS c, MAX(when_run), cmd
+                     FROM commands
+                     GROUP BY cmd_tpl
+                     ORDER BY c DESC
+                     LIMIT :limit";
+        let mut statement = self
+            .connection
+            .prepare(query)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let summary_iter = statement
+            .query_map_named(&[(":limit", &num)], |row| TemplateSummary {
+                cmd_tpl: row.get(0),
+                count: row.get(1),
+                last_run: row.get(2),
+                sample_cmd: row.get(3),
+            })
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        summary_iter.flatten().collect()
+    }
+
+    /// Commands that used to succeed but have failed within the last `window_secs`, most recently
+    /// run first. Commands killed by a signal were interrupted rather than broken, so they don't count.
+    pub fn recently_regressed(&self, window_secs: i64, num: i16) -> Vec<Command> {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+            .as_secs() as i64
+            - window_secs;
+
+        self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             GROUP BY cmd
+             HAVING SUM(CASE WHEN when_run < :since AND exit_code = 0 THEN 1 ELSE 0 END) > 0
+             AND SUM(CASE WHEN when_run >= :since AND exit_code != 0 AND exit_signal IS NULL THEN 1 ELSE 0 END) > 0
+             ORDER BY MAX(id) DESC
+             LIMIT :limit",
+            &[(":since", &since), (":limit", &num)],
+        )
+    }
+
+    /// Commands run at least `min_occurrences` times but not in the last `min_age_secs`, most
+    /// often run first, for rediscovering old favorites.
+    pub fn stale_favorites(
+        &self,
+        min_occurrences: i64,
+        min_age_secs: i64,
+        num: i16,
+    ) -> Vec<Command> {
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+            .as_secs() as i64
+            - min_age_secs;
+
+        self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             GROUP BY cmd
+             HAVING COUNT(*) >= :min_occurrences AND MAX(when_run) < :before
+             ORDER BY COUNT(*) DESC, MAX(id) DESC
+             LIMIT :limit",
+            &[
+                (":min_occurrences", &min_occurrences),
+                (":before", &before),
+                (":limit", &num),
+            ],
+        )
+    }
+
+    /// A command worth being reminded of: picked at random from commands that have succeeded
+    /// before, were run at least a few times, and not in the last day, favoring ones run often
+    /// and long ago.
+    pub fn random_useful(&self) -> Option<Command> {
+        self.random_useful_with(&mut rand::thread_rng())
+    }
+
+    /// `random_useful`, drawing from `rng` so the pick can be reproduced.
+    pub fn random_useful_with<R: Rng>(&self, rng: &mut R) -> Option<Command> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+            .as_secs() as i64;
+
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT cmd, COUNT(*), MAX(when_run)
+                 FROM commands
+                 WHERE hidden = 0
+                 GROUP BY cmd
+                 HAVING COUNT(*) >= :min_occurrences
+                    AND MAX(when_run) < :before
+                    AND SUM(CASE WHEN exit_code = 0 THEN 1 ELSE 0 END) > 0
+                 ORDER BY MAX(id)",
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let candidates: Vec<(String, f64)> = statement
+            .query_map_named(
+                &[
+                    (":min_occurrences", &RANDOM_USEFUL_MIN_OCCURRENCES),
+                    (":before", &(now - RANDOM_USEFUL_MIN_AGE_SECS)),
+                ],
+                |row| {
+                    let count: i64 = row.get(1);
+                    let last_run: i64 = row.get(2);
+                    let days_ago = (now - last_run) as f64 / (24.0 * 60.0 * 60.0);
+                    (row.get(0), count as f64 * days_ago)
+                },
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)))
+            .flatten()
+            .collect();
+
+        let cmd = &candidates
+            .choose_weighted(rng, |candidate| candidate.1)
+            .ok()?
+            .0;
+        self.run_query(
+            "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+             FROM commands
+             WHERE cmd = :cmd
+             GROUP BY cmd",
+            &[(":cmd", cmd)],
+        )
+        .into_iter()
+        .next()
+    }
+
+    /// Likely fixes for `failed_cmd`: the first different command to succeed after it failed, in
+    /// the same session and within a few minutes, most often seen first.
+    pub fn fix_for(&self, failed_cmd: &str, num: i16) -> Vec<Command> {
+        self.run_query(
+            "SELECT MAX(fix.id), fix.cmd, fix.cmd_tpl, fix.session_id, fix.when_run, fix.exit_code, fix.selected, fix.dir, fix.dangerous, fix.parent, fix.cursor_pos, fix.note, fix.source, fix.error_snippet
+             FROM commands failed
+             JOIN commands fix ON fix.id = (
+                 SELECT MIN(c.id) FROM commands c
+                 WHERE c.session_id = failed.session_id AND c.id > failed.id
+                 AND c.exit_code = 0 AND c.cmd != failed.cmd
+             )
+             WHERE failed.cmd = :cmd AND failed.exit_code != 0 AND failed.exit_signal IS NULL
+             AND fix.when_run - failed.when_run <= :window
+             GROUP BY fix.cmd
+             ORDER BY COUNT(*) DESC, MAX(fix.id) DESC
+             LIMIT :limit",
+            &[
+                (":cmd", &failed_cmd),
+                (":window", &FIX_WINDOW_SECS),
+                (":limit", &num),
+            ],
+        )
+    }
+
+    /// Commands with `filename` as one of their arguments, on its own or at the end of a path, most
+    /// recent first. `config.yaml` finds `vim config.yaml` and `cp ./conf/config.yaml /tmp`, but
+    /// not `vim myconfig.yaml.bak`.
+    pub fn commands_touching(&self, filename: &str, num: i16) -> Vec<Command> {
+        let suffix = format!("/{}", filename);
+        let mut commands: Vec<Command> = self
+            .run_query(
+                "SELECT MAX(id), cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, dangerous, parent, cursor_pos, note, source, error_snippet
+                 FROM commands
+                 WHERE cmd LIKE :like ESCAPE '\\' AND hidden = 0
+                 GROUP BY cmd
+                 ORDER BY MAX(id) DESC",
+                &[(":like", &format!("%{}%", History::escape_like(filename)))],
+            )
+            .into_iter()
+            .filter(|command| {
+                command
+                    .cmd
+                    .split(|c: char| c.is_whitespace() || "'\";|&<>()=".contains(c))
+                    .any(|token| token == filename || token.ends_with(&suffix))
+            })
+            .collect();
+        if num >= 0 {
+            commands.truncate(num as usize);
+        }
+        commands
+    }
+
+    /// Commands at least `min_length` characters long that were run at least `min_occurrences`
+    /// times, most run first, as candidates for aliases named after their initials.
+    pub fn suggest_aliases(
+        &self,
+        min_length: i64,
+        min_occurrences: i64,
+        num: i16,
+    ) -> Vec<AliasSuggestion> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT cmd, COUNT(*) AS c FROM commands
+                 WHERE hidden = 0 AND LENGTH(cmd) >= :min_length
+                 GROUP BY cmd
+                 HAVING c >= :min_occurrences
+                 ORDER BY c DESC, MAX(id) DESC
+                 LIMIT :limit",
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let counts: Vec<(String, i64)> = statement
+            .query_map_named(
+                &[
+                    (":min_length", &min_length),
+                    (":min_occurrences", &min_occurrences),
+                    (":limit", &num),
+                ],
+                |row| (row.get(0), row.get(1)),
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)))
+            .filter_map(Result::ok)
+            .collect();
+
+        let mut taken: HashSet<String> = HashSet::new();
+        counts
+            .into_iter()
+            .map(|(command, occurrences)| {
+                let initials: String = command
+                    .split_whitespace()
+                    .filter_map(|word| word.chars().find(char::is_ascii_alphanumeric))
+                    .map(|c| c.to_ascii_lowercase())
+                    .collect();
+                let mut name = initials.to_owned();
+                let mut suffix = 2;
+                while !taken.insert(name.to_owned()) {
+                    name = format!("{}{}", initials, suffix);
+                    suffix += 1;
+                }
+                AliasSuggestion {
+                    name,
+                    command,
+                    occurrences,
+                }
+            })
+            .collect()
+    }
+
+    /// Each directory commands have been run in, with its command count and most recent
+    /// `when_run`, most recently active first.
+    pub fn directories(&self, num: i16) -> Vec<(String, i64, i64)> {
+        let query = "SELECT dir, COUNT(*), MAX(when_run) AS last_run
+                     FROM commands
+                     WHERE dir IS NOT NULL
+                     GROUP BY dir
+                     ORDER BY last_run DESC
+                     LIMIT :limit";
+        let mut statement = self
+            .connection
+            .prepare(query)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let dir_iter = statement
+            .query_map_named(&[(":limit", &num)], |row| {
+                (row.get(0), row.get(1), row.get(2))
+            })
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        dir_iter.flatten().collect()
+    }
+
+    /// Other templates run in the same sessions as `cmd_tpl`, with the number of sessions they
+    /// shared with it, most shared first. Imported commands and ones from before sessions were
+    /// tracked don't belong to a real session, so they're left out.
+    pub fn cooccurrence(&self, cmd_tpl: &str, num: i16) -> Vec<(String, i64)> {
+        let query = "SELECT c2.cmd_tpl, COUNT(*) AS c
+                     FROM (SELECT DISTINCT session_id FROM commands
+                           WHERE cmd_tpl = :cmd_tpl AND session_id NOT IN ('IMPORTED', 'UNKNOWN')) c1
+                     JOIN (SELECT DISTINCT session_id, cmd_tpl FROM commands
+                           WHERE session_id NOT IN ('IMPORTED', 'UNKNOWN')) c2
+                       ON c2.session_id = c1.session_id AND c2.cmd_tpl != :cmd_tpl
+                     GROUP BY c2.cmd_tpl
+                     ORDER BY c DESC, c2.cmd_tpl ASC
+                     LIMIT :limit";
+        let mut statement = self
+            .connection
+            .prepare(query)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let cooccurrence_iter = statement
+            .query_map_named(&[(":cmd_tpl", &cmd_tpl), (":limit", &num)], |row| {
+                (row.get(0), row.get(1))
+            })
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        cooccurrence_iter.flatten().collect()
+    }
+
+    /// Replay the last `holdout` commands: rank suggestions from the history and context as it was
+    /// just before each one ran, leaving it and everything after it out, and report how often it
+    /// was among the first `top_n`.
+    pub fn evaluate(&self, holdout: usize, top_n: usize) -> EvalReport {
+        let mut report = EvalReport::default();
+        let mut hits = 0;
+        let mut reciprocal_ranks = 0.0;
+        let weights = self.rank_weights(&Weights::default());
+
+        for command in self.commands(&None, holdout as i16, 0, false, false) {
+            if command.dir.is_none() || command.when_run.is_none() || command.cmd.is_empty() {
+                continue;
+            }
+
+            self.build_cache_table_matching(
+                command.dir.as_deref(),
+                &Some(command.session_id.clone()),
+                None,
+                command.when_run,
+                command.when_run,
+                None,
+                Some(command.id),
+                &weights,
+            );
+            let position = self
+                .find_matches("", -1, false, false, MatchMode::Substring)
+                .iter()
+                .position(|c| c.cmd == command.cmd);
+
+            report.evaluated += 1;
+            if let Some(position) = position {
+                if position < top_n {
+                    hits += 1;
+                }
+                reciprocal_ranks += 1.0 / (position + 1) as f64;
+            }
+        }
+
+        if report.evaluated > 0 {
+            report.recall = f64::from(hits) / report.evaluated as f64;
+            report.mean_reciprocal_rank = reciprocal_ranks / report.evaluated as f64;
+        }
+        report
+    }
+
+    /// Which schema migrations the database has had and which are still pending, for diagnosing a
+    /// database stuck on an old schema.
+    pub fn schema_status(&self) -> schema::MigrationStatus {
+        schema::status(&self.connection)
+    }
+
+    /// How many commands were run in the last `window_secs` seconds, and how many there are in all.
+    pub fn growth_stats(&self, window_secs: i64) -> GrowthStats {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+            .as_secs() as i64
+            - window_secs;
+
+        self.connection
+            .query_row_named(
+                "SELECT SUM(CASE WHEN when_run >= :since THEN 1 ELSE 0 END), COUNT(*) FROM commands",
+                &[(":since", &since)],
+                |row| GrowthStats {
+                    added: row.get::<_, Option<i64>>(0).unwrap_or(0),
+                    total: row.get(1),
+                },
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)))
+    }
+
+    /// How runs of exactly `cmd` have exited, or `None` if it was never run with a known exit code.
+    pub fn likely_exit(&self, cmd: &str) -> Option<ExitStats> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT exit_code, COUNT(*) AS c FROM commands
+                 WHERE cmd = :cmd AND exit_code IS NOT NULL
+                 GROUP BY exit_code
+                 ORDER BY c DESC, exit_code ASC",
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let counts: Vec<(i32, i64)> = statement
+            .query_map_named(&[(":cmd", &cmd)], |row| (row.get(0), row.get(1)))
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)))
+            .filter_map(Result::ok)
+            .collect();
+
+        let (most_common_exit_code, _) = *counts.first()?;
+        let runs: i64 = counts.iter().map(|(_, count)| count).sum();
+        let successes: i64 = counts
+            .iter()
+            .filter(|(exit_code, _)| *exit_code == 0)
+            .map(|(_, count)| count)
+            .sum();
+        Some(ExitStats {
+            runs,
+            success_rate: successes as f64 / runs as f64,
+            most_common_exit_code,
+        })
+    }
+
+    pub fn duplicate_report(&self) -> DuplicateReport {
+        let query = "SELECT cmd, dir, COUNT(*) AS c
+                     FROM commands
+                     GROUP BY cmd, dir
+                     HAVING c > 1
+                     ORDER BY c DESC";
+        let mut statement = self
+            .connection
+            .prepare(query)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let group_iter = statement
+            .query_map(NO_PARAMS, |row| DuplicateGroup {
+                cmd: row.get(0),
+                dir: row.get(1),
+                count: row.get(2),
+            })
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        let mut report = DuplicateReport::default();
+        for group in group_iter.flatten() {
+            let row_bytes = group.cmd.len() + group.dir.as_ref().map_or(0, |dir| dir.len());
+            report.redundant_rows += group.count - 1;
+            report.reclaimable_bytes += (group.count - 1) * row_bytes as i64;
+            report.groups.push(group);
+        }
+
+        report
+    }
+
+    /// Hash every command row (ignoring its id, so a copy in another database compares equal).
+    pub fn snapshot(&self) -> Snapshot {
+        let mut statement = self
+            .connection
+            .prepare("SELECT cmd, session_id, when_run, exit_code, dir FROM commands")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let hash_iter = statement
+            .query_map(NO_PARAMS, |row| {
+                let mut hasher = DefaultHasher::new();
+                row.get::<_, String>(0).hash(&mut hasher);
+                row.get::<_, String>(1).hash(&mut hasher);
+                row.get::<_, Option<i64>>(2).hash(&mut hasher);
+                row.get::<_, Option<i32>>(3).hash(&mut hasher);
+                row.get::<_, Option<String>>(4).hash(&mut hasher);
+                hasher.finish()
+            })
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        let mut snapshot = Snapshot::default();
+        for hash in hash_iter.flatten() {
+            snapshot.count += 1;
+            *snapshot.row_hashes.entry(hash).or_insert(0) += 1;
+        }
+        snapshot
+    }
+
+    /// Spread out commands imported without timestamps, which all got the time of the import, so
+    /// the age factor can tell them apart. Each cluster of imported commands sharing a `when_run`
+    /// is spaced evenly, in id order, over the year leading up to that time. Commands recorded by
+    /// `add` keep their times, however many share a second. Returns how many were moved.
+    pub fn redistribute_timestamps(&self) -> usize {
+        let clusters: Vec<i64> = {
+            let mut statement = self
+                .connection
+                .prepare(
+                    "SELECT when_run FROM commands
+                     WHERE when_run IS NOT NULL AND (session_id = 'IMPORTED' OR source IS NOT NULL)
+                     GROUP BY when_run
+                     HAVING COUNT(*) >= :min_rows",
+                )
+                .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+            let rows = statement
+                .query_map_named(&[(":min_rows", &TIMESTAMP_CLUSTER_MIN_ROWS)], |row| {
+                    row.get(0)
+                })
+                .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+            rows.flatten().collect()
+        };
+
+        self.connection
+            .execute_batch("BEGIN;")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Begin to work ({})", err)));
+        let mut moved = 0;
+        for when_run in clusters {
+            let ids: Vec<i64> = {
+                let mut statement = self
+                    .connection
+                    .prepare("SELECT id FROM commands WHERE when_run = :when_run AND (session_id = 'IMPORTED' OR source IS NOT NULL) ORDER BY id")
+                    .unwrap_or_else(|err| {
+                        panic!(format!("McFly error: Prepare to work ({})", err))
+                    });
+                let rows = statement
+                    .query_map_named(&[(":when_run", &when_run)], |row| row.get(0))
+                    .unwrap_or_else(|err| {
+                        panic!(format!("McFly error: Query Map to work ({})", err))
+                    });
+                rows.flatten().collect()
+            };
+
+            // The last command keeps the original time; the window widens if needed so every
+            // command gets its own second.
+            let window = REDISTRIBUTE_WINDOW_SECS.max(ids.len() as i64);
+            let count = ids.len() as i64;
+            for (i, id) in ids.iter().enumerate() {
+                let spread = when_run - window + (i as i64 + 1) * window / count;
+                self.connection
+                    .execute_named(
+                        "UPDATE main.commands SET when_run = :when_run WHERE id = :id",
+                        &[(":when_run", &spread), (":id", id)],
+                    )
+                    .unwrap_or_else(|err| {
+                        panic!(format!("McFly error: UPDATE of when_run to work ({})", err))
+                    });
+                moved += 1;
+            }
+        }
+        self.connection
+            .execute_batch("COMMIT;")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Commit to work ({})", err)));
+
+        moved
+    }
+
+    fn run_query(&self, query: &str, params: &[(&str, &dyn ToSql)]) -> Vec<Command> {
+        let mut statement = self.connection.prepare(query).unwrap();
+
+        let closure: fn(&Row) -> Command = |row| Command {
+            id: row.get(0),
+            cmd: row.get(1),
+            cmd_tpl: row.get(2),
+            session_id: row.get(3),
+            when_run: row.get(4),
+            exit_code: row.get(5),
+            selected: row.get(6),
+            dir: row.get(7),
+            dangerous: row.get(8),
+            parent: row.get(9),
+            cursor_pos: row.get(10),
+            note: row.get(11),
+            source: row.get(12),
+            error_snippet: row.get(13),
+            ..Command::default()
+        };
+
+        let command_iter: MappedRows<_> = statement
+            .query_map_named(params, closure)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        let mut vec = Vec::new();
+        for result in command_iter {
+            if let Ok(command) = result {
+                vec.push(command);
+            }
+        }
+
+        vec
+    }
+
+    pub fn last_command(&self, session_id: &Option<String>) -> Option<Command> {
+        self.commands(session_id, 1, 0, false, false)
+            .get(0)
+            .cloned()
+    }
+
+    pub fn last_command_templates(
+        &self,
+        session_id: &Option<String>,
+        num: i16,
+        offset: u16,
+    ) -> Vec<String> {
+        self.commands(session_id, num, offset, false, false)
+            .iter()
+            .map(|command| command.cmd_tpl.to_owned())
+            .collect()
+    }
+
+    // `last_command_templates`, counting back from just before `before_id` when it's given.
+    fn templates_before(
+        &self,
+        session_id: &Option<String>,
+        num: i16,
+        before_id: Option<i64>,
+    ) -> Vec<String> {
+        if before_id.is_none() {
+            return self.last_command_templates(session_id, num, 0);
+        }
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT cmd_tpl FROM commands
+                 WHERE (:session_id IS NULL OR session_id = :session_id) AND id < :before_id
+                 ORDER BY id DESC LIMIT :limit",
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Prepare to work ({})", err)));
+        let template_iter = statement
+            .query_map_named(
+                &[
+                    (":session_id", session_id),
+                    (":before_id", &before_id),
+                    (":limit", &num),
+                ],
+                |row| row.get(0),
+            )
+            .unwrap_or_else(|err| panic!(format!("McFly error: Query Map to work ({})", err)));
+
+        template_iter.flatten().collect()
+    }
+
+    pub fn delete_command(&self, command: &str) {
+        self.connection
+            .execute_named(
+                "DELETE FROM selected_commands WHERE cmd = :command",
+                &[(":command", &command)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: DELETE from selected_commands to work ({})",
+                    err
+                ))
+            });
+
+        self.connection
+            .execute_named(
+                "DELETE FROM command_tags WHERE command_id IN (SELECT id FROM main.commands WHERE cmd IN (:command, mcfly_compress(:command)))",
+                &[(":command", &command)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: DELETE from command_tags to work ({})",
+                    err
+                ))
+            });
+
+        self.connection
+            .execute_named(
+                "DELETE FROM main.commands WHERE cmd IN (:command, mcfly_compress(:command))",
+                &[(":command", &command)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: DELETE from commands to work ({})",
+                    err
+                ))
+            });
+    }
+
+    // `dir`'s device and inode as `device:inode`, which survive a rename, when `dir_inodes` is on.
+    // `None` if it can't be read, leaving only the path to match on.
+    #[cfg(unix)]
+    fn dir_inode(&self, dir: &str) -> Option<String> {
+        use std::os::unix::fs::MetadataExt;
+        if !self.dir_inodes {
+            return None;
+        }
+        fs::metadata(dir)
+            .ok()
+            .map(|metadata| format!("{}:{}", metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn dir_inode(&self, _dir: &str) -> Option<String> {
+        None
+    }
+
+    /// `dir` as `add` stores it: starting with `~` if it's under `relative_home`.
+    pub fn home_relative(&self, dir: &str) -> String {
+        if let Some(home) = &self.relative_home {
+            let home = home.trim_end_matches('/');
+            if !home.is_empty() && dir.starts_with(home) {
+                let rest = &dir[home.len()..];
+                if rest.is_empty() || rest.starts_with('/') {
+                    return format!("~{}", rest);
+                }
+            }
+        }
+        dir.to_string()
+    }
+
+    /// Rewrite directories already stored under `relative_home` as `~/...`, returning how many
+    /// commands changed. Rows already converted are left alone.
+    pub fn relativize_dirs(&self) -> usize {
+        let home = match &self.relative_home {
+            Some(home) if !home.trim_end_matches('/').is_empty() => home.trim_end_matches('/'),
+            _ => return 0,
+        };
+        let prefix = format!("{}/%", History::escape_like(home));
+        let mut changed = 0;
+        for column in &["dir", "old_dir"] {
+            changed += self
+                .connection
+                .execute_named(
+                    &format!(
+                        "UPDATE main.commands SET {0} = '~' || substr({0}, length(:home) + 1)
+                         WHERE {0} = :home OR {0} LIKE :prefix ESCAPE '\\'",
+                        column
+                    ),
+                    &[(":home", &home), (":prefix", &prefix)],
+                )
+                .unwrap_or_else(|err| {
+                    panic!(format!(
+                        "McFly error: UPDATE of {} to work ({})",
+                        column, err
+                    ))
+                });
+        }
+        changed
+    }
+
+    /// Delete commands run before `cutoff` (a Unix timestamp), returning how many were deleted.
+    pub fn expire(&self, cutoff: i64) -> usize {
+        self.connection
+            .execute_named(
+                "DELETE FROM command_tags WHERE command_id IN (SELECT id FROM main.commands WHERE when_run < :cutoff)",
+                &[(":cutoff", &cutoff)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: DELETE from command_tags to work ({})",
+                    err
+                ))
+            });
+
+        self.connection
+            .execute_named(
+                "DELETE FROM main.commands WHERE when_run < :cutoff",
+                &[(":cutoff", &cutoff)],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: DELETE from commands to work ({})",
+                    err
+                ))
+            })
+    }
+
+    pub fn update_paths(&self, old_path: &str, new_path: &str, print_output: bool) {
+        let normalized_old_path = path_update_helpers::normalize_path(old_path);
+        let normalized_new_path = path_update_helpers::normalize_path(new_path);
+
+        if normalized_old_path.len() > 1 && normalized_new_path.len() > 1 {
+            let like_query = normalized_old_path.to_string() + "/%";
+
+            let mut dir_update_statement = self.connection.prepare(
+                "UPDATE main.commands SET dir = :new_dir || SUBSTR(dir, :length) WHERE dir = :exact OR dir LIKE (:like)"
+            ).unwrap();
+
+            let mut old_dir_update_statement = self.connection.prepare(
+                "UPDATE main.commands SET old_dir = :new_dir || SUBSTR(old_dir, :length) WHERE old_dir = :exact OR old_dir LIKE (:like)"
+            ).unwrap();
+
+            let affected = dir_update_statement
+                .execute_named(&[
+                    (":like", &like_query),
+                    (":exact", &normalized_old_path),
+                    (":new_dir", &normalized_new_path),
+                    (":length", &(normalized_old_path.chars().count() as u32 + 1)),
+                ])
+                .unwrap_or_else(|err| panic!(format!("McFly error: dir UPDATE to work ({})", err)));
+
+            old_dir_update_statement
+                .execute_named(&[
+                    (":like", &like_query),
+                    (":exact", &normalized_old_path),
+                    (":new_dir", &normalized_new_path),
+                    (":length", &(normalized_old_path.chars().count() as u32 + 1)),
+                ])
+                .unwrap_or_else(|err| {
+                    panic!(format!("McFly error: old_dir UPDATE to work ({})", err))
+                });
+
+            if print_output {
+                println!(
+                    "McFly: Command database paths renamed from {} to {} (affected {} commands)",
+                    normalized_old_path, normalized_new_path, affected
+                );
+            }
+        } else if print_output {
+            println!("McFly: Not updating paths due to invalid options.");
+        }
+    }
+
+    /// Rebuild the database from scratch: copy every command into a fresh database next to the
+    /// current one, recomputing templates along the way, then rename it into place. A failure
+    /// part way through leaves the original untouched. Other processes that have the database
+    /// open keep using the old file until they reopen it.
+    pub fn rebuild(&mut self) -> io::Result<()> {
+        let db_path = self.db_path()?;
+        let mut rebuild_path = db_path.clone().into_os_string();
+        rebuild_path.push(".rebuild");
+        let rebuild_path = PathBuf::from(rebuild_path);
+        if rebuild_path.exists() {
+            fs::remove_file(&rebuild_path)?;
+        }
+
+        {
+            let connection = Connection::open(&rebuild_path).map_err(History::io_error)?;
+            db_extensions::add_db_functions(&connection);
+            History::create_tables(&connection);
+            schema::first_time_setup(&connection);
+
+            connection
+                .execute_named(
+                    "ATTACH DATABASE :path AS old",
+                    &[(":path", &db_path.to_string_lossy().into_owned())],
+                )
+                .map_err(History::io_error)?;
+            connection
+                .execute_batch(
+                    "BEGIN;
+                     INSERT INTO main.commands (id, cmd, cmd_tpl, session_id, when_run, exit_code, exit_signal, selected, dir, old_dir, interactive, dangerous, parent, cursor_pos, note, source, hidden, arg_count, error_snippet, env_hash, dir_inode)
+                         SELECT id, cmd, cmd_tpl, session_id, when_run, exit_code, exit_signal, selected, dir, old_dir, interactive, dangerous, parent, cursor_pos, note, source, hidden, arg_count, error_snippet, env_hash, dir_inode FROM old.commands;
+                     INSERT INTO main.selected_commands (id, cmd, session_id, dir)
+                         SELECT id, cmd, session_id, dir FROM old.selected_commands;
+                     INSERT INTO main.command_tags (id, command_id, tag)
+                         SELECT id, command_id, tag FROM old.command_tags;
+                     INSERT INTO main.import_watermarks (path, entries, last_cmd)
+                         SELECT path, entries, last_cmd FROM old.import_watermarks;
+                     COMMIT;
+                     DETACH DATABASE old;",
+                )
+                .map_err(History::io_error)?;
+
+            let commands: Vec<(i64, String)> = {
+                let mut statement = connection
+                    .prepare("SELECT id, mcfly_decompress(cmd) FROM main.commands")
+                    .map_err(History::io_error)?;
+                let rows = statement
+                    .query_map(NO_PARAMS, |row| (row.get(0), row.get(1)))
+                    .map_err(History::io_error)?;
+                rows.collect::<Result<_, _>>().map_err(History::io_error)?
+            };
+
+            connection
+                .execute_batch("BEGIN;")
+                .map_err(History::io_error)?;
+            {
+                let mut statement = connection
+                    .prepare("UPDATE main.commands SET cmd_tpl = :cmd_tpl WHERE id = :id")
+                    .map_err(History::io_error)?;
+                for (id, cmd) in commands {
+                    statement
+                        .execute_named(&[(":cmd_tpl", &self.template(&cmd)), (":id", &id)])
+                        .map_err(History::io_error)?;
+                }
+            }
+            connection
+                .execute_batch("COMMIT;")
+                .map_err(History::io_error)?;
+        }
+
+        fs::rename(&rebuild_path, &db_path)?;
+
+        self.connection =
+            History::open_connection(&db_path, &self.db_options).map_err(History::io_error)?;
+        db_extensions::add_db_functions(&self.connection);
+        #[cfg(feature = "compression")]
+        compression::install(&self.connection);
+        for (name, path) in &self.overlays {
+            History::attach_read_only(&self.connection, name, path).map_err(History::io_error)?;
+        }
+        Ok(())
+    }
+
+    fn db_path(&self) -> io::Result<PathBuf> {
+        let file: String = self
+            .connection
+            .query_row(
+                "SELECT file FROM pragma_database_list WHERE name = 'main'",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(History::io_error)?;
+        if file.is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "McFly error: The history database isn't backed by a file",
+            ))
+        } else {
+            Ok(PathBuf::from(file))
+        }
+    }
+
+    fn io_error(err: rusqlite::Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    }
+
+    /// Import another shell history file into the database, tagging each command with `source`
+    /// (e.g. `bash`, `zsh`, `manual`) so it can be told apart later. Returns how many were added.
+    pub fn import(&self, path: &PathBuf, history_format: HistoryFormat, source: &str) -> usize {
+        let commands = shell_history::importable_history(path, history_format, self.skip_comments);
+        History::record_watermark(&self.connection, path, &commands);
+        self.insert_imported(commands, source)
+    }
+
+    /// Import the output of `fc -l` piped in from bash or zsh, recording `fc` as the source.
+    /// Returns how many commands were imported.
+    pub fn import_fc<R: Read>(&self, reader: R) -> usize {
+        let commands = shell_history::fc_history(reader, HistoryFormat::Bash);
+        self.insert_imported(commands, "fc")
+    }
+
+    /// Import the commands added to the shell history file at `path` since it was last imported or
+    /// synced, returning how many were added. The first sync of a file that was never imported
+    /// only notes where it ends, since its commands may already be in the database.
+    pub fn sync_history(
+        &self,
+        path: &PathBuf,
+        history_format: HistoryFormat,
+        source: &str,
+    ) -> usize {
+        let commands = shell_history::importable_history(path, history_format, self.skip_comments);
+        let watermark: Option<(i64, String)> = self
+            .connection
+            .query_row_named(
+                "SELECT entries, last_cmd FROM import_watermarks WHERE path = :path",
+                &[(":path", &path.to_string_lossy().into_owned())],
+                |row| (row.get(0), row.get(1)),
+            )
+            .ok();
+        History::record_watermark(&self.connection, path, &commands);
+
+        let start = match watermark {
+            None => commands.len(),
+            // The shell may have trimmed old entries off the start of the file, so if the entry
+            // count no longer lines up, pick up after the last entry seen before.
+            Some((entries, last_cmd)) => {
+                let entries = entries as usize;
+                if entries > 0
+                    && entries <= commands.len()
+                    && commands[entries - 1].command == last_cmd
+                {
+                    entries
+                } else {
+                    commands
+                        .iter()
+                        .rposition(|command| command.command == last_cmd)
+                        .map_or(0, |index| index + 1)
+                }
+            }
+        };
+        let new_commands = commands.into_iter().skip(start).collect();
+        self.insert_imported(new_commands, source)
+    }
+
+    /// `sync_history` for the bash history file in `$HISTFILE`.
+    pub fn sync_bash_history(&self) -> usize {
+        self.sync_history(
+            &shell_history::history_file_path(),
+            HistoryFormat::Bash,
+            HistoryFormat::Bash.name(),
+        )
+    }
+
+    // Remember how far `path` has been imported: how many entries it had, and the last one.
+    fn record_watermark(
+        connection: &Connection,
+        path: &Path,
+        commands: &[shell_history::HistoryCommand],
+    ) {
+        let last_cmd = commands
+            .last()
+            .map_or("", |command| command.command.as_str());
+        connection
+            .execute_named(
+                "INSERT OR REPLACE INTO import_watermarks (path, entries, last_cmd)
+                 VALUES (:path, :entries, :last_cmd)",
+                &[
+                    (":path", &path.to_string_lossy().into_owned()),
+                    (":entries", &(commands.len() as i64)),
+                    (":last_cmd", &last_cmd),
+                ],
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to record import watermark ({})",
+                    err
+                ))
+            });
+    }
+
+    // Insert imported commands the way `add` would template and flag them, leaving out
+    // `ignored_commands`.
+    fn insert_imported(&self, commands: Vec<shell_history::HistoryCommand>, source: &str) -> usize {
+        let mut statement = self.connection
+            .prepare("INSERT INTO main.commands (cmd, cmd_tpl, session_id, when_run, exit_code, selected, dangerous, source, arg_count) VALUES (mcfly_compress(:cmd), :cmd_tpl, :session_id, :when_run, :exit_code, :selected, :dangerous, :source, :arg_count)")
+            .unwrap_or_else(|err| panic!(format!("McFly error: Unable to prepare insert ({})", err)));
+        let mut imported = 0;
+        for command in commands {
+            if command.command.is_empty() || self.is_ignored(&command.command) {
+                continue;
+            }
+            let cmd_tpl = self.template(&command.command);
+            if cmd_tpl.is_empty() {
+                continue;
+            }
+            statement
+                .execute_named(&[
+                    (":cmd", &command.command),
+                    (":cmd_tpl", &cmd_tpl),
+                    (":session_id", &"IMPORTED"),
+                    (":when_run", &command.when),
+                    (":exit_code", &0),
+                    (":selected", &0),
+                    (
+                        ":dangerous",
+                        &History::is_dangerous(&command.command, &self.dangerous_patterns),
+                    ),
+                    (":source", &source),
+                    (":arg_count", &History::arg_count(&command.command)),
+                ])
+                .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+            imported += 1;
+        }
+        imported
+    }
+
+    // A path ready to go in a URI filename, where ? # and % are special.
+    fn uri_path(path: &Path) -> String {
+        path.to_string_lossy()
+            .replace('%', "%25")
+            .replace('?', "%3f")
+            .replace('#', "%23")
+    }
+
+    fn open_connection(path: &Path, options: &DbOptions) -> rusqlite::Result<Connection> {
+        let connection = match &options.vfs {
+            // A VFS can only be picked through a URI filename, where ? # and % are special.
+            Some(vfs) => Connection::open_with_flags(
+                format!("file:{}?vfs={}", History::uri_path(path), vfs),
+                OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI,
+            )?,
+            None => Connection::open(path)?,
+        };
+        if options.exclusive_locking {
+            connection.query_row("PRAGMA locking_mode = EXCLUSIVE", NO_PARAMS, |_| ())?;
+        }
+        Ok(connection)
+    }
+
+    fn from_shell_history(
+        history_format: HistoryFormat,
+        source: &str,
+        config: &Config,
+        skip_comments: bool,
+    ) -> History {
+        print!(
+            "McFly: Importing shell history for the first time. This may take a minute or two..."
+        );
+        io::stdout().flush().unwrap_or_else(|err| {
+            panic!(format!("McFly error: STDOUT flush should work ({})", err))
+        });
+
+        // Load this first to make sure it works before we create the DB.
+        let history_file_path = shell_history::history_file_path();
+        let commands = shell_history::importable_history(
+            &history_file_path,
+            history_format,
+            skip_comments || config.import.skip_comments == Some(true),
+        );
+
+        // Make ~/.mcfly
+        fs::create_dir_all(Settings::storage_dir_path())
+            .unwrap_or_else(|_| panic!("Unable to create {:?}", Settings::storage_dir_path()));
+
+        // Make ~/.mcfly/history.db
+        let connection = History::open_connection(&Settings::mcfly_db_path(), &config.database)
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Unable to create history DB at {:?}",
+                    Settings::mcfly_db_path()
+                )
+            });
+        db_extensions::add_db_functions(&connection);
+        History::create_tables(&connection);
+        let mut history = History::with_connection(connection, &config.database);
+        // So the import leaves out and templates commands the way `add` will.
+        history.configure(config, skip_comments);
+        History::record_watermark(&history.connection, &history_file_path, &commands);
+        history.insert_imported(commands, source);
+        schema::first_time_setup(&history.connection);
+
+        println!("done.");
+
+        history
+    }
+
+    pub(super) fn create_tables(connection: &Connection) {
+        connection.execute_batch(
+            "CREATE TABLE commands( \
+                      id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                      cmd TEXT NOT NULL, \
+                      cmd_tpl TEXT, \
+                      session_id TEXT NOT NULL, \
+                      when_run INTEGER NOT NULL, \
+                      exit_code INTEGER NOT NULL, \
+                      exit_signal INTEGER, \
+                      selected INTEGER NOT NULL, \
+                      dir TEXT, \
+                      old_dir TEXT, \
+                      interactive INTEGER, \
+                      dangerous INTEGER NOT NULL DEFAULT 0, \
+                      parent TEXT, \
+                      cursor_pos INTEGER, \
+                      note TEXT, \
+                      source TEXT, \
+                      hidden INTEGER NOT NULL DEFAULT 0, \
+                      arg_count INTEGER, \
+                      error_snippet TEXT, \
+                      env_hash TEXT, \
+                      dir_inode TEXT \
+                  ); \
+                  CREATE INDEX command_cmds ON commands (cmd);\
+                  CREATE INDEX command_session_id ON commands (session_id);\
+                  CREATE INDEX command_dirs ON commands (dir);\
+                  \
+                  CREATE TABLE selected_commands( \
+                      id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                      cmd TEXT NOT NULL, \
+                      session_id TEXT NOT NULL, \
+                      dir TEXT NOT NULL \
+                  ); \
+                  CREATE INDEX selected_command_session_cmds ON selected_commands (session_id, cmd);\
+                  \
+                  CREATE TABLE command_tags( \
+                      id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                      command_id INTEGER NOT NULL, \
+                      tag TEXT NOT NULL \
+                  ); \
+                  CREATE UNIQUE INDEX command_tags_command_id_tag ON command_tags (command_id, tag);\
+                  \
+                  CREATE TABLE import_watermarks( \
+                      path TEXT PRIMARY KEY, \
+                      entries INTEGER NOT NULL, \
+                      last_cmd TEXT NOT NULL \
+                  );"
+        ).unwrap_or_else(|err| panic!(format!("McFly error: Unable to initialize history db ({})", err)));
+    }
+
+    pub(super) fn from_db_path(path: PathBuf, options: &DbOptions) -> History {
+        let connection = History::open_connection(&path, options).unwrap_or_else(|err| {
+            panic!(format!(
+                "McFly error: Unable to open history database ({})",
+                err
+            ))
+        });
+        db_extensions::add_db_functions(&connection);
+        History::with_connection(connection, options)
+    }
+
+    // Every option at its default, for both ways of opening the database.
+    fn with_connection(connection: Connection, options: &DbOptions) -> History {
+        History {
+            connection,
+            network: Network::default(),
+            session_id: None,
+            dir: None,
+            template_placeholders: false,
+            template_command: None,
+            dangerous_patterns: History::default_dangerous_patterns(),
+            parent: None,
+            preferred_commands: HashMap::new(),
+            min_occurrences: 1,
+            ignored_commands: IGNORED_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            skip_comments: false,
+            shlvl: None,
+            max_shlvl: None,
+            lookback: 3,
+            same_program_weight: 0.0,
+            modifier_programs: MODIFIER_PROGRAMS.iter().map(|p| p.to_string()).collect(),
+            equivalent_dirs: Vec::new(),
+            private_dirs: Vec::new(),
+            relative_home: None,
+            max_age_days: None,
+            negative_terms: false,
+            unique_templates: false,
+            match_env_hash: None,
+            dir_inodes: false,
+            session_context_only: false,
+            occurrence_window_days: None,
+            max_history_rows: None,
+            modifier_weight: 0.0,
+            recent_sessions: 1,
+            session_recency_weight: 0.0,
+            arg_count_weight: 0.0,
+            missing_path_weight: 0.0,
+            query_length_weight: 0.0,
+            normalization: None,
+            timing: false,
+            timings: Cell::new(Timings::default()),
+            db_options: options.to_owned(),
+            overlays: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AliasSuggestion, Command, DangerPolicy, DbOptions, EditRequest, ExitStats, Features,
+        FilterReason, GrowthStats, History, MatchMode, Normalization, SnapshotDiff,
+    };
+    use crate::config::{Config, Weights};
+    use crate::history::schema;
+    use crate::network::Network;
+    use crate::node::Node;
+    use crate::settings::{HistoryFormat, IgnorePrevious};
+    use crate::simplified_command::simplify_command;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rusqlite::NO_PARAMS;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn in_memory_history() -> History {
+        let history = History::from_db_path(PathBuf::from(":memory:"), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        history
+    }
+
+    fn add(history: &History, cmd: &str, session_id: &str, dir: &str, when_run: i64, exit: i32) {
+        history.add(
+            cmd,
+            Some(session_id),
+            Some(dir),
+            &Some(when_run),
+            Some(exit),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn should_add_respects_ignore_previous() {
+        let history = in_memory_history();
+        add(&history, "make", "s1", "/tmp", now(), 0);
+
+        assert!(history.should_add("make", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("make", "s1", IgnorePrevious::Session));
+        assert!(!history.should_add("make", "s1", IgnorePrevious::Global));
+
+        assert!(history.should_add("make", "s2", IgnorePrevious::Off));
+        assert!(history.should_add("make", "s2", IgnorePrevious::Session));
+        assert!(!history.should_add("make", "s2", IgnorePrevious::Global));
+    }
+
+    #[test]
+    fn should_add_skips_mcfly_internal_commands() {
+        let history = in_memory_history();
+        assert!(!history.should_add("mcfly init zsh", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("mcfly search foo", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("mcfly add --exit 0", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("eval \"$(mcfly init bash)\"", "s1", IgnorePrevious::Off));
+
+        assert!(history.should_add("echo mcfly", "s1", IgnorePrevious::Off));
+        assert!(history.should_add("mcfly train", "s1", IgnorePrevious::Off));
+        assert!(history.should_add("vim mcfly init", "s1", IgnorePrevious::Off));
+    }
+
+    #[test]
+    fn should_add_supports_prefix_ignores() {
+        let mut history = in_memory_history();
+        assert!(!history.should_add("ls", "s1", IgnorePrevious::Off));
+        assert!(history.should_add("ls -la", "s1", IgnorePrevious::Off));
+
+        history.ignored_commands = vec!["ls*".to_string(), "cd".to_string()];
+        assert!(!history.should_add("ls", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("ls -la", "s1", IgnorePrevious::Off));
+        assert!(!history.should_add("cd", "s1", IgnorePrevious::Off));
+        assert!(history.should_add("cd /tmp", "s1", IgnorePrevious::Off));
+        assert!(history.should_add("make", "s1", IgnorePrevious::Off));
+    }
+
+    #[test]
+    fn should_add_can_skip_nested_shells() {
+        let mut history = in_memory_history();
+        history.shlvl = Some(5);
+        assert!(history.should_add("make", "s1", IgnorePrevious::Off));
+
+        history.max_shlvl = Some(2);
+        assert!(!history.should_add("make", "s1", IgnorePrevious::Off));
+        history.shlvl = Some(1);
+        assert!(history.should_add("make", "s1", IgnorePrevious::Off));
+        history.shlvl = None;
+        assert!(history.should_add("make", "s1", IgnorePrevious::Off));
+    }
+
+    #[test]
+    fn add_defaults_to_the_session_from_the_environment() {
+        let mut history = in_memory_history();
+        history.read_env_from(|name| match name {
+            "MCFLY_SESSION_ID" => Some("env-session".to_string()),
+            "MCFLY_DIR" => Some("/env/dir".to_string()),
+            _ => None,
+        });
+
+        history.add(
+            "make",
+            None,
+            None,
+            &Some(now()),
+            Some(0),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+        history.add(
+            "make test",
+            Some("s1"),
+            Some("/tmp"),
+            &Some(now()),
+            Some(0),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].session_id, "env-session");
+        assert_eq!(commands[0].dir, Some("/env/dir".to_string()));
+        assert_eq!(commands[1].session_id, "s1");
+        assert_eq!(commands[1].dir, Some("/tmp".to_string()));
+    }
+
+    #[test]
+    fn add_records_the_parent_program() {
+        let mut history = in_memory_history();
+        let now = now();
+        history.add(
+            "make test",
+            Some("s1"),
+            Some("/src"),
+            &Some(now - 20),
+            Some(0),
+            &None,
+            true,
+            Some("vim"),
+            None,
+            None,
+        );
+        add(&history, "make", "s1", "/src", now - 10, 0);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].parent, Some("vim".to_string()));
+        assert_eq!(commands[1].parent, None);
+
+        history.parent = Some("vim".to_string());
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let with_parent = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        let make_test = with_parent.iter().find(|c| c.cmd == "make test").unwrap();
+        assert_eq!(make_test.parent, Some("vim".to_string()));
+
+        history.parent = None;
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let without_parent = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        let rank =
+            |matches: &[Command], cmd: &str| matches.iter().find(|c| c.cmd == cmd).unwrap().rank;
+        assert!(rank(&with_parent, "make test") > rank(&without_parent, "make test"));
+        assert_eq!(rank(&with_parent, "make"), rank(&without_parent, "make"));
+    }
+
+    #[test]
+    fn add_records_the_cursor_position_of_edit_markers() {
+        assert_eq!(
+            History::parse_edit_marker("#mcfly:edit:7 git commit -m ''"),
+            Some(("git commit -m ''", 7))
+        );
+        assert_eq!(History::parse_edit_marker("#mcfly:edit:99 ls"), None);
+        assert_eq!(History::parse_edit_marker("#mcfly:edit:ls"), None);
+        assert_eq!(History::parse_edit_marker("#mcfly: git status"), None);
+
+        let history = in_memory_history();
+        let marker = "#mcfly:edit:7 git commit -m ''";
+        assert!(history.should_add(marker, "s1", IgnorePrevious::Global));
+        assert!(!history.should_add("#mcfly: git status", "s1", IgnorePrevious::Global));
+
+        add(&history, marker, "s1", "/tmp", now() - 10, 0);
+        add(&history, "git status", "s1", "/tmp", now(), 0);
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].cmd, "git commit -m ''");
+        assert_eq!(commands[0].cursor_pos, Some(7));
+        assert_eq!(commands[1].cursor_pos, None);
+        assert!(!history.should_add("#mcfly:edit:3 git status", "s1", IgnorePrevious::Global));
+    }
+
+    #[test]
+    fn parse_mcfly_marker_decodes_searches_and_edits() {
+        assert_eq!(
+            History::parse_mcfly_marker("#mcfly:edit:4 git status"),
+            Some(EditRequest::Edit {
+                command: "git status".to_string(),
+                cursor_pos: 4,
+            })
+        );
+        assert_eq!(
+            History::parse_mcfly_marker("#mcfly: git st"),
+            Some(EditRequest::Search {
+                query: "git st".to_string(),
+            })
+        );
+        assert_eq!(
+            History::parse_mcfly_marker("#mcfly:edit:x git status"),
+            None
+        );
+        assert_eq!(History::parse_mcfly_marker("git status"), None);
+    }
+
+    #[test]
+    fn add_can_record_templates_with_placeholders() {
+        let mut history = in_memory_history();
+        add(&history, "ssh web42", "s1", "/tmp", now(), 0);
+        history.template_placeholders = true;
+        add(&history, "ssh web17", "s1", "/tmp", now(), 0);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].cmd_tpl, "ssh web42");
+        assert_eq!(commands[1].cmd_tpl, "ssh webNUM");
+        assert_eq!(commands[1].cmd, "ssh web17");
+    }
+
+    #[test]
+    fn add_flags_dangerous_commands() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "rm -rf /", "s1", "/tmp", now - 30, 0);
+        add(&history, "rm notes.txt", "s1", "/tmp", now - 20, 0);
+        add(
+            &history,
+            "git push --force origin main",
+            "s1",
+            "/tmp",
+            now - 10,
+            0,
+        );
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        let flags: Vec<bool> = commands.iter().map(|c| c.dangerous).collect();
+        assert_eq!(flags, vec![true, false, true]);
+
+        history.set_dangerous(commands[0].id, false);
+        history.set_dangerous(commands[1].id, true);
+        let flags: Vec<bool> = history
+            .commands(&None, -1, 0, false, true)
+            .iter()
+            .map(|c| c.dangerous)
+            .collect();
+        assert_eq!(flags, vec![false, true, true]);
+    }
+
+    #[test]
+    fn current_context_reports_the_last_command_templates() {
+        let mut history = in_memory_history();
+        history.lookback = 2;
+        history.dir = Some("/src".to_string());
+        let now = now();
+        add(&history, "make build", "s1", "/src", now - 40, 0);
+        add(&history, "git commit -m 'one'", "s1", "/src", now - 30, 0);
+        add(&history, "make test", "s2", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        let context = history.current_context(&Some("s1".to_string()));
+        assert_eq!(
+            context.last_command_templates,
+            vec!["make test", "git commit"]
+        );
+        assert_eq!(context.dir, "/src");
+        assert_eq!(context.max_occurrences, 2.0);
+        assert_eq!(context.when_run_max - context.when_run_min, 30.0);
+    }
+
+    #[test]
+    fn session_context_only_leaves_a_new_session_without_context() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make build", "s1", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        let new_session = Some("s2".to_string());
+        assert_eq!(
+            history.current_context(&new_session).last_command_templates,
+            vec!["make test", "make build", ""]
+        );
+
+        history.session_context_only = true;
+        assert!(history
+            .current_context(&new_session)
+            .last_command_templates
+            .is_empty());
+        history.build_cache_table(Some("/src"), &new_session, None, None, Some(now));
+        assert_eq!(
+            history
+                .find_matches("make", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn max_history_rows_only_ranks_the_newest_commands() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make build", "s1", "/src", now - 50, 0);
+        add(&history, "make build", "s1", "/src", now - 40, 0);
+        add(&history, "git status", "s1", "/tmp", now - 30, 0);
+        add(&history, "make test", "s1", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+        let order = |history: &History| -> Vec<String> {
+            history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+            history
+                .find_matches("", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect()
+        };
+
+        let uncapped: Vec<String> = order(&history)
+            .into_iter()
+            .filter(|cmd| cmd != "make build")
+            .collect();
+        history.max_history_rows = Some(3);
+        assert_eq!(order(&history), uncapped);
+        history.max_history_rows = Some(100);
+        assert_eq!(order(&history).len(), 3);
+    }
+
+    #[test]
+    fn occurrence_window_days_leaves_out_old_runs() {
+        let mut history = in_memory_history();
+        let now = now();
+        let day = 24 * 60 * 60;
+        for i in 0..3 {
+            add(&history, "make legacy", "s1", "/src", now - 60 * day + i, 0);
+        }
+        add(&history, "make test", "s1", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+        let occurrences = |history: &History| {
+            history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+            let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+            let factor = |cmd: &str| {
+                matches
+                    .iter()
+                    .find(|c| c.cmd == cmd)
+                    .unwrap()
+                    .features
+                    .occurrences_factor
+            };
+            (factor("make legacy"), factor("make test"))
+        };
+
+        let (legacy, test) = occurrences(&history);
+        assert!((legacy - 1.0).abs() < 1e-9);
+        assert!((test - 2.0 / 3.0).abs() < 1e-9);
+
+        history.occurrence_window_days = Some(30);
+        let (legacy, test) = occurrences(&history);
+        assert!(legacy.abs() < 1e-9);
+        assert!((test - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn danger_policy_separates_dangerous_matches() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "rm -rf build", "s1", "/tmp", now - 20, 0);
+        add(&history, "rm build.log", "s1", "/tmp", now - 10, 0);
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+
+        let search = |policy| {
+            let split = history.find_matches_with_policy(
+                "rm",
+                10,
+                false,
+                false,
+                MatchMode::Substring,
+                policy,
+            );
+            let cmds = |commands: Vec<Command>| -> Vec<String> {
+                commands.into_iter().map(|c| c.cmd).collect()
+            };
+            (cmds(split.matches), cmds(split.dangerous))
+        };
+
+        let (matches, dangerous) = search(DangerPolicy::Separate);
+        assert_eq!(matches, vec!["rm build.log"]);
+        assert_eq!(dangerous, vec!["rm -rf build"]);
+
+        let (matches, dangerous) = search(DangerPolicy::Exclude);
+        assert_eq!(matches, vec!["rm build.log"]);
+        assert!(dangerous.is_empty());
+
+        let (matches, dangerous) = search(DangerPolicy::Include);
+        assert_eq!(matches.len(), 2);
+        assert!(dangerous.is_empty());
+    }
+
+    #[test]
+    fn set_note_attaches_a_note_to_a_command() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "./deploy.sh staging", "s1", "/src", now - 20, 0);
+        add(&history, "./deploy.sh staging", "s1", "/src", now - 10, 0);
+
+        let id = history.commands(&None, 1, 0, false, true)[0].id;
+        history.set_note(id, Some("staging, not prod"));
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands[0].note, Some("staging, not prod".to_string()));
+        assert_eq!(commands[1].note, None);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let matches = history.find_matches("deploy", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches[0].note, Some("staging, not prod".to_string()));
+
+        history.set_note(id, None);
+        assert_eq!(history.commands(&None, 1, 0, false, true)[0].note, None);
+    }
+
+    #[test]
+    fn find_matches_global_ignores_the_current_directory() {
+        let mut history = in_memory_history();
+        let now = now();
+        for i in 0..3 {
+            add(&history, "make deploy", "s1", "/ops", now - 100 + i, 0);
+        }
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let local = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        let make_test = local.iter().find(|c| c.cmd == "make test").unwrap();
+        assert_eq!(make_test.features.dir_factor, 1.0);
+
+        history.equivalent_dirs = vec!["/src".to_string()];
+        let global = history.find_matches_global("make", 10, false, false, MatchMode::Substring);
+        assert!(global.iter().all(|c| c.features.dir_factor == 0.0));
+        assert_eq!(
+            global.iter().map(|c| c.cmd.as_str()).collect::<Vec<_>>(),
+            vec!["make deploy", "make test"]
+        );
+    }
+
+    #[test]
+    fn find_matches_global_gives_unknown_dirs_no_credit_either() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make lint", "s1", "", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        let global = history.find_matches_global("make", 10, false, false, MatchMode::Substring);
+        assert!(global.iter().all(|c| c.features.dir_factor == 0.0));
+        assert_eq!(
+            global.iter().map(|c| c.cmd.as_str()).collect::<Vec<_>>(),
+            vec!["make test", "make lint"]
+        );
+    }
+
+    #[test]
+    fn find_matches_prefiltered_ranks_only_matching_commands() {
+        let history = in_memory_history();
+        let now = now();
+        for i in 0..5 {
+            add(&history, "ls -la", "s1", "/src", now - 100 + i, 0);
+        }
+        add(&history, "cargo build", "s1", "/src", now - 30, 0);
+        add(&history, "cargo test", "s1", "/src", now - 20, 0);
+        add(&history, "cargo test", "s1", "/src", now - 10, 0);
+
+        history.build_cache_table(Some("/src"), &None, None, None, None);
+        let full = history.find_matches("cargo", 10, false, false, MatchMode::Substring);
+        let prefiltered =
+            history.find_matches_prefiltered("cargo", 10, false, false, MatchMode::Substring);
+        assert!(prefiltered
+            .iter()
+            .all(|c| full.iter().any(|f| f.cmd == c.cmd)));
+        assert_eq!(
+            prefiltered
+                .iter()
+                .map(|c| c.cmd.as_str())
+                .collect::<Vec<_>>(),
+            vec!["cargo test", "cargo build"]
+        );
+
+        let cached: i64 = history
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM contextual_commands",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(cached, 2);
+        assert_eq!(prefiltered[0].features.occurrences_factor, 1.0);
+    }
+
+    #[test]
+    fn private_dirs_are_left_out_of_search() {
+        let mut history = in_memory_history();
+        history.private_dirs = vec!["/work/client".to_string()];
+        let now = now();
+        add(
+            &history,
+            "make secret",
+            "s1",
+            "/work/client/app",
+            now - 30,
+            0,
+        );
+        add(&history, "make release", "s1", "/work/client", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        history.build_cache_table(Some("/work/client/app"), &None, None, None, Some(now));
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(
+            matches.iter().map(|c| c.cmd.as_str()).collect::<Vec<_>>(),
+            vec!["make test"]
+        );
+        let (_, rejected) =
+            history.find_matches_debug("make", 10, false, false, MatchMode::Substring);
+        assert!(rejected
+            .iter()
+            .all(|(_, reason)| *reason == FilterReason::PrivateDir));
+        assert_eq!(rejected.len(), 2);
+
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 3);
+    }
+
+    #[test]
+    fn overlays_are_searched_alongside_the_history() {
+        let path = env::temp_dir().join(format!("mcfly-overlay-test-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        let now = now();
+        {
+            let recipes = History::from_db_path(path.to_owned(), &DbOptions::default());
+            History::create_tables(&recipes.connection);
+            schema::first_time_setup(&recipes.connection);
+            add(
+                &recipes,
+                "terraform plan -out tfplan",
+                "s1",
+                "/ops",
+                now - 20,
+                0,
+            );
+        }
+
+        let mut history = in_memory_history();
+        add(&history, "terraform apply", "s1", "/src", now - 10, 0);
+        history.attach_overlay("recipes", &path);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let matches = history.find_matches("terraform", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 2);
+        let shared = matches
+            .iter()
+            .find(|c| c.cmd == "terraform plan -out tfplan")
+            .unwrap();
+        assert_eq!(shared.source.as_deref(), Some("recipes"));
+        assert!(shared.id < 0);
+        let own = matches.iter().find(|c| c.cmd == "terraform apply").unwrap();
+        assert_eq!(own.source, None);
+
+        assert!(history
+            .connection
+            .execute("DELETE FROM recipes.commands", NO_PARAMS)
+            .is_err());
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn session_recency_weight_favors_the_latest_session() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make build", "old", "/src", now - 3000, 0);
+        add(&history, "make build", "old", "/src", now - 2900, 0);
+        add(&history, "make build", "old", "/src", now - 2800, 0);
+        add(&history, "make test", "latest", "/tmp", now - 200, 0);
+        add(&history, "make test", "latest", "/tmp", now - 100, 0);
+        let top = |history: &History| {
+            history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+            history.find_matches("make", 1, false, false, MatchMode::Substring)[0]
+                .cmd
+                .to_owned()
+        };
+        assert_eq!(top(&history), "make build");
+
+        history.session_recency_weight = 1.0;
+        assert_eq!(top(&history), "make test");
+    }
+
+    #[test]
+    fn build_cache_table_can_be_rebuilt_on_one_connection() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        assert_eq!(
+            history
+                .find_matches("make", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn home_relative_dirs_match_across_home_directories() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/home/alice/proj", now - 30, 0);
+
+        history.relative_home = Some("/home/alice".to_string());
+        assert_eq!(history.home_relative("/home/alice/proj"), "~/proj");
+        assert_eq!(history.home_relative("/home/alice"), "~");
+        assert_eq!(
+            history.home_relative("/home/alicia/proj"),
+            "/home/alicia/proj"
+        );
+        assert_eq!(history.relativize_dirs(), 1);
+        assert_eq!(history.relativize_dirs(), 0);
+        add(&history, "make test", "s1", "/home/alice/proj", now - 20, 0);
+        let dirs: Vec<_> = history
+            .commands(&None, -1, 0, false, true)
+            .into_iter()
+            .map(|c| c.dir)
+            .collect();
+        assert_eq!(dirs, vec![Some("~/proj".to_string()); 2]);
+
+        history.relative_home = Some("/home/bob/".to_string());
+        assert_eq!(history.home_relative("/home/bob/proj"), "~/proj");
+        history.build_cache_table(Some("/home/bob/proj"), &None, None, None, Some(now));
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|c| c.features.dir_factor == 1.0));
+    }
+
+    #[test]
+    fn missing_path_weight_penalizes_commands_with_missing_paths() {
+        let existing = env::temp_dir().join(format!("mcfly-missing-path-test-{}", process::id()));
+        fs::write(&existing, "").unwrap();
+        let existing_cmd = format!("cat {}", existing.display());
+        let missing_cmd = "cat /nonexistent/mcfly/file.txt";
+
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, &existing_cmd, "s1", "/src", now - 20, 0);
+        add(&history, missing_cmd, "s1", "/src", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let ranks = |history: &History| {
+            let matches = history.find_matches("cat", 10, false, false, MatchMode::Substring);
+            let rank = |cmd: &str| matches.iter().find(|c| c.cmd == cmd).unwrap().rank;
+            (rank(&existing_cmd), rank(missing_cmd))
+        };
+
+        let (existing_before, missing_before) = ranks(&history);
+        history.missing_path_weight = 0.1;
+        let (existing_after, missing_after) = ranks(&history);
+        assert!((existing_after - existing_before).abs() < 1e-9);
+        assert!((missing_before - missing_after - 0.1).abs() < 1e-9);
+
+        let _ = fs::remove_file(&existing);
+    }
+
+    #[test]
+    fn query_length_weight_favors_commands_a_long_query_nearly_covers() {
+        let mut history = in_memory_history();
+        let now = now();
+        let long = "docker run --rm -it node:14 npm test";
+        let longer = "docker run --rm -it node:14 npm test -- --coverage --watchAll=false --ci";
+        add(&history, longer, "s1", "/src", now - 40, 0);
+        add(&history, long, "s1", "/src", now - 30, 0);
+        add(&history, longer, "s1", "/src", now - 20, 0);
+        add(&history, "docker ps", "s1", "/src", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let best = |history: &History, query: &str| {
+            history.find_matches(query, 10, false, false, MatchMode::Substring)[0]
+                .cmd
+                .to_owned()
+        };
+
+        assert_eq!(best(&history, "docker run --rm -it node:14 npm"), longer);
+        history.query_length_weight = 1.0;
+        assert_eq!(best(&history, "docker run --rm -it node:14 npm"), long);
+        assert_eq!(best(&history, "docker"), "docker ps");
+    }
+
+    #[test]
+    fn serve_line_protocol_answers_each_query_with_json() {
+        let mut history = in_memory_history();
+        history.dir = Some("/src".to_string());
+        let now = now();
+        add(&history, "git status", "s1", "/src", now - 20, 0);
+        add(&history, "echo \"done\"", "s1", "/src", now - 10, 0);
+
+        let mut output = Vec::new();
+        history
+            .serve_line_protocol(io::Cursor::new("git\necho\n"), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"query\":\"git\",\"results\":[{\"rank\":"));
+        assert!(lines[0]
+            .contains("\"cmd\":\"git status\",\"dir\":\"/src\",\"factors\":{\"age_factor\":"));
+        assert!(lines[1].contains("\"cmd\":\"echo \\\"done\\\"\""));
+        assert!(!lines[1].contains("git status"));
+    }
+
+    #[test]
+    fn negative_terms_exclude_matching_commands() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "docker ps", "s1", "/src", now - 30, 0);
+        add(&history, "docker system prune", "s1", "/src", now - 20, 0);
+        add(&history, "docker image ls", "s1", "/src", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let search = |history: &History| {
+            let mut cmds: Vec<String> = history
+                .find_matches("docker -prune", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect();
+            cmds.sort();
+            cmds
+        };
+        assert!(search(&history).is_empty());
+
+        history.negative_terms = true;
+        assert_eq!(search(&history), vec!["docker image ls", "docker ps"]);
+    }
+
+    #[test]
+    fn unique_templates_keep_the_best_command_per_template() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "git commit -m 'one'", "s1", "/src", now - 40, 0);
+        add(&history, "git commit -m 'two'", "s1", "/src", now - 30, 0);
+        add(&history, "git commit -m 'three'", "s1", "/src", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let all = history.find_matches("git", 10, false, false, MatchMode::Substring);
+        assert_eq!(all.len(), 4);
+        let best_commit = all.iter().find(|c| c.cmd_tpl == "git commit").unwrap();
+
+        history.unique_templates = true;
+        let unique = history.find_matches("git", 10, false, false, MatchMode::Substring);
+        let commits: Vec<_> = unique
+            .iter()
+            .filter(|c| c.cmd_tpl == "git commit")
+            .collect();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].cmd, best_commit.cmd);
+        assert_eq!(unique.len(), 2);
+    }
+
+    #[test]
+    fn dir_inodes_keep_context_across_a_rename() {
+        let base = env::temp_dir().join(format!("mcfly-inode-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let old = base.join("project");
+        let new = base.join("renamed");
+        fs::create_dir_all(&old).unwrap();
+
+        let mut history = in_memory_history();
+        history.dir_inodes = true;
+        let now = now();
+        add(&history, "make", "s1", old.to_str().unwrap(), now - 20, 0);
+        add(&history, "top", "s1", "/", now - 10, 0);
+        fs::rename(&old, &new).unwrap();
+
+        let dir_factor = |history: &History| {
+            history.build_cache_table(new.to_str(), &None, None, None, Some(now));
+            history.find_matches("make", 10, false, false, MatchMode::Substring)[0]
+                .features
+                .dir_factor
+        };
+        assert!((dir_factor(&history) - 1.0).abs() < 1e-9);
+        history.dir_inodes = false;
+        assert!(dir_factor(&history).abs() < 1e-9);
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn match_env_hash_limits_matches_to_that_environment() {
+        let mut history = in_memory_history();
+        let now = now();
+        for (cmd, env_hash, when) in &[
+            ("kubectl get pods", Some("prod"), now - 30),
+            ("kubectl logs api", Some("staging"), now - 20),
+            ("kubectl describe node", None, now - 10),
+        ] {
+            history.add(
+                cmd,
+                Some("s1"),
+                Some("/src"),
+                &Some(*when),
+                Some(0),
+                &None,
+                true,
+                None,
+                None,
+                *env_hash,
+            );
+        }
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let search = |history: &History| {
+            history
+                .find_matches("kubectl", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(search(&history).len(), 3);
+
+        history.match_env_hash = Some("prod".to_string());
+        assert_eq!(search(&history), vec!["kubectl get pods"]);
+        let (_, rejected) =
+            history.find_matches_debug("kubectl", 10, false, false, MatchMode::Substring);
+        assert_eq!(rejected.len(), 2);
+        assert!(rejected
+            .iter()
+            .all(|(_, reason)| *reason == FilterReason::OtherEnvironment));
+    }
+
+    #[test]
+    fn find_matches_debug_reports_why_commands_were_left_out() {
+        let mut history = in_memory_history();
+        history.min_occurrences = 2;
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 60, 0);
+        add(&history, "make", "s1", "/src", now - 50, 0);
+        add(&history, "make clean", "s1", "/src", now - 40, 0);
+        add(&history, "make clean", "s1", "/src", now - 30, 0);
+        add(&history, "make docs", "s1", "/src", now - 20, 0);
+        add(&history, "cargo test", "s1", "/src", now - 10, 0);
+        let clean = history.commands(&None, -1, 0, false, true)[2].id;
+        history.hide(clean);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let (matches, rejected) =
+            history.find_matches_debug("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(
+            matches.iter().map(|c| c.cmd.as_str()).collect::<Vec<_>>(),
+            vec!["make"]
+        );
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|(c, reason)| (c.cmd.as_str(), *reason))
+                .collect::<Vec<_>>(),
+            vec![
+                ("make docs", FilterReason::TooFewOccurrences),
+                ("make clean", FilterReason::Hidden),
+            ]
+        );
+    }
+
+    #[test]
+    fn find_matches_debug_reports_commands_beyond_the_row_limit() {
+        let mut history = in_memory_history();
+        history.max_history_rows = Some(2);
+        let now = now();
+        add(&history, "make old", "s1", "/src", now - 30, 0);
+        add(&history, "make test", "s1", "/src", now - 20, 0);
+        add(&history, "cargo build", "s1", "/src", now - 10, 0);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let (matches, rejected) =
+            history.find_matches_debug("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|(c, reason)| (c.cmd.as_str(), *reason))
+                .collect::<Vec<_>>(),
+            vec![("make old", FilterReason::BeyondRowLimit)]
+        );
+    }
+
+    #[test]
+    fn hide_removes_a_command_from_search_but_not_stats() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "rm -i junk", "s1", "/tmp", now - 30, 0);
+        add(&history, "rm -i junk", "s1", "/tmp", now - 20, 0);
+        add(&history, "rm -i temp", "s1", "/tmp", now - 10, 0);
+        let id = history.commands(&None, 1, 0, false, true)[0].id;
+
+        history.hide(id);
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        let matches = history.find_matches("rm", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].cmd, "rm -i temp");
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 3);
+        assert_eq!(history.templates(10)[0].count, 3);
+
+        history.unhide(id);
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        assert_eq!(
+            history
+                .find_matches("rm", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn apply_config_overrides_defaults_and_maintain_expires_old_commands() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(
+            &history,
+            "make old",
+            "s1",
+            "/tmp",
+            now - 10 * 24 * 60 * 60,
+            0,
+        );
+        add(&history, "make new", "s1", "/tmp", now - 60, 0);
+
+        let config = Config::parse(
+            "[weights]\nsame_program = 0.5\n[retention]\nmax_age_days = 7\n[search]\nlookback = 5\n",
+        )
+        .unwrap();
+        history.apply_config(&config);
+
+        assert_eq!(history.same_program_weight, 0.5);
+        assert_eq!(history.lookback, 5);
+        assert_eq!(history.min_occurrences, 1);
+        assert_eq!(history.max_age_days, Some(7));
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 2);
+
+        assert_eq!(history.maintain(now), (1, 0));
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].cmd, "make new");
+    }
+
+    #[test]
+    fn a_lookback_of_0_in_the_config_still_searches() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 20, 0);
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+
+        history.apply_config(&Config::parse_sections(
+            "[search]\nlookback = 0\n",
+            "config.toml",
+        ));
+        assert_eq!(history.lookback, 1);
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 2);
+    }
+
+    #[test]
+    fn tag_matching_tags_every_matching_command() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "git status", "s1", "/src", now - 40, 0);
+        add(&history, "ls -la", "s1", "/src", now - 30, 0);
+        add(&history, "git commit -m 'wip'", "s1", "/src", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+
+        assert_eq!(history.tag_matching("git", "git").unwrap(), 3);
+        assert_eq!(history.tag_matching("git", "git").unwrap(), 0);
+        assert_eq!(history.tag_matching("status", "vcs").unwrap(), 2);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(history.tags(commands[0].id), vec!["git", "vcs"]);
+        assert!(history.tags(commands[1].id).is_empty());
+        assert_eq!(history.tags(commands[2].id), vec!["git"]);
+
+        history.delete_command("git status");
+        let count: i64 = history
+            .connection
+            .query_row("SELECT COUNT(*) FROM command_tags", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn templates_groups_commands_by_template() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "git commit -m 'one'", "s1", "/tmp", now - 50, 0);
+        add(&history, "cargo build", "s1", "/tmp", now - 40, 0);
+        add(&history, "git commit -m 'two'", "s1", "/tmp", now - 30, 0);
+        add(&history, "ls -la", "s1", "/tmp", now - 20, 0);
+        add(&history, "git commit -m 'three'", "s1", "/tmp", now - 10, 0);
+        add(&history, "cargo build --release", "s1", "/tmp", now - 5, 0);
+
+        let templates = history.templates(10);
+        let summary: Vec<(&str, i64)> = templates
+            .iter()
+            .map(|t| (t.cmd_tpl.as_str(), t.count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("git commit", 3), ("cargo build", 2), ("ls -la", 1)]
+        );
+        assert_eq!(templates[0].last_run, Some(now - 10));
+        assert_eq!(templates[0].sample_cmd, "git commit -m 'three'");
+    }
+
+    #[test]
+    fn commands_pages_in_either_direction() {
+        let history = in_memory_history();
+        let now = now();
+        for (i, cmd) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            add(&history, cmd, "s1", "/tmp", now + i as i64, 0);
+        }
+        let page = |num, offset, ascending| -> Vec<String> {
+            history
+                .commands(&None, num, offset, false, ascending)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        };
+
+        assert_eq!(page(2, 0, false), vec!["e", "d"]);
+        assert_eq!(page(2, 2, false), vec!["c", "b"]);
+        assert_eq!(page(2, 0, true), vec!["a", "b"]);
+        assert_eq!(page(2, 4, true), vec!["e"]);
+        assert_eq!(page(-1, 0, true).len(), 5);
+        assert!(page(-2, 0, true).is_empty());
+    }
+
+    #[test]
+    fn session_tail_is_in_chronological_order() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "cd project", "s1", "/tmp", now - 50, 0);
+        add(&history, "git pull", "s1", "/tmp", now - 40, 0);
+        add(&history, "top", "s2", "/tmp", now - 30, 0);
+        add(&history, "make", "s1", "/tmp", now - 20, 0);
+        add(&history, "make test", "s1", "/tmp", now - 10, 0);
+
+        let tail: Vec<String> = history
+            .session_tail("s1", 3)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(tail, vec!["git pull", "make", "make test"]);
+    }
+
+    #[test]
+    fn add_with_id_keeps_the_given_id() {
+        let history = in_memory_history();
+        let now = now();
+        let add_with_id = |id, cmd: &str, when| {
+            history.add_with_id(
+                id,
+                cmd,
+                Some("s1"),
+                Some("/src"),
+                &Some(when),
+                Some(0),
+                &None,
+                true,
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(add_with_id(10, "git pull", now - 30));
+        assert!(add_with_id(12, "make", now - 10));
+        assert!(add_with_id(11, "cargo build", now - 20));
+        assert!(!add_with_id(11, "cargo test", now - 5));
+
+        assert_eq!(history.command_by_id(11).unwrap().cmd, "cargo build");
+        assert!(history.command_by_id(13).is_none());
+        let before: Vec<String> = history
+            .preceding(12, 2)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(before, vec!["git pull", "cargo build"]);
+
+        add(&history, "ls -la", "s1", "/src", now, 0);
+        assert_eq!(history.last_command(&None).unwrap().id, 13);
+    }
+
+    #[test]
+    fn commands_without_a_dir_get_neutral_dir_credit() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make here", "s1", "/src", now - 40, 0);
+        add(&history, "make there", "s1", "/tmp", now - 30, 0);
+        add(&history, "make there", "s1", "/tmp", now - 20, 0);
+        add(&history, "make anywhere", "s1", "", now - 10, 0);
+        let stored = history.commands(&None, -1, 0, false, true);
+        assert_eq!(stored[3].dir, None);
+
+        let dir_factor = |dir: &str| {
+            history.build_cache_table(Some(dir), &None, None, None, Some(now));
+            let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+            let factor = |cmd: &str| {
+                matches
+                    .iter()
+                    .find(|c| c.cmd == cmd)
+                    .unwrap()
+                    .features
+                    .dir_factor
+            };
+            (
+                factor("make here"),
+                factor("make there"),
+                factor("make anywhere"),
+            )
+        };
+
+        // One of the three runs with a known directory was in /src, so a command with no known
+        // directory gets a third.
+        let (here, there, anywhere) = dir_factor("/src");
+        assert!((here - 1.0).abs() < 1e-9);
+        assert!(there.abs() < 1e-9);
+        assert!((anywhere - 1.0 / 3.0).abs() < 1e-9);
+
+        // An unknown current directory doesn't match commands with no directory either.
+        let (here, _, anywhere) = dir_factor("");
+        assert!(here.abs() < 1e-9);
+        assert!(anywhere.abs() < 1e-9);
+    }
+
+    #[test]
+    fn suggest_aliases_picks_long_frequent_commands() {
+        let history = in_memory_history();
+        let now = now();
+        for i in 0..4 {
+            add(
+                &history,
+                "docker compose up -d",
+                "s1",
+                "/src",
+                now - 100 + i,
+                0,
+            );
+            add(&history, "git status", "s1", "/src", now - 90 + i, 0);
+        }
+        for i in 0..3 {
+            add(
+                &history,
+                "docker compose up --detach",
+                "s1",
+                "/src",
+                now - 80 + i,
+                0,
+            );
+        }
+        add(
+            &history,
+            "kubectl get pods --all-namespaces",
+            "s1",
+            "/src",
+            now - 10,
+            0,
+        );
+
+        let suggestions = history.suggest_aliases(15, 3, 10);
+        let names: Vec<(&str, &str)> = suggestions
+            .iter()
+            .map(|s| (s.name.as_str(), s.command.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("dcud", "docker compose up -d"),
+                ("dcud2", "docker compose up --detach")
+            ]
+        );
+        assert_eq!(suggestions[0].occurrences, 4);
+        assert_eq!(
+            suggestions[0].shell_alias(),
+            "alias dcud='docker compose up -d'"
+        );
+
+        let quoted = AliasSuggestion {
+            name: "gcm".to_string(),
+            command: "git commit -m 'wip'".to_string(),
+            occurrences: 5,
+        };
+        assert_eq!(
+            quoted.shell_alias(),
+            "alias gcm='git commit -m '\\''wip'\\'''"
+        );
+    }
+
+    #[test]
+    fn commands_touching_matches_whole_file_names() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "vim config.yaml", "s1", "/src", now - 50, 0);
+        add(
+            &history,
+            "cp myconfig.yaml.bak /tmp",
+            "s1",
+            "/src",
+            now - 40,
+            0,
+        );
+        add(
+            &history,
+            "kubectl apply -f=./deploy/config.yaml",
+            "s1",
+            "/src",
+            now - 30,
+            0,
+        );
+        add(
+            &history,
+            "cat \"config.yaml\" | grep port",
+            "s1",
+            "/src",
+            now - 20,
+            0,
+        );
+        add(&history, "vim config.yaml.orig", "s1", "/src", now - 10, 0);
+
+        let cmds = |num| -> Vec<String> {
+            history
+                .commands_touching("config.yaml", num)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect()
+        };
+        assert_eq!(
+            cmds(10),
+            vec![
+                "cat \"config.yaml\" | grep port",
+                "kubectl apply -f=./deploy/config.yaml",
+                "vim config.yaml"
+            ]
+        );
+        assert_eq!(cmds(1).len(), 1);
+    }
+
+    #[test]
+    fn preceding_prefers_the_same_session() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "cd project", "s1", "/tmp", now - 50, 0);
+        add(&history, "vim notes", "s2", "/tmp", now - 40, 0);
+        add(&history, "git pull", "s1", "/tmp", now - 30, 0);
+        add(&history, "top", "s2", "/tmp", now - 20, 0);
+        add(&history, "make", "s1", "/tmp", now - 10, 1);
+        let failed_id = history.last_command(&Some("s1".to_string())).unwrap().id;
+
+        let cmds = |k| -> Vec<String> {
+            history
+                .preceding(failed_id, k)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        };
+
+        assert_eq!(cmds(1), vec!["git pull"]);
+        assert_eq!(cmds(2), vec!["cd project", "git pull"]);
+        assert_eq!(cmds(3), vec!["cd project", "git pull", "top"]);
+        assert_eq!(cmds(10).len(), 4);
+        assert!(history.preceding(1, 5).is_empty());
+    }
+
+    #[test]
+    fn recently_regressed_finds_commands_that_started_failing() {
+        let history = in_memory_history();
+        let now = now();
+        let day = 24 * 60 * 60;
+        add(&history, "./deploy.sh", "s1", "/src", now - 7 * day, 0);
+        add(&history, "make", "s1", "/src", now - 7 * day, 0);
+        add(&history, "./flaky.sh", "s1", "/src", now - 7 * day, 2);
+        add(&history, "./deploy.sh", "s1", "/src", now - 60, 1);
+        add(&history, "make", "s1", "/src", now - 50, 0);
+        add(&history, "./flaky.sh", "s1", "/src", now - 40, 2);
+        add(&history, "make", "s1", "/src", now - 30, 130);
+
+        let regressed = history.recently_regressed(day, 10);
+        assert_eq!(regressed.len(), 1);
+        assert_eq!(regressed[0].cmd, "./deploy.sh");
+        assert_eq!(regressed[0].exit_code, Some(1));
+    }
+
+    #[test]
+    fn fix_for_suggests_what_succeeded_after_a_failure() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "cargo buil", "s1", "/src", now - 100, 101);
+        add(&history, "cargo biuld", "s1", "/src", now - 90, 101);
+        add(&history, "cargo build", "s1", "/src", now - 80, 0);
+        add(&history, "cargo buil", "s2", "/src", now - 50, 101);
+        add(&history, "ls", "s3", "/src", now - 45, 0);
+        add(&history, "cargo build", "s2", "/src", now - 40, 0);
+        add(&history, "cargo buil", "s1", "/src", now - 30, 101);
+        add(&history, "cargo test", "s1", "/src", now - 20, 0);
+
+        let fixes: Vec<String> = history
+            .fix_for("cargo buil", 10)
+            .into_iter()
+            .map(|c| c.cmd)
+            .collect();
+        assert_eq!(fixes, vec!["cargo build", "cargo test"]);
+        assert!(history.fix_for("cargo build", 10).is_empty());
+    }
+
+    #[test]
+    fn stale_favorites_finds_frequent_commands_not_run_lately() {
+        let history = in_memory_history();
+        let now = now();
+        let year = 365 * 24 * 60 * 60;
+        for i in 0..5 {
+            add(&history, "ant build", "s1", "/src", now - year - i, 0);
+            add(&history, "cargo build", "s1", "/src", now - i, 0);
+        }
+        add(&history, "mvn install", "s1", "/src", now - year, 0);
+
+        let stale = history.stale_favorites(3, 30 * 24 * 60 * 60, 10);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].cmd, "ant build");
+        assert!(history.stale_favorites(6, 30 * 24 * 60 * 60, 10).is_empty());
+    }
+
+    #[test]
+    fn directories_lists_each_directory_by_recent_activity() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 50, 0);
+        add(&history, "ls -la", "s1", "/docs", now - 40, 0);
+        add(&history, "make test", "s1", "/src", now - 30, 0);
+        add(&history, "ls -la", "s1", "/tmp", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+
+        assert_eq!(
+            history.directories(10),
+            vec![
+                ("/src".to_string(), 3, now - 10),
+                ("/tmp".to_string(), 1, now - 20),
+                ("/docs".to_string(), 1, now - 40),
+            ]
+        );
+        assert_eq!(history.directories(1).len(), 1);
+    }
+
+    #[test]
+    fn cooccurrence_counts_shared_sessions() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "a", "s1", "/tmp", now - 60, 0);
+        add(&history, "b", "s1", "/tmp", now - 50, 0);
+        add(&history, "c", "s1", "/tmp", now - 40, 0);
+        add(&history, "a", "s2", "/tmp", now - 30, 0);
+        add(&history, "c", "s2", "/tmp", now - 20, 0);
+        add(&history, "d", "s3", "/tmp", now - 10, 0);
+        add(&history, "a", "IMPORTED", "/tmp", now - 5, 0);
+        add(&history, "d", "IMPORTED", "/tmp", now - 4, 0);
+        add(&history, "a", "UNKNOWN", "/tmp", now - 3, 0);
+        add(&history, "d", "UNKNOWN", "/tmp", now - 2, 0);
+
+        assert_eq!(
+            history.cooccurrence("a", 10),
+            vec![("c".to_string(), 2), ("b".to_string(), 1)]
+        );
+        assert_eq!(history.cooccurrence("a", 1), vec![("c".to_string(), 2)]);
+        assert!(history.cooccurrence("d", 10).is_empty());
+    }
+
+    #[test]
+    fn sync_history_imports_only_new_entries() {
+        let history = in_memory_history();
+        let path = env::temp_dir().join(format!("mcfly-sync-bash-{}", process::id()));
+        fs::write(&path, "git status\nmake\n").unwrap();
+        assert_eq!(history.import(&path, HistoryFormat::Bash, "bash"), 2);
+        assert_eq!(history.sync_history(&path, HistoryFormat::Bash, "bash"), 0);
+
+        fs::write(&path, "git status\nmake\ncargo build\ncargo test\n").unwrap();
+        assert_eq!(history.sync_history(&path, HistoryFormat::Bash, "bash"), 2);
+        // The shell trimmed the oldest entries while adding one.
+        fs::write(&path, "cargo build\ncargo test\nmake test\n").unwrap();
+        assert_eq!(history.sync_history(&path, HistoryFormat::Bash, "bash"), 1);
+
+        let cmds: Vec<String> = history
+            .commands(&None, -1, 0, false, true)
+            .into_iter()
+            .map(|c| c.cmd)
+            .collect();
+        assert_eq!(
+            cmds,
+            vec![
+                "git status",
+                "make",
+                "cargo build",
+                "cargo test",
+                "make test"
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn import_records_the_source_of_each_command() {
+        let history = in_memory_history();
+        let bash_path = env::temp_dir().join(format!("mcfly-import-bash-{}", process::id()));
+        let zsh_path = env::temp_dir().join(format!("mcfly-import-zsh-{}", process::id()));
+        fs::write(&bash_path, "git status\nmake\n").unwrap();
+        fs::write(&zsh_path, ": 1577836800:0;cargo build\n").unwrap();
+
+        assert_eq!(history.import(&bash_path, HistoryFormat::Bash, "bash"), 2);
+        assert_eq!(
+            history.import(
+                &zsh_path,
+                HistoryFormat::Zsh {
+                    extended_history: true
+                },
+                "zsh"
+            ),
+            1
+        );
+        add(&history, "ls -la", "s1", "/tmp", now(), 0);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        let sources: Vec<(&str, Option<&str>)> = commands
+            .iter()
+            .map(|c| (c.cmd.as_str(), c.source.as_deref()))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                ("git status", Some("bash")),
+                ("make", Some("bash")),
+                ("cargo build", Some("zsh")),
+                ("ls -la", None),
+            ]
+        );
+
+        fs::remove_file(&bash_path).unwrap();
+        fs::remove_file(&zsh_path).unwrap();
+    }
+
+    #[test]
+    fn import_follows_the_same_settings_as_add() {
+        let mut history = in_memory_history();
+        history.ignored_commands = vec!["ls".to_string()];
+        history.template_placeholders = true;
+        history.skip_comments = true;
+        let path = env::temp_dir().join(format!("mcfly-import-settings-{}", process::id()));
+        fs::write(&path, "ls\n# a note to self\nssh web42\n").unwrap();
+
+        assert_eq!(history.import(&path, HistoryFormat::Bash, "bash"), 1);
+        add(&history, "ssh web43", "s1", "/tmp", now(), 0);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].cmd, "ssh web42");
+        assert_eq!(commands[0].cmd_tpl, commands[1].cmd_tpl);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rebuild_swaps_in_a_fresh_database() {
+        let path = env::temp_dir().join(format!("mcfly-rebuild-test-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        let mut history = History::from_db_path(path.to_owned(), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+
+        let now = now();
+        add(&history, "git commit -m 'one'", "s1", "/src", now - 30, 0);
+        add(&history, "cargo build", "s1", "/src", now - 20, 0);
+        add(&history, "ls -la", "s2", "/tmp", now - 10, 0);
+        history
+            .connection
+            .execute("UPDATE commands SET cmd_tpl = ''", NO_PARAMS)
+            .unwrap();
+
+        history.rebuild().unwrap();
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        let templates: Vec<&str> = commands.iter().map(|c| c.cmd_tpl.as_str()).collect();
+        assert_eq!(templates, vec!["git commit", "cargo build", "ls -la"]);
+        assert_eq!(commands[2].session_id, "s2");
+        assert!(!path.with_extension("db.rebuild").exists());
+
+        add(&history, "make", "s1", "/src", now, 0);
+        let reopened = History::from_db_path(path.to_owned(), &DbOptions::default());
+        assert_eq!(reopened.commands(&None, -1, 0, false, true).len(), 4);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sync_history_picks_up_where_it_left_off_after_a_rebuild() {
+        let db_path = env::temp_dir().join(format!("mcfly-rebuild-sync-{}.db", process::id()));
+        let path = env::temp_dir().join(format!("mcfly-rebuild-sync-bash-{}", process::id()));
+        let _ = fs::remove_file(&db_path);
+        let mut history = History::from_db_path(db_path.to_owned(), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        fs::write(&path, "git status\nmake\n").unwrap();
+        assert_eq!(history.import(&path, HistoryFormat::Bash, "bash"), 2);
+
+        history.rebuild().unwrap();
+        fs::write(&path, "git status\nmake\ncargo build\n").unwrap();
+        assert_eq!(history.sync_history(&path, HistoryFormat::Bash, "bash"), 1);
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 3);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn rebuild_keeps_overlays_attached() {
+        let path = env::temp_dir().join(format!("mcfly-rebuild-overlay-{}.db", process::id()));
+        let overlay = env::temp_dir().join(format!("mcfly-rebuild-recipes-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&overlay);
+        let now = now();
+        {
+            let recipes = History::from_db_path(overlay.to_owned(), &DbOptions::default());
+            History::create_tables(&recipes.connection);
+            schema::first_time_setup(&recipes.connection);
+            add(&recipes, "terraform plan", "s1", "/ops", now - 20, 0);
+        }
+        let mut history = History::from_db_path(path.to_owned(), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        add(&history, "terraform apply", "s1", "/src", now - 10, 0);
+        history.attach_overlay("recipes", &overlay);
+
+        history.rebuild().unwrap();
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let matches = history.find_matches("terraform", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 2);
+        assert!(matches
+            .iter()
+            .any(|c| c.source.as_deref() == Some("recipes")));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&overlay).unwrap();
+    }
+
+    #[test]
+    fn rebuild_needs_a_database_file() {
+        let mut history = in_memory_history();
+        assert!(history.rebuild().is_err());
+    }
+
+    #[test]
+    fn db_options_set_the_locking_mode_and_vfs() {
+        let locking_mode = |history: &History| -> String {
+            history
+                .connection
+                .query_row("PRAGMA locking_mode", NO_PARAMS, |row| row.get(0))
+                .unwrap()
+        };
+        let path = env::temp_dir().join(format!("mcfly-db-options-test-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let history = History::from_db_path(path.to_owned(), &DbOptions::default());
+        assert_eq!(locking_mode(&history), "normal");
+        drop(history);
+
+        let options = DbOptions {
+            exclusive_locking: true,
+            vfs: Some("unix-dotfile".to_string()),
+        };
+        let history = History::from_db_path(path.to_owned(), &options);
+        assert_eq!(locking_mode(&history), "exclusive");
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        add(&history, "make", "s1", "/src", now(), 0);
+        assert_eq!(history.commands(&None, -1, 0, false, true).len(), 1);
+        drop(history);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn evaluate_reports_recall_of_held_out_commands() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "ls -la", "s1", "/src", now - 1000, 0);
+        for i in 0..10 {
+            add(&history, "cargo build", "s1", "/src", now - 900 + i * 60, 0);
+        }
+        add(&history, "brand new command", "s1", "/src", now, 0);
+
+        let report = history.evaluate(3, 1);
+        assert_eq!(report.evaluated, 3);
+        assert!((report.recall - 2.0 / 3.0).abs() < 1e-9);
+        assert!((report.mean_reciprocal_rank - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(in_memory_history().evaluate(3, 1).evaluated, 0);
+    }
+
+    #[test]
+    fn evaluate_only_uses_the_context_from_before_each_command() {
+        let mut history = in_memory_history();
+        history.same_program_weight = 1.0;
+        let now = now();
+        for i in 0..3 {
+            add(&history, "make test", "s1", "/src", now - 600 + i * 100, 0);
+        }
+        for i in 0..3 {
+            add(&history, "git status", "s1", "/src", now - 300 + i * 100, 0);
+        }
+        add(&history, "make test", "s1", "/src", now, 0);
+
+        // Just before "make test" ran, the last command was "git status", so the same-program
+        // bonus goes to "git status". Had "make test" leaked into its own context, it would have
+        // had the bonus and come first.
+        let report = history.evaluate(1, 1);
+        assert_eq!(report.evaluated, 1);
+        assert_eq!(report.recall, 0.0);
+        assert!((report.mean_reciprocal_rank - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn duplicate_report_counts_redundant_rows() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 50, 0);
+        add(&history, "make", "s1", "/src", now - 40, 0);
+        add(&history, "make", "s1", "/src", now - 30, 0);
+        add(&history, "make", "s1", "/docs", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+        add(&history, "git status", "s2", "/src", now - 5, 0);
+
+        let report = history.duplicate_report();
+        assert_eq!(report.groups.len(), 2);
+        assert_eq!(report.groups[0].cmd, "make");
+        assert_eq!(report.groups[0].count, 3);
+        assert_eq!(report.redundant_rows, 3);
+        assert_eq!(report.reclaimable_bytes, 2 * 8 + 14);
+    }
+
+    #[test]
+    fn warm_cache_builds_the_cache_without_searching() {
+        let history = in_memory_history();
+        let cached = || -> i64 {
+            history
+                .connection
+                .query_row(
+                    "SELECT COUNT(*) FROM contextual_commands",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        history.warm_cache(Some("/tmp"), &Some("s1".to_string()));
+        assert_eq!(cached(), 0);
+
+        let now = now();
+        add(&history, "make", "s1", "/tmp", now - 30, 0);
+        add(&history, "make", "s1", "/tmp", now - 20, 0);
+        add(&history, "cargo test", "s2", "/src", now - 10, 0);
+        history.warm_cache(Some("/tmp"), &Some("s1".to_string()));
+        assert_eq!(cached(), 2);
+    }
+
+    #[test]
+    fn build_cache_table_can_skip_rarely_run_commands() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "make tset", "s1", "/tmp", now - 40, 1);
+        for i in 0..3 {
+            add(&history, "make test", "s1", "/tmp", now - 30 + i, 0);
+        }
+
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        assert_eq!(
+            history
+                .find_matches("make", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
+
+        history.min_occurrences = 2;
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].cmd, "make test");
+    }
+
+    #[test]
+    fn palette_for_puts_commands_from_the_directory_first() {
+        let history = in_memory_history();
+        let now = now();
+        for i in 0..10 {
+            add(&history, "git status", "s1", "/home", now - 100 + i, 0);
+        }
+        add(&history, "cargo build", "s1", "/src", now - 50, 0);
+        add(&history, "cargo test", "s1", "/src", now - 40, 0);
+        // Imported, so its directory is unknown: it wasn't run in /src as far as anyone knows.
+        add(&history, "make", "s1", "", now - 30, 0);
+
+        let palette: Vec<String> = history
+            .palette_for("/src", 10)
+            .into_iter()
+            .map(|c| c.cmd)
+            .collect();
+        assert_eq!(palette.len(), 4);
+        assert!(palette[..2].contains(&"cargo build".to_string()));
+        assert!(palette[..2].contains(&"cargo test".to_string()));
+        assert!(palette[2..].contains(&"git status".to_string()));
+        assert!(palette[2..].contains(&"make".to_string()));
+
+        assert_eq!(history.palette_for("/home", 1)[0].cmd, "git status");
+    }
+
+    #[test]
+    fn timings_are_recorded_only_when_enabled() {
+        let mut history = in_memory_history();
+        add(&history, "make", "s1", "/tmp", now() - 10, 0);
+
+        history.build_cache_table(Some("/tmp"), &None, None, None, None);
+        history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert!(history.timings().build_cache_table.is_none());
+        assert!(history.timings().find_matches.is_none());
+
+        history.timing = true;
+        history.build_cache_table(Some("/tmp"), &None, None, None, None);
+        assert!(history.timings().build_cache_table.is_some());
+        assert!(history.timings().find_matches.is_none());
+        history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert!(history.timings().find_matches.is_some());
+    }
+
+    #[test]
+    fn build_cache_table_handles_a_long_lookback() {
+        let mut history = in_memory_history();
+        let now = now();
+        for i in 0..20 {
+            add(
+                &history,
+                &format!("make target{}", i),
+                "s1",
+                "/tmp",
+                now - 100 + i,
+                0,
+            );
+        }
+
+        history.lookback = 2000;
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+        let matches = history.find_matches("make", -1, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 20);
+        assert!(matches.iter().all(|c| c.features.overlap_factor >= 0.0));
+    }
+
+    #[test]
+    fn export_features_writes_a_row_per_command() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "git status", "s1", "/src", now - 30, 0);
+        add(&history, "echo \"a, b\"", "s1", "/src", now - 20, 0);
+        history.record_selected_from_ui("git status", "s1", "/tmp");
+        add(&history, "git status", "s1", "/tmp", now - 10, 0);
+
+        let mut csv = Vec::new();
+        history.export_features("/src", &mut csv).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        let header = reader.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+
+        let cached: i64 = history
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM contextual_commands",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rows.len() as i64, cached);
+        assert_eq!(&header[0], "cmd");
+        assert_eq!(&header[2], "age_factor");
+        assert_eq!(&header[12], "selected");
+        assert!(rows
+            .iter()
+            .any(|row| &row[0] == "echo \"a, b\"" && &row[12] == "0"));
+        assert!(rows
+            .iter()
+            .any(|row| &row[0] == "git status" && &row[12] == "1"));
+    }
+
+    #[test]
+    fn same_program_commands_rank_higher_after_that_program() {
+        let mut history = in_memory_history();
+        history.same_program_weight = 0.05;
+        let now = now();
+        add(&history, "git log", "s1", "/src", now - 30, 0);
+        add(&history, "cat log", "s1", "/src", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+        let rank = |history: &History, cmd: &str| {
+            history
+                .find_matches("log", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .find(|c| c.cmd == cmd)
+                .unwrap()
+                .rank
+        };
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let git_log = rank(&history, "git log");
+        let cat_log = rank(&history, "cat log");
+        assert_eq!(
+            history.find_matches("log", 10, false, false, MatchMode::Substring)[0].cmd,
+            "git log"
+        );
+
+        history.same_program_weight = 0.0;
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        assert!(git_log > rank(&history, "git log"));
+        assert_eq!(cat_log, rank(&history, "cat log"));
+    }
+
+    #[test]
+    fn random_useful_picks_frequent_commands_not_run_lately() {
+        let history = in_memory_history();
+        let now = now();
+        let day = 24 * 60 * 60;
+        for i in 0..10 {
+            add(
+                &history,
+                "docker system prune",
+                "s1",
+                "/src",
+                now - 30 * day - i,
+                0,
+            );
+        }
+        for i in 0..3 {
+            add(
+                &history,
+                "tar xzf a.tgz",
+                "s1",
+                "/src",
+                now - 2 * day - i,
+                0,
+            );
+        }
+        for i in 0..5 {
+            add(&history, "make broken", "s1", "/src", now - 30 * day - i, 1);
+            add(&history, "cargo test", "s1", "/src", now - 60 - i, 0);
+        }
+        add(&history, "rare", "s1", "/src", now - 30 * day, 0);
+
+        let picks = |seed: u64| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..100)
+                .map(|_| history.random_useful_with(&mut rng).unwrap().cmd)
+                .collect()
+        };
+        let first = picks(42);
+        assert_eq!(first, picks(42));
+        let count = |cmd: &str| first.iter().filter(|pick| *pick == cmd).count();
+        assert_eq!(count("docker system prune") + count("tar xzf a.tgz"), 100);
+        assert!(count("docker system prune") > count("tar xzf a.tgz"));
+
+        assert!(in_memory_history().random_useful().is_none());
+    }
+
+    #[test]
+    fn arg_count_treats_quoted_arguments_as_one() {
+        assert_eq!(History::arg_count(""), 0);
+        assert_eq!(History::arg_count("ls"), 0);
+        assert_eq!(History::arg_count("  git   status "), 1);
+        assert_eq!(History::arg_count("git commit -m 'fix the build'"), 3);
+        assert_eq!(History::arg_count("echo \"a 'b' c\" d"), 2);
+        assert_eq!(History::arg_count("echo 'it'\\''s' x"), 2);
+        assert_eq!(History::arg_count("touch my\\ file.txt"), 1);
+        assert_eq!(History::arg_count("grep -r \"\" ."), 3);
+    }
+
+    #[test]
+    fn arg_count_weight_favors_matches_of_the_same_shape() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "git status", "s1", "/src", now - 40, 0);
+        for i in 0..3 {
+            add(
+                &history,
+                "git status --short --branch",
+                "s1",
+                "/src",
+                now - 30 + i,
+                0,
+            );
+        }
+        let rank = |history: &History, cmd: &str| {
+            history
+                .find_matches("git s", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .find(|c| c.cmd == cmd)
+                .unwrap()
+                .rank
+        };
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let short = rank(&history, "git status");
+        let long = rank(&history, "git status --short --branch");
+        assert!(long > short);
+
+        history.arg_count_weight = 2.0;
+        assert!((rank(&history, "git status") - short - 2.0).abs() < 1e-9);
+        assert!((rank(&history, "git status --short --branch") - long - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(
+            history.find_matches("git s", 10, false, false, MatchMode::Substring)[0].cmd,
+            "git status"
+        );
+    }
+
+    #[test]
+    fn arg_count_is_stored_when_adding() {
+        let history = in_memory_history();
+        add(&history, "cp 'a b' c", "s1", "/src", now(), 0);
+        let arg_count: i64 = history
+            .connection
+            .query_row("SELECT arg_count FROM commands", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(arg_count, 2);
+    }
+
+    #[test]
+    fn error_snippets_are_stored_truncated_for_failed_commands() {
+        let history = in_memory_history();
+        let stderr = format!("\nerror: {}\nsecond line", "x".repeat(300));
+        for (cmd, exit_code) in &[("make", 2), ("make test", 0)] {
+            history.add(
+                cmd,
+                Some("s1"),
+                Some("/src"),
+                &Some(now() - 10),
+                Some(*exit_code),
+                &None,
+                true,
+                None,
+                Some(&stderr),
+                None,
+            );
+        }
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        let snippet = commands[0].error_snippet.as_ref().unwrap();
+        assert_eq!(snippet.chars().count(), 200);
+        assert!(snippet.starts_with("error: xxx"));
+        assert_eq!(commands[1].error_snippet, None);
+
+        history.build_cache_table(Some("/src"), &None, None, None, None);
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        let failed = matches.iter().find(|c| c.cmd == "make").unwrap();
+        assert_eq!(failed.error_snippet.as_ref(), Some(snippet));
+    }
+
+    #[test]
+    fn growth_stats_count_commands_in_the_window() {
+        let history = in_memory_history();
+        assert_eq!(history.growth_stats(60), GrowthStats::default());
+
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 7200, 0);
+        add(&history, "make test", "s1", "/src", now - 3700, 0);
+        add(&history, "git status", "s1", "/src", now - 600, 0);
+        add(&history, "git diff", "s1", "/src", now - 10, 0);
+
+        assert_eq!(
+            history.growth_stats(3600),
+            GrowthStats { added: 2, total: 4 }
+        );
+        assert_eq!(history.growth_stats(86400).added, 4);
+    }
+
+    #[test]
+    fn canonical_command_is_the_most_common_for_a_template() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "git commit -m 'wip'", "s1", "/src", now - 50, 0);
+        add(&history, "git commit -m 'fix'", "s1", "/src", now - 40, 0);
+        add(&history, "git commit -m 'wip'", "s1", "/src", now - 30, 0);
+        add(&history, "git commit -m 'docs'", "s1", "/src", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+
+        let canonical = history.canonical_command("git commit").unwrap();
+        assert_eq!(canonical.cmd, "git commit -m 'wip'");
+        assert_eq!(canonical.when_run, Some(now - 30));
+
+        add(&history, "git commit -m 'docs'", "s1", "/src", now - 5, 0);
+        add(&history, "git commit -m 'docs'", "s1", "/src", now - 4, 0);
+        assert_eq!(
+            history.canonical_command("git commit").unwrap().cmd,
+            "git commit -m 'docs'"
+        );
+        assert!(history.canonical_command("cargo build").is_none());
+    }
+
+    #[test]
+    fn likely_exit_reports_how_a_command_usually_ends() {
+        let history = in_memory_history();
+        let now = now();
+        for (offset, exit) in [0, 2, 2, 1, 2].iter().enumerate() {
+            add(
+                &history,
+                "make deploy",
+                "s1",
+                "/src",
+                now - 50 + offset as i64,
+                *exit,
+            );
+        }
+        add(&history, "make", "s1", "/src", now - 10, 0);
+
+        let stats = history.likely_exit("make deploy").unwrap();
+        assert_eq!(stats.runs, 5);
+        assert!((stats.success_rate - 0.2).abs() < 1e-9);
+        assert_eq!(stats.most_common_exit_code, 2);
+        assert_eq!(
+            history.likely_exit("make"),
+            Some(ExitStats {
+                runs: 1,
+                success_rate: 1.0,
+                most_common_exit_code: 0,
+            })
+        );
+        assert_eq!(history.likely_exit("make dep"), None);
+    }
+
+    #[test]
+    fn since_returns_only_newer_commands_in_order() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 30, 0);
+        add(&history, "make test", "s1", "/src", now - 20, 0);
+        let last_id = history.since(0).last().unwrap().id;
+
+        add(&history, "git add .", "s2", "/src", now - 10, 0);
+        add(&history, "git commit", "s1", "/src", now, 0);
+        let cmds: Vec<String> = history.since(last_id).into_iter().map(|c| c.cmd).collect();
+        assert_eq!(cmds, vec!["git add .", "git commit"]);
+
+        let newest = history.since(last_id).last().unwrap().id;
+        assert!(history.since(newest).is_empty());
+    }
+
+    #[test]
+    fn add_records_where_cd_commands_went_as_old_dir() {
+        let history = in_memory_history();
+        let home = env::var("HOME").unwrap();
+        for (cmd, old_dir) in &[("cd ~/foo", "/wrong"), ("cd -", "/prev"), ("ls", "/prev")] {
+            history.add(
+                cmd,
+                Some("s1"),
+                Some("/src"),
+                &Some(now()),
+                Some(0),
+                &Some(old_dir.to_string()),
+                true,
+                None,
+                None,
+                None,
+            );
+        }
+
+        let dirs: Vec<(Option<String>, Option<String>)> = history
+            .connection
+            .prepare("SELECT dir, old_dir FROM commands ORDER BY id")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| (row.get(0), row.get(1)))
+            .unwrap()
+            .map(|dirs| dirs.unwrap())
+            .collect();
+        let home_foo = PathBuf::from(home)
+            .join("foo")
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(dirs[0], (Some("/src".to_string()), Some(home_foo)));
+        assert_eq!(
+            dirs[1],
+            (Some("/src".to_string()), Some("/prev".to_string()))
+        );
+        assert_eq!(
+            dirs[2],
+            (Some("/src".to_string()), Some("/prev".to_string()))
+        );
+    }
+
+    #[test]
+    fn prefix_mode_only_matches_commands_starting_with_the_search() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "sudo git pull", "s1", "/src", now - 20, 0);
+        add(&history, "git status", "s1", "/src", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let cmds = |match_mode| -> Vec<String> {
+            let mut cmds: Vec<String> = history
+                .find_matches("git", 10, false, false, match_mode)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect();
+            cmds.sort();
+            cmds
+        };
+        assert_eq!(
+            cmds(MatchMode::Substring),
+            vec!["git status", "sudo git pull"]
+        );
+        assert_eq!(cmds(MatchMode::Prefix), vec!["git status"]);
+        assert!(history
+            .find_matches("gt", 10, true, false, MatchMode::Prefix)
+            .iter()
+            .all(|c| c.cmd.starts_with('g')));
+        assert!(history
+            .find_matches("it", 10, false, false, MatchMode::Prefix)
+            .is_empty());
+    }
+
+    #[test]
+    fn redistribute_timestamps_spreads_out_imported_commands() {
+        let history = in_memory_history();
+        let now = now();
+        let imported_at = now - 1000;
+        for i in 0..30 {
+            add(
+                &history,
+                &format!("echo {}", i),
+                "IMPORTED",
+                "/",
+                imported_at,
+                0,
+            );
+        }
+        add(&history, "make", "s1", "/src", imported_at, 0);
+        add(&history, "make test", "s1", "/src", now, 0);
+
+        assert_eq!(history.redistribute_timestamps(), 30);
+
+        let commands = history.commands(&None, -1, 0, false, true);
+        let times: Vec<i64> = commands.iter().map(|c| c.when_run.unwrap()).collect();
+        assert!(times[..30].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(times[0] >= imported_at - 365 * 24 * 60 * 60);
+        assert_eq!(times[29], imported_at);
+        assert_eq!(times[30], imported_at);
+        assert_eq!(times[31], now);
+
+        assert_eq!(history.redistribute_timestamps(), 0);
+    }
+
+    #[test]
+    fn redistribute_timestamps_leaves_recorded_bursts_alone() {
+        let history = in_memory_history();
+        let burst_at = now() - 1000;
+        for i in 0..30 {
+            add(&history, &format!("echo {}", i), "s1", "/src", burst_at, 0);
+        }
+
+        assert_eq!(history.redistribute_timestamps(), 0);
+        assert!(history
+            .commands(&None, -1, 0, false, true)
+            .iter()
+            .all(|c| c.when_run == Some(burst_at)));
+    }
+
+    #[test]
+    fn confidences_sum_to_one_and_follow_rank() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "cargo build", "s1", "/src", now - 40, 1);
+        add(&history, "cargo test", "s1", "/src", now - 30, 0);
+        add(&history, "cargo test", "s1", "/src", now - 20, 0);
+        add(&history, "cargo fmt", "s1", "/tmp", now - 10, 0);
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+
+        let matches =
+            history.find_matches_with_confidence("cargo", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 3);
+        let total: f64 = matches.iter().map(|(_, confidence)| confidence).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(matches
+            .windows(2)
+            .all(|pair| pair[0].1 >= pair[1].1 && pair[0].0.rank >= pair[1].0.rank));
+        assert!(matches[0].1 > matches[2].1);
+
+        let top =
+            history.find_matches_with_confidence("cargo", 1, false, false, MatchMode::Substring);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0.cmd, matches[0].0.cmd);
+        assert!((top[0].1 - matches[0].1).abs() < 1e-9);
+        assert!(history
+            .find_matches_with_confidence("make", 10, false, false, MatchMode::Substring)
+            .is_empty());
+    }
+
+    #[test]
+    fn template_command_replaces_the_built_in_templates() {
+        let mut history = in_memory_history();
+        let command = "git commit -m 'fix the build'";
+        assert_eq!(history.template(command), "git commit");
+
+        history.template_command = Some("cat".to_string());
+        assert_eq!(history.template(command), command);
+        add(&history, command, "s1", "/src", now(), 0);
+        assert_eq!(
+            history.commands(&None, 1, 0, false, false)[0].cmd_tpl,
+            command
+        );
+
+        history.template_command = Some("exit 1".to_string());
+        assert_eq!(history.template(command), "git commit");
+        history.template_command = Some("sleep 5".to_string());
+        assert_eq!(history.template(command), "git commit");
+    }
+
+    #[test]
+    fn simplify_command_matches_the_stored_template() {
+        let history = in_memory_history();
+        let command = "kubectl logs pod-1234 --tail 50";
+        add(&history, command, "s1", "/src", now(), 0);
+        assert_eq!(
+            simplify_command(command),
+            history.commands(&None, 1, 0, false, false)[0].cmd_tpl
+        );
+    }
+
+    #[test]
+    fn best_match_returns_the_top_match() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "cargo build", "s1", "/src", now - 30, 0);
+        add(&history, "cargo test", "s1", "/src", now - 20, 0);
+        add(&history, "cargo test", "s1", "/src", now - 10, 0);
+
+        let best = history.best_match("cargo", "/src").unwrap();
+        history.build_cache_table(Some("/src"), &None, None, None, None);
+        let top = history.find_matches("cargo", 1, false, false, MatchMode::Substring);
+        assert_eq!(top.len(), 1);
+        assert_eq!(best.cmd, top[0].cmd);
+        assert_eq!(best.rank, top[0].rank);
+
+        assert!(history.best_match("make", "/src").is_none());
+    }
+
+    #[test]
+    fn dominant_factor_names_the_factor_behind_the_top_match() {
+        let history = in_memory_history();
+        let now = now();
+        // A rare, short command whose only run was in this directory, so neither its occurrences
+        // nor its length speak for it.
+        for i in 0..10 {
+            add(
+                &history,
+                "ls -la ~/projects",
+                "s1",
+                "/home",
+                now - 100 + i,
+                0,
+            );
+        }
+        add(&history, "make", "s1", "/src", now - 10, 0);
+
+        let (factor, contribution) = history.dominant_factor("make", "/src").unwrap();
+        assert_eq!(factor, "dir_factor");
+        assert!(contribution > 0.0);
+
+        assert!(history.dominant_factor("cargo", "/src").is_none());
+    }
+
+    #[test]
+    fn snapshot_diff_reports_added_and_removed_rows() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "make", "s1", "/src", now - 20, 0);
+        add(&history, "make", "s1", "/src", now - 20, 0);
+        let before = history.snapshot();
+        assert_eq!(before.count, 2);
+
+        add(&history, "make test", "s1", "/src", now - 10, 0);
+        let after = history.snapshot();
+        assert_eq!(after.count, 3);
+        assert_eq!(
+            before.diff(&after),
+            SnapshotDiff {
+                added: 1,
+                removed: 0,
+            }
+        );
+        assert_eq!(
+            after.diff(&before),
+            SnapshotDiff {
+                added: 0,
+                removed: 1,
+            }
+        );
+
+        history.delete_command("make");
+        assert_eq!(
+            after.diff(&history.snapshot()),
+            SnapshotDiff {
+                added: 0,
+                removed: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn modifier_commands_rank_higher_when_weighted() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(&history, "vim main.rs", "s1", "/src", now - 30, 0);
+        add(&history, "ls", "s1", "/src", now - 20, 0);
+        add(&history, "ls", "s1", "/src", now - 10, 0);
+        let rank = |history: &History, cmd: &str| {
+            history
+                .find_matches("", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .find(|c| c.cmd == cmd)
+                .unwrap()
+                .rank
+        };
+
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        let unweighted = rank(&history, "vim main.rs");
+        let ls = rank(&history, "ls");
+        assert!(unweighted < ls);
+
+        history.modifier_weight = 2.0;
+        history.build_cache_table(Some("/src"), &None, None, None, Some(now));
+        assert!((rank(&history, "vim main.rs") - unweighted - 2.0).abs() < 1e-9);
+        assert_eq!(rank(&history, "ls"), ls);
+        assert!(rank(&history, "vim main.rs") > rank(&history, "ls"));
+    }
+
+    #[test]
+    fn surprise_is_highest_for_commands_never_run() {
+        let history = in_memory_history();
+        let now = now();
+        for i in 0..5 {
+            add(&history, "cargo test", "s1", "/src", now - 60 + i, 0);
+        }
+        add(&history, "rm -rf target", "s1", "/src", now - 30, 0);
+        add(&history, "git status", "s1", "/tmp", now - 20, 0);
+
+        let frequent = history.surprise("cargo test", "/src");
+        let rare = history.surprise("rm -rf target", "/src");
+        let unseen = history.surprise("rm -rf /", "/src");
+        assert!(frequent > 0.0);
+        assert!(frequent < rare);
+        assert!(rare < unseen);
+        assert!(unseen.is_finite());
+    }
+
+    #[test]
+    fn find_matches_with_weights_previews_without_changing_weights() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "vim main.rs", "s1", "/src", now - 30, 0);
+        add(&history, "ls", "s1", "/src", now - 20, 0);
+        add(&history, "ls", "s1", "/src", now - 10, 0);
+        let order = |weights: &Weights| -> Vec<String> {
+            history
+                .find_matches_with_weights("", Some("/src"), weights, 10)
+                .into_iter()
+                .map(|c| c.cmd)
+                .collect()
+        };
+
+        assert_eq!(order(&Weights::default()), vec!["ls", "vim main.rs"]);
+        let modifier_heavy = Weights {
+            modifier: Some(2.0),
+            ..Weights::default()
+        };
+        history.build_cache_table(Some("/tmp"), &None, None, None, None);
+        assert_eq!(order(&modifier_heavy), vec!["vim main.rs", "ls"]);
+        assert_eq!(history.modifier_weight, 0.0);
+        assert_eq!(order(&Weights::default()), vec!["ls", "vim main.rs"]);
+
+        // The cache built for /tmp is still there, with every command run elsewhere.
+        let matches = history.find_matches("", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|c| c.features.dir_factor == 0.0));
+    }
+
+    #[test]
+    fn equivalent_dirs_count_toward_dir_factor() {
+        let mut history = in_memory_history();
+        let now = now();
+        add(
+            &history,
+            "cargo test",
+            "s1",
+            "/home/me/project",
+            now - 30,
+            0,
+        );
+        add(&history, "cargo build", "s1", "/tmp", now - 20, 0);
+        let dir_factor = |history: &History, cmd: &str| {
+            history.build_cache_table(Some("/app"), &None, None, None, Some(now));
+            history
+                .find_matches("cargo", 10, false, false, MatchMode::Substring)
+                .into_iter()
+                .find(|c| c.cmd == cmd)
+                .unwrap()
+                .features
+                .dir_factor
+        };
+        assert_eq!(dir_factor(&history, "cargo test"), 0.0);
+
+        history.equivalent_dirs = vec!["/home/me/project".to_string()];
+        assert_eq!(dir_factor(&history, "cargo test"), 1.0);
+        assert_eq!(dir_factor(&history, "cargo build"), 0.0);
+    }
+
+    #[test]
+    fn closest_suggests_commands_by_edit_distance() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "git checkout main", "s1", "/tmp", now - 30, 0);
+        add(&history, "git checkout", "s1", "/tmp", now - 20, 0);
+        add(&history, "cargo test", "s1", "/tmp", now - 10, 0);
+
+        let suggestions = history.closest("git chekout", 2);
+        assert_eq!(suggestions.len(), 2);
+        assert_eq!(suggestions[0].0.cmd, "git checkout");
+        assert_eq!(suggestions[0].1, 1);
+        assert_eq!(suggestions[1].0.cmd, "git checkout main");
+    }
+
+    #[test]
+    fn find_matches_puts_preferred_commands_first() {
+        let mut history = in_memory_history();
+        let now = now();
+        for i in 0..5 {
+            add(&history, "kubectl get pods", "s1", "/tmp", now - 100 + i, 0);
+        }
+        add(&history, "kubectl logs -f web", "s1", "/tmp", now - 50, 0);
+        add(&history, "ls -la", "s1", "/tmp", now - 40, 0);
+        history.preferred_commands.insert(
+            "kubectl".to_string(),
+            vec![
+                "kubectl logs -f web".to_string(),
+                "kubectl config use-context prod".to_string(),
+            ],
+        );
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+
+        let matches: Vec<String> = history
+            .find_matches("kubectl", 10, false, false, MatchMode::Substring)
+            .into_iter()
+            .map(|c| c.cmd)
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                "kubectl logs -f web",
+                "kubectl config use-context prod",
+                "kubectl get pods"
+            ]
+        );
+
+        let matches = history.find_matches("kubectl", 1, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].cmd, "kubectl logs -f web");
+
+        let matches = history.find_matches("ls", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches[0].cmd, "ls -la");
+    }
+
+    #[test]
+    fn find_matches_supports_wildcards() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "docker system prune", "s1", "/tmp", now - 30, 0);
+        add(&history, "docker prune-ish", "s1", "/tmp", now - 20, 0);
+        add(&history, "prune docker", "s1", "/tmp", now - 10, 0);
+        add(&history, "echo a*b", "s1", "/tmp", now - 5, 0);
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(now));
+
+        let mut matches: Vec<String> = history
+            .find_matches("docker*prune", 10, false, false, MatchMode::Substring)
+            .into_iter()
+            .map(|c| c.cmd)
+            .collect();
+        matches.sort();
+        assert_eq!(matches, vec!["docker prune-ish", "docker system prune"]);
+
+        let matches = history.find_matches("docker*prune", 10, false, false, MatchMode::Substring);
+        let system_prune = matches
+            .iter()
+            .find(|c| c.cmd == "docker system prune")
+            .unwrap();
+        assert_eq!(system_prune.match_bounds, vec![(0, 6), (14, 19)]);
+
+        assert_eq!(
+            history
+                .find_matches("system", 10, false, false, MatchMode::Substring)
+                .len(),
+            1
+        );
+        assert_eq!(
+            history.find_matches("a*b", 10, false, false, MatchMode::Substring)[0].cmd,
+            "echo a*b"
+        );
+    }
+
+    #[test]
+    fn normalization_keeps_one_factor_from_dominating() {
+        let mut history = in_memory_history();
+        history.network = Network {
+            final_bias: 0.0,
+            final_weights: [1.0, 0.0, 0.0],
+            hidden_nodes: [
+                Node {
+                    dir: 1.0,
+                    occurrences: 1.0,
+                    ..Node::default()
+                },
+                Node::default(),
+                Node::default(),
+            ],
+            ..Network::default()
+        };
+        let command = |cmd: &str, occurrences_factor: f64, dir_factor: f64| {
+            let features = Features {
+                occurrences_factor,
+                dir_factor,
+                ..Features::default()
+            };
+            Command {
+                cmd: cmd.to_string(),
+                rank: history.network.output(&features),
+                features,
+                ..Command::default()
+            }
+        };
+        let mut commands = vec![
+            command("make", 1.0, 0.40),
+            command("make test", 0.7, 0.50),
+            command("make clean", 0.0, 0.45),
+        ];
+        let order = |commands: &[Command]| -> Vec<String> {
+            commands.iter().map(|c| c.cmd.to_owned()).collect()
+        };
+        assert_eq!(order(&commands), vec!["make", "make test", "make clean"]);
+
+        history.normalize(&mut commands, Normalization::MinMax);
+        assert_eq!(order(&commands), vec!["make test", "make", "make clean"]);
+        assert_eq!(commands[0].features.dir_factor, 1.0);
+        assert_eq!(commands[0].features.age_factor, 0.0);
+
+        let mut commands = vec![
+            command("make", 1.0, 0.40),
+            command("make test", 0.7, 0.50),
+            command("make clean", 0.0, 0.45),
+        ];
+        history.normalize(&mut commands, Normalization::ZScore);
+        assert_eq!(order(&commands), vec!["make test", "make", "make clean"]);
+    }
+
+    #[test]
+    fn find_matches_breaks_rank_ties_by_recency() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "echo newer", "s1", "/tmp", now - 10, 0);
+        add(&history, "echo older", "s1", "/tmp", now - 20, 0);
+        history.build_cache_table(Some("/tmp"), &Some("s1".to_string()), None, None, Some(now));
+        history
+            .connection
+            .execute("UPDATE contextual_commands SET rank = 0.5", NO_PARAMS)
+            .unwrap();
+
+        for _ in 0..3 {
+            let matches = history.find_matches("echo", 10, false, false, MatchMode::Substring);
+            assert_eq!(matches[0].cmd, "echo newer");
+            assert_eq!(matches[1].cmd, "echo older");
+        }
+    }
+
+    #[test]
+    fn find_matches_treats_like_wildcards_literally() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "printf \"%s\" foo", "s1", "/tmp", now - 30, 0);
+        add(&history, "printf \"hi\" foo", "s1", "/tmp", now - 20, 0);
+        add(&history, "echo a_b", "s1", "/tmp", now - 15, 0);
+        add(&history, "echo axb", "s1", "/tmp", now - 10, 0);
+        history.build_cache_table(Some("/tmp"), &Some("s1".to_string()), None, None, Some(now));
+
+        let cmds = |search, fuzzy| -> Vec<String> {
+            history
+                .find_matches(search, 10, fuzzy, false, MatchMode::Substring)
+                .into_iter()
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(cmds("printf \"%s\"", false), vec!["printf \"%s\" foo"]);
+        assert_eq!(cmds("a_b", false), vec!["echo a_b"]);
+        assert_eq!(cmds("%s", true), vec!["printf \"%s\" foo"]);
+    }
+
+    #[test]
+    fn find_matches_can_exclude_scripted_commands() {
+        let history = in_memory_history();
+        let now = now();
+        history.add(
+            "make test",
+            Some("s1"),
+            Some("/tmp"),
+            &Some(now - 20),
+            Some(0),
+            &None,
+            true,
+            None,
+            None,
+            None,
+        );
+        history.add(
+            "make clean",
+            Some("s1"),
+            Some("/tmp"),
+            &Some(now - 10),
+            Some(0),
+            &None,
+            false,
+            None,
+            None,
+            None,
+        );
+        history.build_cache_table(Some("/tmp"), &Some("s1".to_string()), None, None, Some(now));
+
+        assert_eq!(
+            history
+                .find_matches("make", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
+
+        let interactive = history.find_matches("make", 10, false, true, MatchMode::Substring);
+        assert_eq!(interactive.len(), 1);
+        assert_eq!(interactive[0].cmd, "make test");
+    }
+
+    #[test]
+    fn signal_terminations_are_not_recent_failures() {
+        let history = in_memory_history();
+        let now = now();
+        add(&history, "sleep 100", "s1", "/tmp", now - 10, 130);
+        add(&history, "false", "s1", "/tmp", now - 5, 1);
+
+        history.build_cache_table(Some("/tmp"), &Some("s1".to_string()), None, None, Some(now));
+
+        let interrupted = &history.find_matches("sleep", 10, false, false, MatchMode::Substring)[0];
+        assert_eq!(interrupted.features.recent_failure_factor, 0.0);
+
+        let failed = &history.find_matches("false", 10, false, false, MatchMode::Substring)[0];
+        assert_eq!(failed.features.recent_failure_factor, 1.0);
+    }
+
+    #[test]
+    fn build_cache_table_can_be_pinned_to_a_past_time() {
+        let history = in_memory_history();
+        let then = now() - 7 * 24 * 60 * 60;
+        add(&history, "make deploy", "s1", "/tmp", then - 30, 2);
+        add(&history, "make clean", "s1", "/tmp", then + 60, 0);
+
+        history.build_cache_table(Some("/tmp"), &None, None, None, Some(then));
+        let matches = history.find_matches("make", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].cmd, "make deploy");
+        assert_eq!(matches[0].features.recent_failure_factor, 1.0);
+
+        history.build_cache_table(Some("/tmp"), &None, None, None, None);
+        let matches = history.find_matches("make deploy", 10, false, false, MatchMode::Substring);
+        assert_eq!(matches[0].features.recent_failure_factor, 0.0);
+        assert_eq!(
+            history
+                .find_matches("make", 10, false, false, MatchMode::Substring)
+                .len(),
+            2
+        );
     }
 }
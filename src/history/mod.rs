@@ -1,5 +1,15 @@
-pub use self::history::{Command, Features, History};
+pub use self::history::{
+    AliasSuggestion, Command, Context, DangerPolicy, DbOptions, DuplicateGroup, DuplicateReport,
+    EditRequest, EvalReport, ExitStats, Features, FilterReason, GrowthStats, History, MatchMode,
+    Normalization, Snapshot, SnapshotDiff, SplitMatches, TemplateSummary, Timings,
+};
+#[cfg(feature = "pool")]
+pub use self::pool::{HistoryPool, PooledHistory};
+pub use self::schema::MigrationStatus;
 
+mod compression;
 mod db_extensions;
 mod history;
+#[cfg(feature = "pool")]
+mod pool;
 mod schema;
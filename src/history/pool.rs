@@ -0,0 +1,193 @@
+#[cfg(feature = "compression")]
+use crate::history::compression;
+use crate::history::{schema, DbOptions, History};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a connection waits on another connection's lock before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A set of `History` handles on one database, for answering searches from several threads.
+///
+/// Each reader has its own connection, so each has its own `contextual_commands` cache table and
+/// should call `build_cache_table` before `find_matches`. Writes all go through a single
+/// connection, one at a time.
+pub struct HistoryPool {
+    path: PathBuf,
+    options: DbOptions,
+    idle: Mutex<Vec<History>>,
+    writer: Mutex<History>,
+}
+
+/// A reader checked out of a `HistoryPool`. It goes back to the pool when dropped.
+pub struct PooledHistory<'a> {
+    pool: &'a HistoryPool,
+    history: Option<History>,
+}
+
+impl HistoryPool {
+    /// Open a pool on an existing McFly database, migrating it if needed. Every connection is
+    /// opened with `options`.
+    pub fn new(path: PathBuf, options: &DbOptions) -> HistoryPool {
+        schema::migrate(&History::from_db_path(path.to_owned(), options).connection);
+        let writer = HistoryPool::open(&path, options);
+        HistoryPool {
+            path,
+            options: options.to_owned(),
+            idle: Mutex::new(Vec::new()),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Check out a reader, opening a new connection if none are idle.
+    pub fn get(&self) -> PooledHistory<'_> {
+        let history = self
+            .idle
+            .lock()
+            .unwrap_or_else(|err| panic!(format!("McFly error: Pool lock to work ({})", err)))
+            .pop()
+            .unwrap_or_else(|| HistoryPool::open(&self.path, &self.options));
+        PooledHistory {
+            pool: self,
+            history: Some(history),
+        }
+    }
+
+    /// Run `f` against the writer connection, waiting for any other write to finish first.
+    pub fn write<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&History) -> T,
+    {
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|err| panic!(format!("McFly error: Pool lock to work ({})", err)));
+        f(&writer)
+    }
+
+    fn open(path: &PathBuf, options: &DbOptions) -> History {
+        let history = History::from_db_path(path.to_owned(), options);
+        history
+            .connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .unwrap_or_else(|err| panic!(format!("McFly error: Busy timeout to set ({})", err)));
+        #[cfg(feature = "compression")]
+        compression::install(&history.connection);
+        history
+    }
+}
+
+impl<'a> Deref for PooledHistory<'a> {
+    type Target = History;
+
+    fn deref(&self) -> &History {
+        self.history.as_ref().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledHistory<'a> {
+    fn drop(&mut self) {
+        if let Some(history) = self.history.take() {
+            if let Ok(mut idle) = self.pool.idle.lock() {
+                idle.push(history);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HistoryPool;
+    use crate::history::{schema, DbOptions, History, MatchMode};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_db_path() -> PathBuf {
+        let path = env::temp_dir().join(format!("mcfly-pool-test-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        let history = History::from_db_path(path.to_owned(), &DbOptions::default());
+        History::create_tables(&history.connection);
+        schema::first_time_setup(&history.connection);
+        path
+    }
+
+    #[test]
+    fn concurrent_searches_share_one_database() {
+        let path = temp_db_path();
+        let pool = Arc::new(HistoryPool::new(path.to_owned(), &DbOptions::default()));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        pool.write(|history| {
+            for cmd in &["cargo build", "cargo test", "git status"] {
+                history.add(
+                    cmd,
+                    Some("s1"),
+                    Some("/tmp"),
+                    &Some(now - 10),
+                    Some(0),
+                    &None,
+                    true,
+                    None,
+                    None,
+                    None,
+                );
+            }
+        });
+
+        let searches: Vec<_> = (0..4)
+            .map(|i| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        if i == 0 {
+                            pool.write(|history| {
+                                history.add(
+                                    "ls",
+                                    Some("s2"),
+                                    Some("/tmp"),
+                                    &Some(now - 5),
+                                    Some(0),
+                                    &None,
+                                    true,
+                                    None,
+                                    None,
+                                    None,
+                                )
+                            });
+                        }
+                        let history = pool.get();
+                        history.build_cache_table(
+                            Some("/tmp"),
+                            &Some("s1".to_string()),
+                            None,
+                            None,
+                            Some(now),
+                        );
+                        assert_eq!(
+                            history
+                                .find_matches("cargo", 10, false, false, MatchMode::Substring)
+                                .len(),
+                            2
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for search in searches {
+            search.join().unwrap();
+        }
+        assert!(pool.idle.lock().unwrap().len() <= 4);
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,26 +1,76 @@
+use crate::history::History;
 use crate::simplified_command::SimplifiedCommand;
 use rusqlite::{Connection, NO_PARAMS};
 use std::io;
 use std::io::Write;
 
-pub const CURRENT_SCHEMA_VERSION: u16 = 3;
+pub const CURRENT_SCHEMA_VERSION: u16 = 17;
+
+/// What each schema version adds, starting from version 1, for `status`.
+const MIGRATIONS: [&str; CURRENT_SCHEMA_VERSION as usize] = [
+    "command templates (cmd_tpl)",
+    "session ids",
+    "selected commands",
+    "exit signals",
+    "interactive flags",
+    "dangerous flags",
+    "parent programs",
+    "cursor positions",
+    "notes",
+    "command tags",
+    "import sources",
+    "hidden flags",
+    "argument counts",
+    "error snippets",
+    "import watermarks",
+    "environment hashes",
+    "directory inodes",
+];
+
+/// Which schema migrations a database has had, from `status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    /// The schema version the database is at, 0 if it has never been migrated.
+    pub current_version: u16,
+    /// The version `migrate` brings databases to.
+    pub latest_version: u16,
+    /// The versions `migrate` would still apply, oldest first, with what each adds.
+    pub pending: Vec<(u16, &'static str)>,
+}
 
 pub fn first_time_setup(connection: &Connection) {
     make_schema_versions_table(connection);
     write_current_schema_version(connection);
 }
 
-pub fn migrate(connection: &Connection) {
-    make_schema_versions_table(connection);
-
-    let current_version: u16 = connection
-        .query_row::<Option<u16>, _, _>(
-            "select max(version) FROM schema_versions ORDER BY version DESC LIMIT 1",
+/// Where `connection`'s database is in the list of migrations, without changing anything.
+pub fn status(connection: &Connection) -> MigrationStatus {
+    let has_versions: bool = connection
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_versions'",
             NO_PARAMS,
             |row| row.get(0),
         )
-        .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)))
-        .unwrap_or(0);
+        .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)));
+    let current_version = if has_versions {
+        current_version(connection)
+    } else {
+        0
+    };
+
+    MigrationStatus {
+        current_version,
+        latest_version: CURRENT_SCHEMA_VERSION,
+        pending: (current_version + 1..=CURRENT_SCHEMA_VERSION)
+            .map(|version| (version, MIGRATIONS[version as usize - 1]))
+            .collect(),
+    }
+}
+
+pub fn migrate(connection: &Connection) {
+    make_schema_versions_table(connection);
+
+    let current_version = current_version(connection);
 
     if current_version < CURRENT_SCHEMA_VERSION {
         print!(
@@ -95,12 +145,217 @@ pub fn migrate(connection: &Connection) {
             });
     }
 
+    if current_version < 4 {
+        connection
+            .execute_batch(
+                "ALTER TABLE commands ADD COLUMN exit_signal INTEGER; \
+                 UPDATE commands SET exit_signal = exit_code - 128 WHERE exit_code > 128 AND exit_code <= 192;",
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add exit_signal to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 5 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN interactive INTEGER;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add interactive to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 6 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN dangerous INTEGER NOT NULL DEFAULT 0;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add dangerous to commands ({})",
+                    err
+                ))
+            });
+
+        let mut statement = connection
+            .prepare("UPDATE commands SET dangerous = 1 WHERE id = :id")
+            .unwrap_or_else(|err| {
+                panic!(format!("McFly error: Unable to prepare update ({})", err))
+            });
+
+        let dangerous_patterns = History::default_dangerous_patterns();
+        for (id, cmd) in cmd_strings(connection) {
+            if History::is_dangerous(&cmd, &dangerous_patterns) {
+                statement
+                    .execute_named(&[(":id", &id)])
+                    .unwrap_or_else(|err| panic!(format!("McFly error: Update to work ({})", err)));
+            }
+        }
+    }
+
+    if current_version < 7 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN parent TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add parent to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 8 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN cursor_pos INTEGER;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add cursor_pos to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 9 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN note TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add note to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 10 {
+        connection
+            .execute_batch(
+                "CREATE TABLE command_tags( \
+                  id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                  command_id INTEGER NOT NULL, \
+                  tag TEXT NOT NULL \
+                ); \
+                CREATE UNIQUE INDEX command_tags_command_id_tag ON command_tags (command_id, tag);",
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!("McFly error: Unable to add command_tags ({})", err))
+            });
+    }
+
+    if current_version < 11 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN source TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add source to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 12 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add hidden to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 13 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN arg_count INTEGER;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add arg_count to commands ({})",
+                    err
+                ))
+            });
+
+        let mut statement = connection
+            .prepare("UPDATE commands SET arg_count = :arg_count WHERE id = :id")
+            .unwrap_or_else(|err| {
+                panic!(format!("McFly error: Unable to prepare update ({})", err))
+            });
+
+        for (id, cmd) in cmd_strings(connection) {
+            statement
+                .execute_named(&[(":arg_count", &History::arg_count(&cmd)), (":id", &id)])
+                .unwrap_or_else(|err| panic!(format!("McFly error: Insert to work ({})", err)));
+        }
+    }
+
+    if current_version < 14 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN error_snippet TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add error_snippet to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 15 {
+        connection
+            .execute_batch(
+                "CREATE TABLE import_watermarks( \
+                  path TEXT PRIMARY KEY, \
+                  entries INTEGER NOT NULL, \
+                  last_cmd TEXT NOT NULL \
+                );",
+            )
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add import_watermarks ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 16 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN env_hash TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add env_hash to commands ({})",
+                    err
+                ))
+            });
+    }
+
+    if current_version < 17 {
+        connection
+            .execute_batch("ALTER TABLE commands ADD COLUMN dir_inode TEXT;")
+            .unwrap_or_else(|err| {
+                panic!(format!(
+                    "McFly error: Unable to add dir_inode to commands ({})",
+                    err
+                ))
+            });
+    }
+
     if current_version < CURRENT_SCHEMA_VERSION {
         println!("done.");
         write_current_schema_version(connection);
     }
 }
 
+fn current_version(connection: &Connection) -> u16 {
+    connection
+        .query_row::<Option<u16>, _, _>(
+            "select max(version) FROM schema_versions ORDER BY version DESC LIMIT 1",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap_or_else(|err| panic!(format!("McFly error: Query to work ({})", err)))
+        .unwrap_or(0)
+}
+
 fn make_schema_versions_table(connection: &Connection) {
     connection
         .execute_batch(
@@ -133,7 +388,7 @@ fn write_current_schema_version(connection: &Connection) {
 }
 
 fn cmd_strings(connection: &Connection) -> Vec<(i64, String)> {
-    let query = "SELECT id, cmd FROM commands ORDER BY id DESC";
+    let query = "SELECT id, mcfly_decompress(cmd) FROM commands ORDER BY id DESC";
     let mut statement = connection.prepare(query).unwrap();
     let command_iter = statement
         .query_map(NO_PARAMS, |row| (row.get(0), row.get(1)))
@@ -148,3 +403,45 @@ fn cmd_strings(connection: &Connection) -> Vec<(i64, String)> {
 
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{first_time_setup, migrate, status, CURRENT_SCHEMA_VERSION};
+    use crate::history::{DbOptions, History};
+    use std::path::PathBuf;
+
+    #[test]
+    fn status_lists_the_pending_migrations() {
+        let history = History::from_db_path(PathBuf::from(":memory:"), &DbOptions::default());
+        History::create_tables(&history.connection);
+
+        history
+            .connection
+            .execute_batch(
+                "CREATE TABLE schema_versions( \
+                    id INTEGER PRIMARY KEY AUTOINCREMENT, \
+                    version INTEGER NOT NULL, \
+                    when_run INTEGER NOT NULL); \
+                 INSERT INTO schema_versions (version, when_run) VALUES (13, 0);",
+            )
+            .unwrap();
+        let old = status(&history.connection);
+        assert_eq!(old.current_version, 13);
+        assert_eq!(old.latest_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(
+            old.pending,
+            vec![
+                (14, "error snippets"),
+                (15, "import watermarks"),
+                (16, "environment hashes"),
+                (17, "directory inodes")
+            ]
+        );
+
+        let fresh = History::from_db_path(PathBuf::from(":memory:"), &DbOptions::default());
+        History::create_tables(&fresh.connection);
+        first_time_setup(&fresh.connection);
+        migrate(&fresh.connection);
+        assert!(status(&fresh.connection).pending.is_empty());
+    }
+}
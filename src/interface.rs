@@ -1,5 +1,6 @@
 use crate::command_input::{CommandInput, Move};
 use crate::history::History;
+use crate::history::MatchMode;
 
 use crate::fixed_length_grapheme_string::FixedLengthGraphemeString;
 use crate::history::Command;
@@ -7,6 +8,7 @@ use crate::history_cleaner;
 use crate::settings::KeyScheme;
 use crate::settings::Settings;
 use std::io::{stdin, stdout, Write};
+use std::time::Duration;
 use termion::color;
 use termion::event::Key;
 use termion::input::TermRead;
@@ -121,7 +123,7 @@ impl<'a> Interface<'a> {
 
     fn build_cache_table(&self) {
         self.history.build_cache_table(
-            &self.settings.dir.to_owned(),
+            Some(&self.settings.dir),
             &Some(self.settings.session_id.to_owned()),
             None,
             None,
@@ -245,9 +247,25 @@ impl<'a> Interface<'a> {
             write!(screen, "{}", color::Fg(color::Reset)).unwrap();
         }
         screen.flush().unwrap();
+
+        if self.debug && self.history.timing {
+            let timings = self.history.timings();
+            let millis = |duration: Option<Duration>| {
+                duration.map_or("-".to_string(), |duration| {
+                    format!("{:.1}ms", duration.as_micros() as f64 / 1000.0)
+                })
+            };
+            self.debug(
+                screen,
+                format!(
+                    "cache: {} search: {}",
+                    millis(timings.build_cache_table),
+                    millis(timings.find_matches)
+                ),
+            );
+        }
     }
 
-    #[allow(unused)]
     fn debug<W: Write, S: Into<String>>(&self, screen: &mut W, s: S) {
         write!(
             screen,
@@ -306,6 +324,8 @@ impl<'a> Interface<'a> {
             &self.input.command,
             self.settings.results as i16,
             self.settings.fuzzy,
+            self.settings.interactive_only,
+            MatchMode::Substring,
         );
     }
 
@@ -1,4 +1,6 @@
 pub mod command_input;
+pub mod config;
+pub mod edit_distance;
 pub mod fake_typer;
 pub mod fixed_length_grapheme_string;
 pub mod history;
@@ -7,6 +9,7 @@ pub mod interface;
 pub mod network;
 pub mod node;
 pub mod path_update_helpers;
+pub mod relative_time;
 pub mod settings;
 pub mod shell_history;
 pub mod simplified_command;
@@ -10,14 +10,22 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
 fn handle_addition(settings: &Settings, history: &mut History) {
-    if history.should_add(&settings.command) {
+    if history.should_add(
+        &settings.command,
+        &settings.session_id,
+        settings.ignore_previous,
+    ) {
         history.add(
             &settings.command,
-            &settings.session_id,
-            &settings.dir,
+            Some(&settings.session_id),
+            Some(&settings.dir),
             &settings.when_run,
             settings.exit_code,
             &settings.old_dir,
+            settings.interactive,
+            None,
+            settings.error_snippet.as_deref(),
+            settings.env_hash.as_deref(),
         );
 
         if settings.append_to_histfile {
@@ -93,10 +101,22 @@ fn handle_move(settings: &Settings, history: &mut History) {
     history.update_paths(&settings.old_dir.clone().unwrap(), &settings.dir, true);
 }
 
+fn handle_maintain(history: &History) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|err| panic!(format!("McFly error: Time went backwards ({})", err)))
+        .as_secs() as i64;
+    let (expired, relativized) = history.maintain(now);
+    println!(
+        "McFly: Deleted {} old commands and made {} directories home-relative.",
+        expired, relativized
+    );
+}
+
 fn main() {
     let settings = Settings::parse_args();
 
-    let mut history = History::load(settings.history_format);
+    let mut history = History::load(settings.import_format, settings.skip_comments);
 
     match settings.mode {
         Mode::Add => {
@@ -111,5 +131,8 @@ fn main() {
         Mode::Move => {
             handle_move(&settings, &mut history);
         }
+        Mode::Maintain => {
+            handle_maintain(&history);
+        }
     }
 }
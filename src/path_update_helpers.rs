@@ -1,6 +1,6 @@
 use relative_path::RelativePath;
 use std::env;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use unicode_segmentation::UnicodeSegmentation;
 
 pub fn normalize_path(incoming_path: &str) -> String {
@@ -36,6 +36,42 @@ pub fn normalize_path(incoming_path: &str) -> String {
         .to_string()
 }
 
+/// Where a `cd` or `pushd` run from `dir` goes, or `None` if it isn't one or the destination
+/// can't be told from the command alone (`cd -`, `pushd +1`, variables).
+pub fn parse_cd_command(command: &str, dir: &str) -> Option<String> {
+    let mut words = command.split_whitespace();
+    let program = words.next()?;
+    if program != "cd" && program != "pushd" {
+        return None;
+    }
+    if words.any(|word| word == "-") {
+        return None;
+    }
+
+    let target = match parse_mv_command(command).as_slice() {
+        [] if program == "cd" => "~".to_string(),
+        [target] if !target.starts_with('+') => target.to_owned(),
+        _ => return None,
+    };
+    if target.contains('$') || target.contains('`') {
+        return None;
+    }
+
+    let expanded_target = shellexpand::tilde(&target);
+    let mut path_buf = PathBuf::from(dir);
+    for component in Path::new(expanded_target.as_ref()).components() {
+        match component {
+            Component::RootDir => path_buf = PathBuf::from("/"),
+            Component::ParentDir => {
+                path_buf.pop();
+            }
+            Component::Normal(name) => path_buf.push(name),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    path_buf.to_str().map(|path| path.to_string())
+}
+
 pub fn parse_mv_command(command: &str) -> Vec<String> {
     let mut in_double_quote = false;
     let mut in_single_quote = false;
@@ -110,7 +146,7 @@ pub fn parse_mv_command(command: &str) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{normalize_path, parse_mv_command};
+    use super::{normalize_path, parse_cd_command, parse_mv_command};
     use std::env;
     use std::path::PathBuf;
 
@@ -204,4 +240,50 @@ mod tests {
             vec!["\"foo".to_string(), "bar".to_string()]
         );
     }
+
+    #[test]
+    fn parse_cd_command_resolves_the_destination() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(
+            parse_cd_command("cd foo/bar", "/src"),
+            Some("/src/foo/bar".to_string())
+        );
+        assert_eq!(
+            parse_cd_command("cd ..", "/src/app"),
+            Some("/src".to_string())
+        );
+        assert_eq!(
+            parse_cd_command("cd /tmp/", "/src"),
+            Some("/tmp".to_string())
+        );
+        assert_eq!(
+            parse_cd_command("cd 'My Documents'", "/src"),
+            Some("/src/My Documents".to_string())
+        );
+        assert_eq!(
+            parse_cd_command("pushd -q ../lib", "/src/app"),
+            Some("/src/lib".to_string())
+        );
+        assert_eq!(
+            parse_cd_command("cd ~/foo", "/src"),
+            Some(
+                PathBuf::from(&home)
+                    .join("foo")
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+        assert_eq!(parse_cd_command("cd", "/src"), Some(home));
+    }
+
+    #[test]
+    fn parse_cd_command_gives_up_when_it_cant_tell() {
+        assert_eq!(parse_cd_command("ls foo", "/src"), None);
+        assert_eq!(parse_cd_command("cdx foo", "/src"), None);
+        assert_eq!(parse_cd_command("cd -", "/src"), None);
+        assert_eq!(parse_cd_command("pushd", "/src"), None);
+        assert_eq!(parse_cd_command("pushd +1", "/src"), None);
+        assert_eq!(parse_cd_command("cd $PROJECT", "/src"), None);
+        assert_eq!(parse_cd_command("cd a b", "/src"), None);
+    }
 }
@@ -0,0 +1,89 @@
+/// Turn a time expression like `2 hours ago`, `today`, or `yesterday` into a Unix timestamp,
+/// counting back from `now`. `today` and `yesterday` mean the start of that day in local time.
+/// Returns `None` for anything else.
+pub fn parse_relative_time(expr: &str, now: i64) -> Option<i64> {
+    let expr = expr.trim().to_lowercase();
+    match expr.as_str() {
+        "now" => return Some(now),
+        "today" => return start_of_day(now, 0),
+        "yesterday" => return start_of_day(now, 1),
+        _ => {}
+    }
+
+    let words: Vec<&str> = expr.split_whitespace().collect();
+    match words.as_slice() {
+        [count, unit, "ago"] => {
+            let count: i64 = count.parse().ok()?;
+            let seconds = match unit.trim_end_matches('s') {
+                "second" | "sec" => 1,
+                "minute" | "min" => 60,
+                "hour" | "hr" => 60 * 60,
+                "day" => 24 * 60 * 60,
+                "week" => 7 * 24 * 60 * 60,
+                _ => return None,
+            };
+            Some(now - count.checked_mul(seconds)?)
+        }
+        _ => None,
+    }
+}
+
+// Local midnight `days_back` days before the day containing `now`. The conversions look useless
+// where `time_t` is 64 bits, but it's 32 bits on some platforms.
+#[allow(clippy::useless_conversion)]
+fn start_of_day(now: i64, days_back: i32) -> Option<i64> {
+    let time = now as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return None;
+    }
+    tm.tm_sec = 0;
+    tm.tm_min = 0;
+    tm.tm_hour = 0;
+    tm.tm_mday -= days_back;
+    // Let mktime work out whether daylight saving time was in effect at midnight.
+    tm.tm_isdst = -1;
+    match unsafe { libc::mktime(&mut tm) } {
+        -1 => None,
+        start => Some(i64::from(start)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_relative_time;
+
+    const NOW: i64 = 1_600_000_000;
+
+    #[test]
+    fn parses_counts_of_units_ago() {
+        assert_eq!(
+            parse_relative_time("2 hours ago", NOW),
+            Some(NOW - 2 * 60 * 60)
+        );
+        assert_eq!(parse_relative_time("1 minute ago", NOW), Some(NOW - 60));
+        assert_eq!(
+            parse_relative_time(" 3 Days  Ago ", NOW),
+            Some(NOW - 3 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_relative_time("now", NOW), Some(NOW));
+    }
+
+    #[test]
+    fn parses_today_and_yesterday_as_local_midnight() {
+        let today = parse_relative_time("today", NOW).unwrap();
+        let yesterday = parse_relative_time("yesterday", NOW).unwrap();
+        assert!(today <= NOW && NOW - today < 24 * 60 * 60);
+        let day = today - yesterday;
+        assert!((23 * 60 * 60..=25 * 60 * 60).contains(&day));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        assert_eq!(parse_relative_time("", NOW), None);
+        assert_eq!(parse_relative_time("last tuesday", NOW), None);
+        assert_eq!(parse_relative_time("two hours ago", NOW), None);
+        assert_eq!(parse_relative_time("2 fortnights ago", NOW), None);
+        assert_eq!(parse_relative_time("2 hours", NOW), None);
+    }
+}
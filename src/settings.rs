@@ -15,6 +15,7 @@ pub enum Mode {
     Search,
     Train,
     Move,
+    Maintain,
 }
 
 #[derive(Debug)]
@@ -41,6 +42,82 @@ pub enum HistoryFormat {
     Fish,
 }
 
+impl HistoryFormat {
+    /// The shell this format comes from, recorded as the source of imported commands.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HistoryFormat::Bash => "bash",
+            HistoryFormat::Zsh { .. } => "zsh",
+            HistoryFormat::Fish => "fish",
+        }
+    }
+}
+
+/// The shell McFly is running under, for picking which history to import when none is given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Unknown,
+}
+
+impl Shell {
+    /// Look at `MCFLY_SHELL` (which `mcfly.bash` sets), then `SHELL`, the login shell.
+    pub fn detect() -> Shell {
+        Shell::detect_from(
+            env::var("MCFLY_SHELL").ok().as_deref(),
+            env::var("SHELL").ok().as_deref(),
+        )
+    }
+
+    /// `detect`, given the values of `MCFLY_SHELL` and `SHELL`.
+    pub fn detect_from(mcfly_shell: Option<&str>, shell: Option<&str>) -> Shell {
+        mcfly_shell
+            .map(Shell::from_name)
+            .filter(|shell| *shell != Shell::Unknown)
+            .or_else(|| shell.map(Shell::from_name))
+            .unwrap_or(Shell::Unknown)
+    }
+
+    /// Parse a shell path or name like `/usr/bin/zsh`, `fish`, or `-bash` (as `$0` reads in a
+    /// login shell).
+    pub fn from_name(name: &str) -> Shell {
+        let name = name.rsplit('/').next().unwrap_or(name);
+        match name.trim_start_matches('-') {
+            "bash" | "sh" => Shell::Bash,
+            "zsh" => Shell::Zsh,
+            "fish" => Shell::Fish,
+            _ => Shell::Unknown,
+        }
+    }
+
+    /// The format of this shell's history file, with bash as the fallback.
+    pub fn history_format(self) -> HistoryFormat {
+        match self {
+            Shell::Zsh => HistoryFormat::Zsh {
+                extended_history: false,
+            },
+            Shell::Fish => HistoryFormat::Fish,
+            Shell::Bash | Shell::Unknown => HistoryFormat::Bash,
+        }
+    }
+}
+
+/// Controls whether `add` skips a command identical to the one run just before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IgnorePrevious {
+    /// Record every command, even immediate repeats.
+    Off,
+
+    /// Skip a command only if it repeats the previous command from the same session.
+    Session,
+
+    /// Skip a command if it repeats the previous command from any session, so that opening a new
+    /// terminal window won't replay the last command in the history.
+    Global,
+}
+
 #[derive(Debug)]
 pub struct Settings {
     pub mode: Mode,
@@ -55,11 +132,20 @@ pub struct Settings {
     pub when_run: Option<i64>,
     pub exit_code: Option<i32>,
     pub old_dir: Option<String>,
+    pub error_snippet: Option<String>,
+    pub env_hash: Option<String>,
     pub append_to_histfile: bool,
     pub refresh_training_cache: bool,
     pub lightmode: bool,
     pub key_scheme: KeyScheme,
     pub history_format: HistoryFormat,
+    /// The format of the shell history imported when the database is first created: the one given
+    /// with `--history_format`, or else the detected shell's.
+    pub import_format: HistoryFormat,
+    pub ignore_previous: IgnorePrevious,
+    pub interactive: bool,
+    pub interactive_only: bool,
+    pub skip_comments: bool,
 }
 
 impl Default for Settings {
@@ -75,6 +161,8 @@ impl Default for Settings {
             when_run: None,
             exit_code: None,
             old_dir: None,
+            error_snippet: None,
+            env_hash: None,
             refresh_training_cache: false,
             append_to_histfile: false,
             debug: false,
@@ -82,6 +170,11 @@ impl Default for Settings {
             lightmode: false,
             key_scheme: KeyScheme::Emacs,
             history_format: HistoryFormat::Bash,
+            import_format: HistoryFormat::Bash,
+            ignore_previous: IgnorePrevious::Global,
+            interactive: true,
+            interactive_only: false,
+            skip_comments: false,
         }
     }
 }
@@ -107,6 +200,9 @@ impl Settings {
                 .help("Shell history file to read from when adding or searching (defaults to $MCFLY_HISTORY)")
                 .value_name("MCFLY_HISTORY")
                 .takes_value(true))
+            .arg(Arg::with_name("skip_comments")
+                .long("skip-comments")
+                .help("Leave out shell comments when importing shell history (defaults to on if $MCFLY_IMPORT_SKIP_COMMENTS is set)"))
             .arg(Arg::with_name("history_format")
                 .long("history_format")
                 .help("Shell history file format, 'bash', 'zsh', or 'fish' (defaults to 'bash')")
@@ -121,9 +217,22 @@ impl Settings {
                     .value_name("EXIT_CODE")
                     .help("Exit code of command")
                     .takes_value(true))
+                .arg(Arg::with_name("error")
+                    .long("error")
+                    .value_name("STDERR")
+                    .help("What the command printed to stderr, if it failed (only the first line is kept, cut to 200 characters)")
+                    .takes_value(true))
+                .arg(Arg::with_name("env_hash")
+                    .long("env-hash")
+                    .value_name("HASH")
+                    .help("A hash of the environment variables the command depends on, such as KUBECONFIG")
+                    .takes_value(true))
                 .arg(Arg::with_name("append_to_histfile")
                     .long("append-to-histfile")
                     .help("Also append new history to $HISTFILE (e.q., .bash_history)"))
+                .arg(Arg::with_name("non_interactive")
+                    .long("non-interactive")
+                    .help("The command was run from a script rather than typed at an interactive prompt"))
                 .arg(Arg::with_name("zsh_extended_history")
                     .long("zsh-extended-history")
                     .help("If appending, use zsh's EXTENDED_HISTORY format"))
@@ -170,6 +279,9 @@ impl Settings {
                     .short("f")
                     .long("fuzzy")
                     .help("Fuzzy-find results instead of searching for contiguous strings"))
+                .arg(Arg::with_name("interactive_only")
+                    .long("interactive-only")
+                    .help("Only suggest commands that were typed at an interactive prompt"))
                 .arg(Arg::with_name("output_selection")
                     .short("o")
                     .long("output-selection")
@@ -196,6 +308,8 @@ impl Settings {
                     .multiple(false)
                     .required(true)
                     .index(2)))
+            .subcommand(SubCommand::with_name("maintain")
+                .about("Delete commands older than retention.max_age_days and convert directories for search.home_relative_dirs, as set in ~/.mcfly/config.toml"))
             .subcommand(SubCommand::with_name("train")
                 .about("Train the suggestion engine (developer tool)")
                 .arg(Arg::with_name("refresh_cache")
@@ -208,6 +322,8 @@ impl Settings {
         let mut settings = Settings::default();
 
         settings.debug = matches.is_present("debug") || env::var("MCFLY_DEBUG").is_ok();
+        settings.skip_comments =
+            matches.is_present("skip_comments") || env::var("MCFLY_IMPORT_SKIP_COMMENTS").is_ok();
         settings.session_id = matches
             .value_of("session_id")
             .map(|s| s.to_string())
@@ -254,6 +370,9 @@ impl Settings {
                 );
 
                 settings.append_to_histfile = add_matches.is_present("append_to_histfile");
+                settings.interactive = !add_matches.is_present("non_interactive");
+                settings.error_snippet = add_matches.value_of("error").map(String::from);
+                settings.env_hash = add_matches.value_of("env_hash").map(String::from);
                 if add_matches.is_present("zsh_extended_history") {
                     match settings.history_format {
                         HistoryFormat::Zsh { .. } => settings.history_format = HistoryFormat::Zsh { extended_history: true },
@@ -261,6 +380,15 @@ impl Settings {
                     }
                 }
 
+                settings.ignore_previous = match env::var("MCFLY_IGNORE_PREVIOUS")
+                    .as_ref()
+                    .map(String::as_ref)
+                {
+                    Ok("off") => IgnorePrevious::Off,
+                    Ok("session") => IgnorePrevious::Session,
+                    _ => IgnorePrevious::Global,
+                };
+
                 if add_matches.value_of("exit").is_some() {
                     settings.exit_code =
                         Some(value_t!(add_matches, "exit", i32).unwrap_or_else(|e| e.exit()));
@@ -294,11 +422,14 @@ impl Settings {
                 }
 
                 // CD shows PWD as the resulting directory, but we want it from the source directory.
+                // The resulting directory is recorded as old_dir instead.
                 if settings.command.starts_with("cd ")
                     || settings.command.starts_with("pushd ")
                     || settings.command.starts_with("j ")
                 {
+                    let destination = settings.dir.to_owned();
                     settings.dir = settings.old_dir.to_owned().unwrap_or(settings.dir);
+                    settings.old_dir = Some(destination);
                 }
             }
 
@@ -327,6 +458,9 @@ impl Settings {
                 settings.fuzzy =
                     search_matches.is_present("fuzzy") || env::var("MCFLY_FUZZY").is_ok();
 
+                settings.interactive_only = search_matches.is_present("interactive_only")
+                    || env::var("MCFLY_INTERACTIVE_ONLY").is_ok();
+
                 settings.output_selection = search_matches
                     .value_of("output_selection")
                     .map(|s| s.to_owned());
@@ -355,6 +489,10 @@ impl Settings {
                 settings.refresh_training_cache = train_matches.is_present("refresh_cache");
             }
 
+            ("maintain", Some(_)) => {
+                settings.mode = Mode::Maintain;
+            }
+
             ("move", Some(move_matches)) => {
                 settings.mode = Mode::Move;
                 settings.old_dir = Some(String::from(
@@ -373,6 +511,12 @@ impl Settings {
             _ => unreachable!(), // If all subcommands are defined above, anything else is unreachable!()
         }
 
+        settings.import_format = if matches.is_present("history_format") {
+            settings.history_format
+        } else {
+            Shell::detect().history_format()
+        };
+
         settings.lightmode = match env::var_os("MCFLY_LIGHT") {
             Some(_val) => true,
             None => false,
@@ -399,3 +543,39 @@ impl Settings {
         Settings::storage_dir_path().join(PathBuf::from("history.db"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryFormat, Shell};
+
+    #[test]
+    fn detects_the_shell_from_mcfly_shell_then_shell() {
+        assert_eq!(Shell::detect_from(None, Some("/bin/bash")), Shell::Bash);
+        assert_eq!(
+            Shell::detect_from(None, Some("/usr/local/bin/zsh")),
+            Shell::Zsh
+        );
+        assert_eq!(Shell::detect_from(None, Some("/usr/bin/fish")), Shell::Fish);
+        let tcsh = Shell::detect_from(None, Some("/usr/bin/tcsh"));
+        assert_eq!(tcsh, Shell::Unknown);
+        match tcsh.history_format() {
+            HistoryFormat::Bash => {}
+            format => panic!("expected bash as the fallback, got {:?}", format),
+        }
+
+        assert_eq!(
+            Shell::detect_from(Some("-zsh"), Some("/usr/bin/tcsh")),
+            Shell::Zsh
+        );
+        assert_eq!(
+            Shell::detect_from(Some("nonsense"), Some("/usr/bin/tcsh")),
+            Shell::Unknown
+        );
+        assert_eq!(
+            Shell::detect_from(Some("nonsense"), Some("/bin/bash")),
+            Shell::Bash
+        );
+
+        assert_eq!(Shell::detect_from(None, None), Shell::Unknown);
+    }
+}
@@ -139,6 +139,58 @@ pub fn full_history(path: &PathBuf, history_format: HistoryFormat) -> Vec<Histor
     }
 }
 
+/// Commands from the output of `fc -l`, without their history numbers. bash puts a tab and a space
+/// between the number and the command and zsh puts two spaces; both add a `*` after the number of
+/// an edited entry. Lines without a number continue the multi-line command before them.
+pub fn fc_history<R: Read>(mut reader: R, history_format: HistoryFormat) -> Vec<HistoryCommand> {
+    let mut buffer = Vec::new();
+    reader
+        .read_to_end(&mut buffer)
+        .unwrap_or_else(|err| panic!("McFly error: Unable to read fc output ({})", err));
+    let numbered_line = Regex::new(r"^\s*\d+\*?\s+(.*)$").unwrap();
+    let when = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|err| panic!("McFly error: Time went backwards ({})", err))
+        .as_secs() as i64;
+
+    let mut commands: Vec<HistoryCommand> = Vec::new();
+    for line in String::from_utf8_lossy(&buffer).lines() {
+        if let Some(captures) = numbered_line.captures(line) {
+            commands.push(HistoryCommand::new(&captures[1], when, history_format));
+        } else if let Some(command) = commands.last_mut() {
+            command.command.push('\n');
+            command.command.push_str(line);
+        }
+    }
+    commands.retain(|command| !command.command.is_empty());
+    commands
+}
+
+/// Whether a history entry is a shell comment. `#mcfly:` markers aren't comments; they record
+/// searches and are handled separately.
+pub fn is_comment(command: &str) -> bool {
+    command.trim_start().starts_with('#') && !is_search_marker(command)
+}
+
+/// Whether a history entry is a `#mcfly:` marker left by a ctrl-r search, which `should_add`
+/// never records either.
+pub fn is_search_marker(command: &str) -> bool {
+    command.starts_with("#mcfly:")
+}
+
+/// The history entries to import into McFly: never search markers, and optionally no comments.
+pub fn importable_history(
+    path: &PathBuf,
+    history_format: HistoryFormat,
+    skip_comments: bool,
+) -> Vec<HistoryCommand> {
+    full_history(path, history_format)
+        .into_iter()
+        .filter(|command| !is_search_marker(&command.command))
+        .filter(|command| !skip_comments || !is_comment(&command.command))
+        .collect()
+}
+
 pub fn last_history_line(path: &PathBuf, history_format: HistoryFormat) -> Option<String> {
     // Could switch to https://github.com/mikeycgto/rev_lines
     full_history(path, history_format)
@@ -228,7 +280,31 @@ pub fn append_history_entry(command: &HistoryCommand, path: &PathBuf, debug: boo
 
 #[cfg(test)]
 mod tests {
-    use super::has_leading_timestamp;
+    use super::{fc_history, has_leading_timestamp, importable_history};
+    use crate::settings::HistoryFormat;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn fc_history_strips_history_numbers() {
+        let bash = "  498\t git status\n  499\t cargo test -- --nocapture\n  500*\t echo 'a  b'\n";
+        let zsh = " 1021  git status\n 1022  for f in *; do\n  echo $f\ndone\n";
+        let commands = |output: &str| -> Vec<String> {
+            fc_history(output.as_bytes(), HistoryFormat::Bash)
+                .into_iter()
+                .map(|command| command.command)
+                .collect()
+        };
+        assert_eq!(
+            commands(bash),
+            vec!["git status", "cargo test -- --nocapture", "echo 'a  b'"]
+        );
+        assert_eq!(
+            commands(zsh),
+            vec!["git status", "for f in *; do\n  echo $f\ndone"]
+        );
+    }
 
     #[test]
     fn has_leading_timestamp_works() {
@@ -241,4 +317,33 @@ mod tests {
         assert_eq!(false, has_leading_timestamp("1234567890"));
         assert_eq!(false, has_leading_timestamp("hello 1234567890"));
     }
+
+    #[test]
+    fn importable_history_can_skip_comments() {
+        let path = env::temp_dir().join(format!("mcfly-comments-test-{}", process::id()));
+        fs::write(
+            &path,
+            "#1577836800\nls -la\n# a note to self\n  #indented note\n#mcfly: git status\necho '#not a comment'\n",
+        )
+        .unwrap();
+
+        let commands = |skip_comments| -> Vec<String> {
+            importable_history(&path, HistoryFormat::Bash, skip_comments)
+                .into_iter()
+                .map(|command| command.command)
+                .collect()
+        };
+        assert_eq!(commands(true), vec!["ls -la", "echo '#not a comment'"]);
+        assert_eq!(
+            commands(false),
+            vec![
+                "ls -la",
+                "# a note to self",
+                "  #indented note",
+                "echo '#not a comment'"
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
 }
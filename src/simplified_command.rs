@@ -2,11 +2,27 @@ use unicode_segmentation::UnicodeSegmentation;
 
 const TRUNCATE_TO_N_TOKENS: u16 = 2;
 
+/// Text put in place of quoted strings and paths, then of numbers and hashes when `placeholders` is
+/// on. The same text occurring literally in a command is escaped with a `\`, which templates
+/// otherwise never contain, so `echo PATH` and `echo /tmp` don't share a template.
+const PLACEHOLDERS: [&str; 4] = ["QUOTED", "PATH", "NUM", "HEX"];
+
+/// Words at least this long made of hex digits (with at least one digit and one letter) are treated as hashes or ids.
+const MIN_HEX_LENGTH: usize = 7;
+
+/// The template `History::add` records for `command` with McFly's default settings, for previewing
+/// how commands will be grouped. `History::template` also applies a history's configured settings.
+pub fn simplify_command(command: &str) -> String {
+    SimplifiedCommand::new(command, true).result
+}
+
 #[derive(Debug)]
 pub struct SimplifiedCommand {
     pub original: String,
     pub result: String,
     pub truncate: bool,
+    /// Replace numbers with NUM and hashes with HEX, so `kubectl logs pod-1234` and `kubectl logs pod-5678` share a template.
+    pub placeholders: bool,
 }
 
 #[allow(clippy::collapsible_if)]
@@ -17,15 +33,41 @@ pub struct SimplifiedCommand {
 /// - Check to see if unknown strings represent valid local paths in the directory where the command was run.
 impl SimplifiedCommand {
     pub fn new<S: Into<String>>(command: S, truncate: bool) -> SimplifiedCommand {
+        SimplifiedCommand::with_placeholders(command, truncate, false)
+    }
+
+    pub fn with_placeholders<S: Into<String>>(
+        command: S,
+        truncate: bool,
+        placeholders: bool,
+    ) -> SimplifiedCommand {
         let mut simplified_command = SimplifiedCommand {
             original: command.into(),
             result: String::new(),
             truncate,
+            placeholders,
         };
         simplified_command.simplify();
         simplified_command
     }
 
+    /// Whether `command` literally contains placeholder text that its template escapes, with
+    /// `placeholders` as for `with_placeholders`.
+    pub fn would_collide(command: &str, placeholders: bool) -> bool {
+        SimplifiedCommand::produced_placeholders(placeholders)
+            .iter()
+            .any(|placeholder| command.contains(placeholder))
+    }
+
+    // The placeholder text a simplification can put in a template.
+    fn produced_placeholders(placeholders: bool) -> &'static [&'static str] {
+        if placeholders {
+            &PLACEHOLDERS
+        } else {
+            &PLACEHOLDERS[..2]
+        }
+    }
+
     fn simplify(&mut self) {
         let mut in_double_quote = false;
         let mut in_single_quote = false;
@@ -68,11 +110,8 @@ impl SimplifiedCommand {
                             }
                         }
 
-                        if !self.result.is_empty() && buffer.contains('/') {
-                            self.result.push_str("PATH");
-                        } else {
-                            self.result.push_str(&buffer);
-                        }
+                        let token = self.simplify_token(&buffer);
+                        self.result.push_str(&token);
                         self.result.push_str(grapheme);
                         buffer.clear();
                     }
@@ -85,11 +124,63 @@ impl SimplifiedCommand {
                 }
             }
         }
-        if !self.result.is_empty() && buffer.contains('/') {
-            self.result.push_str("PATH");
+        let token = self.simplify_token(&buffer);
+        self.result.push_str(&token);
+    }
+
+    fn simplify_token(&self, token: &str) -> String {
+        if !self.result.is_empty() && token.contains('/') {
+            "PATH".to_string()
+        } else if self.placeholders {
+            SimplifiedCommand::replace_literals(&self.escape_placeholders(token))
         } else {
-            self.result.push_str(&buffer);
+            self.escape_placeholders(token)
+        }
+    }
+
+    // Escape any placeholder text this simplification could produce that `token` contains literally.
+    fn escape_placeholders(&self, token: &str) -> String {
+        SimplifiedCommand::produced_placeholders(self.placeholders)
+            .iter()
+            .fold(token.to_string(), |token, placeholder| {
+                token.replace(placeholder, &format!("\\{}", placeholder))
+            })
+    }
+
+    fn replace_literals(token: &str) -> String {
+        let mut result = String::new();
+        let mut word = String::new();
+        for c in token.chars().chain(std::iter::once(' ')) {
+            if c.is_ascii_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            let is_hex = word.len() >= MIN_HEX_LENGTH
+                && word.chars().all(|c| c.is_ascii_hexdigit())
+                && word.chars().any(|c| c.is_ascii_digit())
+                && word.chars().any(|c| c.is_ascii_alphabetic());
+            if is_hex {
+                result.push_str("HEX");
+            } else {
+                let mut in_number = false;
+                for c in word.chars() {
+                    if c.is_ascii_digit() {
+                        if !in_number {
+                            result.push_str("NUM");
+                        }
+                        in_number = true;
+                    } else {
+                        result.push(c);
+                        in_number = false;
+                    }
+                }
+            }
+            word.clear();
+            result.push(c);
         }
+        result.pop();
+        result
     }
 }
 
@@ -194,6 +285,87 @@ mod tests {
         assert_eq!(simplified_command.result, "git ci");
     }
 
+    #[test]
+    fn it_replaces_numbers_with_placeholders() {
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("kubectl logs pod-1234", false, true);
+        assert_eq!(simplified_command.result, "kubectl logs pod-NUM");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("kubectl logs pod-5678", false, true);
+        assert_eq!(simplified_command.result, "kubectl logs pod-NUM");
+
+        let simplified_command = SimplifiedCommand::with_placeholders("ssh web42", false, true);
+        assert_eq!(simplified_command.result, "ssh webNUM");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("kubectl logs pod-1234", false, false);
+        assert_eq!(simplified_command.result, "kubectl logs pod-1234");
+    }
+
+    #[test]
+    fn it_replaces_hashes_with_placeholders() {
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("git show 3f2a9c1", false, true);
+        assert_eq!(simplified_command.result, "git show HEX");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("docker exec -it 4b1e2c3d5f6a bash", false, true);
+        assert_eq!(simplified_command.result, "docker exec -it HEX bash");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("git checkout deadbeef", false, true);
+        assert_eq!(simplified_command.result, "git checkout deadbeef");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("git checkout feature-a1b2c3d4e5", false, true);
+        assert_eq!(simplified_command.result, "git checkout feature-HEX");
+    }
+
+    #[test]
+    fn it_escapes_literal_placeholder_text() {
+        assert_eq!(
+            SimplifiedCommand::new("echo PATH", false).result,
+            "echo \\PATH"
+        );
+        assert_eq!(
+            SimplifiedCommand::new("echo /tmp", false).result,
+            "echo PATH"
+        );
+        assert_eq!(
+            SimplifiedCommand::new("git ci -m QUOTED", false).result,
+            "git ci -m \\QUOTED"
+        );
+        assert_eq!(
+            SimplifiedCommand::with_placeholders("ssh webNUM", false, true).result,
+            "ssh web\\NUM"
+        );
+        assert_eq!(
+            SimplifiedCommand::with_placeholders("ssh web42", false, true).result,
+            "ssh webNUM"
+        );
+        assert_eq!(
+            SimplifiedCommand::new("ssh webNUM", false).result,
+            "ssh webNUM"
+        );
+
+        assert!(SimplifiedCommand::would_collide("echo PATH", false));
+        assert!(!SimplifiedCommand::would_collide("ssh webNUM", false));
+        assert!(SimplifiedCommand::would_collide("ssh webNUM", true));
+        assert!(!SimplifiedCommand::would_collide("echo /tmp", true));
+    }
+
+    #[test]
+    fn it_collapses_version_strings_consistently() {
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("pip install foo==1.2.3", false, true);
+        assert_eq!(simplified_command.result, "pip install foo==NUM.NUM.NUM");
+
+        let simplified_command =
+            SimplifiedCommand::with_placeholders("pip install foo==1.10.0", false, true);
+        assert_eq!(simplified_command.result, "pip install foo==NUM.NUM.NUM");
+    }
+
     //    #[test]
     //    fn it_sorts_and_expands_command_line_arguments() {
     //        let simplified_command = SimplifiedCommand::new("ls -t 2 -lah --foo bar --baz=bing");
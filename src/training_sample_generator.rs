@@ -1,6 +1,7 @@
 use crate::history::Command;
 use crate::history::Features;
 use crate::history::History;
+use crate::history::MatchMode;
 use crate::settings::Settings;
 use crate::training_cache;
 use rand::seq::IteratorRandom;
@@ -32,7 +33,7 @@ impl<'a> TrainingSampleGenerator<'a> {
 
     pub fn generate_data_set(history: &History) -> Vec<(Features, bool)> {
         let mut data_set: Vec<(Features, bool)> = Vec::new();
-        let commands = history.commands(&None, -1, 0, true);
+        let commands = history.commands(&None, -1, 0, true, false);
 
         let mut positive_examples = 0;
         let mut negative_examples = 0;
@@ -52,9 +53,8 @@ impl<'a> TrainingSampleGenerator<'a> {
             }
 
             // Setup the cache for the time this command was recorded.
-            // Unwrap is safe here because we check command.dir.is_none() above.
             history.build_cache_table(
-                &command.dir.to_owned().unwrap(),
+                command.dir.as_deref(),
                 &Some(command.session_id.clone()),
                 None,
                 command.when_run,
@@ -62,7 +62,8 @@ impl<'a> TrainingSampleGenerator<'a> {
             );
 
             // Load the entire match set.
-            let results = history.find_matches(&String::new(), -1, false);
+            let results =
+                history.find_matches(&String::new(), -1, false, false, MatchMode::Substring);
 
             // Get the features for this command at the time it was logged.
             if positive_examples <= negative_examples {